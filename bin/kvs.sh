@@ -3,7 +3,7 @@
 port=50061
 mode=$1
 if [ "$mode" == "" ]; then
-    echo "Usage: ./kvs.sh [set|get|delete]"
+    echo "Usage: ./kvs.sh [set|get|delete|scan]"
     exit
 fi
 
@@ -39,8 +39,26 @@ case "$mode" in
         echo $result | jq -r '.value' | base64 -d
         echo
         ;;
+    "delete")
+        key=$2
+        if [ "$key" == "" ]; then
+            echo "Usage: ./kvs.sh delete [key]"
+            exit
+        fi
+        key=$(echo -n $key | base64)
+        grpcurl -plaintext -import-path ./grpc -proto espikey.proto \
+          -d '{"key": "'$key'"}' \
+          "[::]:${port}" espikey.KVService/Delete
+        ;;
+    "scan")
+        prefix=$(echo -n $2 | base64)
+        grpcurl -plaintext -import-path ./grpc -proto espikey.proto \
+          -d '{"prefix": "'$prefix'"}' \
+          "[::]:${port}" espikey.KVService/Scan \
+          | jq -r '(.key | @base64d) + "\t" + ((.value // "") | @base64d)'
+        ;;
     *)
-        echo "Usage: ./kvs.sh [set|get|delete]"
+        echo "Usage: ./kvs.sh [set|get|delete|scan]"
         ;;
 esac
 
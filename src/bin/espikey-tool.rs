@@ -5,7 +5,10 @@ use std::path::PathBuf;
 use clap::Parser;
 use itertools::Itertools;
 
-use espikey::table::{Block, BlockHandle, Footer, FOOTER_ENCODED_LENGTH};
+use espikey::table::{
+    read_block_with_dictionary, Block, BlockHandle, Footer, FOOTER_ENCODED_LENGTH,
+    ZSTD_DICTIONARY_KEY,
+};
 use espikey::version_edit::VersionEdit;
 use espikey::write_batch::{ValueTypeCode, WriteBatch};
 use espikey::{log, InternalKey};
@@ -71,6 +74,15 @@ fn show_wal_record(data: &[u8]) {
     }
 }
 
+// PrintReporter shows the bytes dropped by log::Reader, so that the dump goes on over corruption
+struct PrintReporter;
+
+impl log::Reporter for PrintReporter {
+    fn corruption(&mut self, bytes: usize, reason: &espikey::Status) {
+        println!("dropped {} bytes: {}", bytes, reason);
+    }
+}
+
 fn show_internal_key(prefix: &str, ikey: &InternalKey) {
     let user_key = ikey.user_key();
     let seq = ikey.sequence();
@@ -149,21 +161,42 @@ fn main() -> anyhow::Result<()> {
 
             let mut scratch = Vec::new();
             let meta_index_block =
-                espikey::table::read_block(&file, &footer.metaindex_handle, &mut scratch)?;
+                espikey::table::read_block(&file, &footer.metaindex_handle, &mut scratch, true)?;
 
             let mut scratch = Vec::new();
             let index_block =
-                espikey::table::read_block(&file, &footer.index_handle, &mut scratch)?;
+                espikey::table::read_block(&file, &footer.index_handle, &mut scratch, true)?;
+
+            // the data blocks may be compressed with the dictionary in the meta blocks
+            let mut dictionary = None;
+            for (key, value) in Block::new(meta_index_block).unwrap().iter() {
+                if key == ZSTD_DICTIONARY_KEY.as_bytes() {
+                    let (block_handle, _) = BlockHandle::decode_from(value)?;
+                    let mut scratch = Vec::new();
+                    let block =
+                        espikey::table::read_block(&file, &block_handle, &mut scratch, true)?;
+                    dictionary = Some(block.to_vec());
+                }
+            }
 
             println!("data block(accessed by index): ");
             let block = Block::new(index_block).unwrap();
             for (i, (_, value)) in block.iter().enumerate() {
                 let mut scratch = Vec::new();
                 let (block_handle, _) = BlockHandle::decode_from(value)?;
-                let block = espikey::table::read_block(&file, &block_handle, &mut scratch)?;
+                let block = read_block_with_dictionary(
+                    &file,
+                    &block_handle,
+                    &mut scratch,
+                    true,
+                    dictionary.as_deref(),
+                )?;
                 let block = Block::new(block).unwrap();
 
-                println!("=== block#{} (offset={}, size={}) ===", i, block_handle.offset, block_handle.size);
+                println!(
+                    "=== block#{} (offset={}, size={}) ===",
+                    i, block_handle.offset, block_handle.size
+                );
                 for (key, value) in block.iter() {
                     let ikey = InternalKey::decode_from(&key);
                     show_internal_key("        key:   ", &ikey);
@@ -200,7 +233,11 @@ fn main() -> anyhow::Result<()> {
         }
         Mode::Manifest => {
             println!("manifest(versino-edit)");
-            let mut reader = log::Reader::new(file);
+            let mut reader = log::Reader::with_reporter(
+                file,
+                Box::new(PrintReporter),
+                log::RecoveryMode::SkipToNextBlock,
+            );
             while let Some(entry) = reader.read()? {
                 println!("length: {}", entry.len());
                 let ve = VersionEdit::decode_from(&entry)?;
@@ -209,7 +246,11 @@ fn main() -> anyhow::Result<()> {
         }
         Mode::Wal => {
             println!("wal");
-            let mut reader = log::Reader::new(file);
+            let mut reader = log::Reader::with_reporter(
+                file,
+                Box::new(PrintReporter),
+                log::RecoveryMode::SkipToNextBlock,
+            );
             while let Some(entry) = reader.read()? {
                 let wb = WriteBatch::from(entry)?;
                 println!("sequence: {}, count: {}", wb.get_sequence(), wb.get_count());
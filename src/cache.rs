@@ -0,0 +1,277 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::table::Block;
+
+// the cache is split into shards by the hash of the key, so that lookups don't contend on one lock
+const NUM_SHARD_BITS: usize = 4;
+
+// ShardedLruCache maps keys to values, and evicts the least recently used entries
+// when the total charge of the entries exceeds the capacity.
+// each shard has an equal part of the capacity, so the total charge may exceed the capacity
+// by up to a shard's rounding. a cache with one shard keeps the capacity exactly.
+pub struct ShardedLruCache<K, V> {
+    shards: Vec<Mutex<LruShard<K, V>>>,
+    shard_bits: usize,
+    hasher: RandomState,
+    capacity: usize,
+
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct LruShard<K, V> {
+    capacity: usize,
+    usage: usize,
+    entries: HashMap<K, Entry<V>>,
+    // the keys ordered by the last use. the first one is the least recently used.
+    lru: BTreeMap<u64, K>,
+    // increases on every use, and orders the entries in lru
+    tick: u64,
+}
+
+struct Entry<V> {
+    value: V,
+    charge: usize,
+    tick: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> ShardedLruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self::with_shard_bits(capacity, NUM_SHARD_BITS)
+    }
+
+    // with_shard_bits returns a cache split into 2^shard_bits shards
+    pub fn with_shard_bits(capacity: usize, shard_bits: usize) -> Self {
+        let num_shards = 1 << shard_bits;
+        let shard_capacity = capacity.div_ceil(num_shards);
+        let shards = (0..num_shards)
+            .map(|_| {
+                Mutex::new(LruShard {
+                    capacity: shard_capacity,
+                    usage: 0,
+                    entries: HashMap::new(),
+                    lru: BTreeMap::new(),
+                    tick: 0,
+                })
+            })
+            .collect();
+        ShardedLruCache {
+            shards,
+            shard_bits,
+            hasher: RandomState::new(),
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<LruShard<K, V>> {
+        if self.shard_bits == 0 {
+            return &self.shards[0];
+        }
+        let hash = self.hasher.hash_one(key);
+        &self.shards[(hash >> (64 - self.shard_bits)) as usize]
+    }
+
+    // get returns the value of key, and marks it as the most recently used
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut shard = self.shard(key).lock().unwrap();
+        let value = shard.touch(key);
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    // insert adds value for key, replacing the old value of key.
+    // the least recently used entries are evicted until the shard is within its capacity,
+    // so a value larger than the capacity is not kept.
+    pub fn insert(&self, key: K, value: V, charge: usize) {
+        let mut shard = self.shard(&key).lock().unwrap();
+        shard.remove(&key);
+
+        shard.tick += 1;
+        let tick = shard.tick;
+        shard.usage += charge;
+        shard.lru.insert(tick, key.clone());
+        shard.entries.insert(
+            key,
+            Entry {
+                value,
+                charge,
+                tick,
+            },
+        );
+
+        while shard.usage > shard.capacity {
+            let Some((_, oldest)) = shard.lru.pop_first() else {
+                break;
+            };
+            let entry = shard.entries.remove(&oldest).unwrap();
+            shard.usage -= entry.charge;
+        }
+    }
+
+    // erase removes the entry of key if it exists
+    pub fn erase(&self, key: &K) {
+        self.shard(key).lock().unwrap().remove(key);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // usage returns the total charge of the entries in the cache
+    pub fn usage(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().usage)
+            .sum()
+    }
+
+    // hits is the number of gets which found the key
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    // misses is the number of gets which didn't find the key
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruShard<K, V> {
+    fn touch(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(key)?;
+        let old_tick = std::mem::replace(&mut entry.tick, tick);
+        let value = entry.value.clone();
+
+        let key = self.lru.remove(&old_tick).unwrap();
+        self.lru.insert(tick, key);
+        Some(value)
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.tick);
+            self.usage -= entry.charge;
+        }
+    }
+}
+
+impl<K, V> fmt::Debug for ShardedLruCache<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShardedLruCache")
+            .field("capacity", &self.capacity)
+            .field("hits", &self.hits)
+            .field("misses", &self.misses)
+            .finish()
+    }
+}
+
+// BlockCache keeps the uncompressed data blocks of tables, and it's shared by all tables of the dbs
+// opened with the same options.
+// a block is keyed by the cache id of its table and its offset in the file, and charged by its size.
+// file numbers are not used as keys, because they are unique only within a db.
+#[derive(Debug)]
+pub struct BlockCache {
+    cache: ShardedLruCache<(u64, u64), Block>,
+    // the last cache id handed out by new_id
+    last_id: AtomicU64,
+}
+
+impl BlockCache {
+    // new returns a cache which holds blocks of up to capacity bytes
+    pub fn new(capacity: usize) -> Self {
+        BlockCache {
+            cache: ShardedLruCache::new(capacity),
+            last_id: AtomicU64::new(0),
+        }
+    }
+
+    // new_id returns an id which no other user of the cache has, to key the blocks of a table with
+    pub fn new_id(&self) -> u64 {
+        self.last_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn get(&self, cache_id: u64, offset: u64) -> Option<Block> {
+        self.cache.get(&(cache_id, offset))
+    }
+
+    pub fn insert(&self, cache_id: u64, offset: u64, block: Block) {
+        let charge = block.size();
+        self.cache.insert((cache_id, offset), block, charge);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.cache.capacity()
+    }
+
+    // usage returns the total size of the cached blocks
+    pub fn usage(&self) -> usize {
+        self.cache.usage()
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.cache.hits()
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.cache.misses()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NUM_SHARDS: usize = 1 << NUM_SHARD_BITS;
+
+    #[test]
+    fn test_lru_cache() {
+        // one entry of charge 1 per shard
+        let cache = ShardedLruCache::new(NUM_SHARDS);
+        assert_eq!(cache.get(&1), None);
+        cache.insert(1, "a", 1);
+        assert_eq!(cache.get(&1), Some("a"));
+        cache.insert(1, "b", 1);
+        assert_eq!(cache.get(&1), Some("b"));
+        assert_eq!(cache.usage(), 1);
+        assert_eq!((cache.hits(), cache.misses()), (2, 1));
+
+        cache.erase(&1);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.usage(), 0);
+
+        // an entry larger than the shard capacity is not kept
+        cache.insert(2, "c", 2);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.usage(), 0);
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = ShardedLruCache::new(100 * NUM_SHARDS);
+        for i in 0..10000 {
+            cache.insert(i, i, 10);
+            // the first entry is kept by being used
+            assert_eq!(cache.get(&0), Some(0));
+        }
+        assert!(cache.usage() <= 100 * NUM_SHARDS);
+
+        let cached = (0..10000).filter(|i| cache.get(i).is_some()).count();
+        assert!(cached <= 10 * NUM_SHARDS);
+        // the recent entries are kept
+        assert!((9995..10000).all(|i| cache.get(&i).is_some()));
+    }
+}
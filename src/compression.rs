@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, LazyLock, RwLock};
+
+use crate::{Result, Status};
+
+// CompressionType is the type byte in the trailer of a block.
+// the values of No, Snappy and Zstd are the same as the ones of LevelDB, and Lz4 is the one of RocksDB.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionType {
+    No,
+    #[default]
+    Snappy,
+    Zstd,
+    Lz4,
+    // Custom is the codec registered with register_compressor for the type byte
+    Custom(u8),
+}
+
+impl CompressionType {
+    // block_type returns the type byte of the compression
+    pub fn block_type(self) -> u8 {
+        match self {
+            CompressionType::No => 0,
+            CompressionType::Snappy => 1,
+            CompressionType::Zstd => 2,
+            CompressionType::Lz4 => 4,
+            CompressionType::Custom(block_type) => block_type,
+        }
+    }
+
+    // compressor returns the codec of the compression type, or None for no compression
+    // or a type which no codec is registered for
+    pub fn compressor(self) -> Option<Arc<dyn Compressor>> {
+        compressor(self.block_type())
+    }
+}
+
+// Compressor is a codec of table blocks, identified by the type byte stored in the block trailer.
+// a dictionary is given if the table has one, and codecs which don't support it ignore it.
+pub trait Compressor: Send + Sync {
+    fn block_type(&self) -> u8;
+
+    // compress returns None if input can't be compressed
+    fn compress(&self, input: &[u8], dictionary: Option<&[u8]>) -> Option<Vec<u8>>;
+
+    // decompress returns None if input is not a valid compressed block
+    fn decompress(&self, input: &[u8], dictionary: Option<&[u8]>) -> Option<Vec<u8>>;
+}
+
+// the compressors which blocks can be compressed with, registered by their block type byte.
+// the built-in ones are always registered, and applications add their own with register_compressor.
+static COMPRESSORS: LazyLock<RwLock<HashMap<u8, Arc<dyn Compressor>>>> = LazyLock::new(|| {
+    let builtins: [Arc<dyn Compressor>; 3] = [
+        Arc::new(SnappyCompressor),
+        Arc::new(ZstdCompressor),
+        Arc::new(Lz4Compressor),
+    ];
+    let compressors = builtins
+        .into_iter()
+        .map(|compressor| (compressor.block_type(), compressor))
+        .collect();
+    RwLock::new(compressors)
+});
+
+// register_compressor registers a codec for the tables of all dbs in the process, so that
+// blocks are compressed with it by CompressionType::Custom of its type byte, and decompressed by it.
+// the type byte is stored in the tables, so it's an InvalidArgument error to register
+// the one of no compression or one which is registered already.
+pub fn register_compressor(compressor: Arc<dyn Compressor>) -> Result<()> {
+    let block_type = compressor.block_type();
+    if block_type == CompressionType::No.block_type() {
+        return Err(Status::InvalidArgument);
+    }
+    let mut compressors = COMPRESSORS.write().unwrap();
+    if compressors.contains_key(&block_type) {
+        return Err(Status::InvalidArgument);
+    }
+    compressors.insert(block_type, compressor);
+    Ok(())
+}
+
+// compressor returns the codec registered for block_type
+pub fn compressor(block_type: u8) -> Option<Arc<dyn Compressor>> {
+    COMPRESSORS.read().unwrap().get(&block_type).cloned()
+}
+
+struct SnappyCompressor;
+
+impl Compressor for SnappyCompressor {
+    fn block_type(&self) -> u8 {
+        CompressionType::Snappy.block_type()
+    }
+
+    fn compress(&self, input: &[u8], _dictionary: Option<&[u8]>) -> Option<Vec<u8>> {
+        snap::raw::Encoder::new().compress_vec(input).ok()
+    }
+
+    fn decompress(&self, input: &[u8], _dictionary: Option<&[u8]>) -> Option<Vec<u8>> {
+        snap::raw::Decoder::new().decompress_vec(input).ok()
+    }
+}
+
+// blocks are compressed with the level which LevelDB uses by default
+const ZSTD_COMPRESSION_LEVEL: i32 = 1;
+
+struct ZstdCompressor;
+
+impl Compressor for ZstdCompressor {
+    fn block_type(&self) -> u8 {
+        CompressionType::Zstd.block_type()
+    }
+
+    fn compress(&self, input: &[u8], dictionary: Option<&[u8]>) -> Option<Vec<u8>> {
+        let mut compressor = zstd::bulk::Compressor::with_dictionary(
+            ZSTD_COMPRESSION_LEVEL,
+            dictionary.unwrap_or_default(),
+        )
+        .ok()?;
+        compressor.compress(input).ok()
+    }
+
+    fn decompress(&self, input: &[u8], dictionary: Option<&[u8]>) -> Option<Vec<u8>> {
+        let mut decoder =
+            zstd::stream::read::Decoder::with_dictionary(input, dictionary.unwrap_or_default())
+                .ok()?;
+        let mut output = Vec::new();
+        decoder.read_to_end(&mut output).ok()?;
+        Some(output)
+    }
+}
+
+// Lz4Compressor stores the length of the uncompressed block in front of the compressed one
+struct Lz4Compressor;
+
+impl Compressor for Lz4Compressor {
+    fn block_type(&self) -> u8 {
+        CompressionType::Lz4.block_type()
+    }
+
+    fn compress(&self, input: &[u8], _dictionary: Option<&[u8]>) -> Option<Vec<u8>> {
+        Some(lz4_flex::block::compress_prepend_size(input))
+    }
+
+    fn decompress(&self, input: &[u8], _dictionary: Option<&[u8]>) -> Option<Vec<u8>> {
+        lz4_flex::block::decompress_size_prepended(input).ok()
+    }
+}
+
+// train_zstd_dictionary trains a dictionary of at most max_size bytes from samples.
+// it returns None if there are too few samples to train.
+pub(crate) fn train_zstd_dictionary(samples: &[Vec<u8>], max_size: usize) -> Option<Vec<u8>> {
+    zstd::dict::from_samples(samples, max_size).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compressors() {
+        let input = b"espikey is a leveldb port in rust. ".repeat(20);
+        for compression in [
+            CompressionType::Snappy,
+            CompressionType::Zstd,
+            CompressionType::Lz4,
+        ] {
+            let compressor = compression.compressor().unwrap();
+            assert_eq!(compressor.block_type(), compression.block_type());
+
+            let compressed = compressor.compress(&input, None).unwrap();
+            assert!(compressed.len() < input.len());
+            assert_eq!(
+                compressor.decompress(&compressed, None),
+                Some(input.clone())
+            );
+            assert_eq!(compressor.decompress(b"\xff\xff\xff\xff\xff", None), None);
+        }
+        assert!(CompressionType::No.compressor().is_none());
+        assert!(compressor(3).is_none());
+    }
+
+    // XorCompressor is a codec for the tests, which flips the bits of a block
+    struct XorCompressor(u8);
+
+    impl Compressor for XorCompressor {
+        fn block_type(&self) -> u8 {
+            self.0
+        }
+
+        fn compress(&self, input: &[u8], _dictionary: Option<&[u8]>) -> Option<Vec<u8>> {
+            Some(input.iter().map(|b| !b).collect())
+        }
+
+        fn decompress(&self, input: &[u8], _dictionary: Option<&[u8]>) -> Option<Vec<u8>> {
+            Some(input.iter().map(|b| !b).collect())
+        }
+    }
+
+    #[test]
+    fn test_register_compressor() {
+        assert!(CompressionType::Custom(0x80).compressor().is_none());
+        register_compressor(Arc::new(XorCompressor(0x80))).unwrap();
+        let compressor = CompressionType::Custom(0x80).compressor().unwrap();
+        assert_eq!(compressor.block_type(), 0x80);
+        let compressed = compressor.compress(b"espikey", None).unwrap();
+        assert_eq!(
+            compressor.decompress(&compressed, None),
+            Some(b"espikey".to_vec())
+        );
+
+        // a type byte can't be taken over, since the tables have it
+        assert_eq!(
+            register_compressor(Arc::new(XorCompressor(0x80))),
+            Err(Status::InvalidArgument)
+        );
+        assert_eq!(
+            register_compressor(Arc::new(XorCompressor(
+                CompressionType::Snappy.block_type()
+            ))),
+            Err(Status::InvalidArgument)
+        );
+        assert_eq!(
+            register_compressor(Arc::new(XorCompressor(0))),
+            Err(Status::InvalidArgument)
+        );
+        // a custom type of a built-in codec is the codec
+        assert_eq!(
+            CompressionType::Custom(4)
+                .compressor()
+                .unwrap()
+                .block_type(),
+            4
+        );
+    }
+
+    #[test]
+    fn test_zstd_dictionary() {
+        let samples = (0..1000)
+            .map(|i| format!("{{\"id\":{},\"name\":\"user{}\"}}", i, i * 7).into_bytes())
+            .collect::<Vec<_>>();
+        let dictionary = train_zstd_dictionary(&samples, 1024).unwrap();
+        assert!(dictionary.len() <= 1024);
+
+        let compressor = CompressionType::Zstd.compressor().unwrap();
+        let input = b"{\"id\":1001,\"name\":\"user7007\"}";
+        let compressed = compressor.compress(input, Some(&dictionary)).unwrap();
+        assert!(compressed.len() < compressor.compress(input, None).unwrap().len());
+        assert_eq!(
+            compressor.decompress(&compressed, Some(&dictionary)),
+            Some(input.to_vec())
+        );
+
+        // too few samples to train
+        assert_eq!(train_zstd_dictionary(&samples[..1], 1024), None);
+    }
+}
@@ -0,0 +1,491 @@
+use std::sync::Arc;
+
+use crate::iterator::InternalIterator;
+use crate::merger::MergingIterator;
+use crate::version_set::Version;
+use crate::write_batch::ValueTypeCode;
+use crate::{parse_internal_key, InternalKey, Result, Status, MAX_SEQUENCE_NUMBER};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    // the internal iterator is positioned at the entry which yields the current user key
+    Forward,
+    // the internal iterator is positioned just before all entries of the current user key,
+    // and the current user key and value are kept in saved_key and saved_value
+    Reverse,
+}
+
+// DbIterator iterates over the user keys of the db as of a sequence number.
+// older versions of a key and deleted keys are hidden, and every user key is returned at most once.
+//
+// the iteration is limited to the keys in [lower_bound, upper_bound) if the bounds are given.
+pub struct DbIterator {
+    iter: MergingIterator<Box<dyn InternalIterator + Send>>,
+    sequence: u64,
+    lower_bound: Option<Vec<u8>>,
+    upper_bound: Option<Vec<u8>>,
+
+    direction: Direction,
+    valid: bool,
+    saved_key: Vec<u8>,
+    saved_value: Vec<u8>,
+    status: Option<Status>,
+
+    // keeps the table files of the version from being removed while iterating
+    _version: Arc<Version>,
+}
+
+impl DbIterator {
+    pub(crate) fn new(
+        iter: MergingIterator<Box<dyn InternalIterator + Send>>,
+        sequence: u64,
+        lower_bound: Option<&[u8]>,
+        upper_bound: Option<&[u8]>,
+        version: Arc<Version>,
+    ) -> Self {
+        DbIterator {
+            iter,
+            sequence,
+            lower_bound: lower_bound.map(|b| b.to_vec()),
+            upper_bound: upper_bound.map(|b| b.to_vec()),
+
+            direction: Direction::Forward,
+            valid: false,
+            saved_key: Vec::new(),
+            saved_value: Vec::new(),
+            status: None,
+
+            _version: version,
+        }
+    }
+
+    pub fn valid(&self) -> bool {
+        self.valid
+    }
+
+    // key returns the user key of the current entry
+    pub fn key(&self) -> &[u8] {
+        assert!(self.valid);
+        match self.direction {
+            Direction::Forward => user_key(self.iter.key()),
+            Direction::Reverse => &self.saved_key,
+        }
+    }
+
+    pub fn value(&self) -> &[u8] {
+        assert!(self.valid);
+        match self.direction {
+            Direction::Forward => self.iter.value(),
+            Direction::Reverse => &self.saved_value,
+        }
+    }
+
+    pub fn status(&self) -> Result<()> {
+        if let Some(e) = &self.status {
+            return Err(e.clone());
+        }
+        self.iter.status()
+    }
+
+    pub fn seek_to_first(&mut self) {
+        match self.lower_bound.clone() {
+            Some(lower_bound) => self.seek_internal(&lower_bound),
+            None => {
+                self.direction = Direction::Forward;
+                self.saved_value.clear();
+                self.iter.seek_to_first();
+                self.find_next_user_entry(false);
+            }
+        }
+    }
+
+    pub fn seek_to_last(&mut self) {
+        self.direction = Direction::Reverse;
+        self.saved_value.clear();
+        match &self.upper_bound {
+            Some(upper_bound) => {
+                // position at the last entry before the upper bound
+                let target =
+                    InternalKey::new(upper_bound, MAX_SEQUENCE_NUMBER, ValueTypeCode::Value);
+                self.iter.seek(target.get_contents());
+                if self.iter.valid() {
+                    self.iter.prev();
+                } else {
+                    self.iter.seek_to_last();
+                }
+            }
+            None => self.iter.seek_to_last(),
+        }
+        self.find_prev_user_entry();
+    }
+
+    // seek positions at the first key which is not less than target
+    pub fn seek(&mut self, target: &[u8]) {
+        match &self.lower_bound {
+            Some(lower_bound) if target < lower_bound.as_slice() => {
+                self.seek_internal(&lower_bound.clone())
+            }
+            _ => self.seek_internal(target),
+        }
+    }
+
+    fn seek_internal(&mut self, target: &[u8]) {
+        self.direction = Direction::Forward;
+        self.saved_value.clear();
+        let target = InternalKey::new(target, self.sequence, ValueTypeCode::Value);
+        self.iter.seek(target.get_contents());
+        self.find_next_user_entry(false);
+    }
+
+    pub fn next(&mut self) {
+        assert!(self.valid);
+
+        if self.direction == Direction::Reverse {
+            // the internal iterator is just before the entries of the current key,
+            // so it's moved into them, and they are skipped below.
+            self.direction = Direction::Forward;
+            if self.iter.valid() {
+                self.iter.next();
+            } else {
+                self.iter.seek_to_first();
+            }
+        } else {
+            // the current key is saved to skip its older entries
+            self.saved_key.clear();
+            self.saved_key.extend_from_slice(user_key(self.iter.key()));
+            self.iter.next();
+        }
+        self.find_next_user_entry(true);
+    }
+
+    pub fn prev(&mut self) {
+        assert!(self.valid);
+
+        if self.direction == Direction::Forward {
+            // the internal iterator is at the current entry.
+            // it's moved backward until the user key changes, so that the reverse scan below works.
+            self.saved_key.clear();
+            self.saved_key.extend_from_slice(user_key(self.iter.key()));
+            loop {
+                self.iter.prev();
+                if !self.iter.valid() {
+                    self.valid = false;
+                    self.saved_key.clear();
+                    self.saved_value.clear();
+                    return;
+                }
+                if user_key(self.iter.key()) < self.saved_key.as_slice() {
+                    break;
+                }
+            }
+            self.direction = Direction::Reverse;
+        }
+        self.find_prev_user_entry();
+    }
+
+    // find_next_user_entry moves forward to the newest visible entry of the next user key.
+    // if skipping is true, the entries of the user key in saved_key are skipped.
+    fn find_next_user_entry(&mut self, mut skipping: bool) {
+        while self.iter.valid() {
+            match parse_internal_key(self.iter.key()) {
+                Ok((user_key, _, _))
+                    if self
+                        .upper_bound
+                        .as_ref()
+                        .is_some_and(|upper_bound| user_key >= upper_bound.as_slice()) =>
+                {
+                    break;
+                }
+                Ok((user_key, sequence, value_type)) if sequence <= self.sequence => {
+                    match value_type {
+                        ValueTypeCode::Deletion => {
+                            // all the following entries of the key are hidden by the deletion
+                            self.saved_key.clear();
+                            self.saved_key.extend_from_slice(user_key);
+                            skipping = true;
+                        }
+                        ValueTypeCode::Value => {
+                            if !skipping || user_key > self.saved_key.as_slice() {
+                                self.valid = true;
+                                self.saved_key.clear();
+                                return;
+                            }
+                        }
+                    }
+                }
+                // the entry is newer than the sequence
+                Ok(_) => {}
+                Err(e) => self.status = Some(e),
+            }
+            self.iter.next();
+        }
+        self.saved_key.clear();
+        self.valid = false;
+    }
+
+    // find_prev_user_entry moves backward over the entries of the previous user key,
+    // and saves the newest visible value of it.
+    fn find_prev_user_entry(&mut self) {
+        let mut value_type = ValueTypeCode::Deletion;
+        while self.iter.valid() {
+            match parse_internal_key(self.iter.key()) {
+                Ok((user_key, _, _))
+                    if self
+                        .lower_bound
+                        .as_ref()
+                        .is_some_and(|lower_bound| user_key < lower_bound.as_slice()) =>
+                {
+                    break;
+                }
+                Ok((user_key, sequence, entry_type)) if sequence <= self.sequence => {
+                    if value_type != ValueTypeCode::Deletion && user_key < self.saved_key.as_slice()
+                    {
+                        // a visible value of the key in saved_key was found
+                        break;
+                    }
+                    value_type = entry_type;
+                    match value_type {
+                        ValueTypeCode::Deletion => {
+                            self.saved_key.clear();
+                            self.saved_value.clear();
+                        }
+                        ValueTypeCode::Value => {
+                            self.saved_key.clear();
+                            self.saved_key.extend_from_slice(user_key);
+                            self.saved_value.clear();
+                            self.saved_value.extend_from_slice(self.iter.value());
+                        }
+                    }
+                }
+                // the entry is newer than the sequence
+                Ok(_) => {}
+                Err(e) => self.status = Some(e),
+            }
+            self.iter.prev();
+        }
+
+        if value_type == ValueTypeCode::Deletion {
+            self.valid = false;
+            self.saved_key.clear();
+            self.saved_value.clear();
+            self.direction = Direction::Forward;
+        } else {
+            self.valid = true;
+        }
+    }
+}
+
+fn user_key(internal_key: &[u8]) -> &[u8] {
+    &internal_key[..internal_key.len() - 8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare_internal_key;
+    use crate::memtable::MemTable;
+
+    // mem_tables returns an older and a newer memtable, which together hold
+    //   a=a1, b deleted, c=c2, d=d1, e deleted, f=f1
+    // at the last sequence, and a=a1, b=b1, c=c1, d=d1, e=e1 at sequence 5.
+    fn mem_tables() -> [MemTable; 2] {
+        let older = MemTable::default();
+        for (sequence, key) in [(1, "a"), (2, "b"), (3, "c"), (4, "d"), (5, "e")] {
+            let value = format!("{}1", key);
+            older.add(
+                sequence,
+                ValueTypeCode::Value,
+                key.as_bytes(),
+                value.as_bytes(),
+            );
+        }
+        let newer = MemTable::default();
+        newer.add(6, ValueTypeCode::Deletion, b"b", b"");
+        newer.add(7, ValueTypeCode::Value, b"c", b"c2");
+        newer.add(8, ValueTypeCode::Deletion, b"e", b"");
+        newer.add(9, ValueTypeCode::Value, b"f", b"f1");
+        [older, newer]
+    }
+
+    fn db_iter(
+        mem_tables: &[MemTable],
+        sequence: u64,
+        lower_bound: Option<&[u8]>,
+        upper_bound: Option<&[u8]>,
+    ) -> DbIterator {
+        let children = mem_tables
+            .iter()
+            .map(|mem_table| Box::new(mem_table.iter()) as Box<dyn InternalIterator + Send>)
+            .collect();
+        DbIterator::new(
+            MergingIterator::new(children, compare_internal_key),
+            sequence,
+            lower_bound,
+            upper_bound,
+            Arc::new(Version::default()),
+        )
+    }
+
+    fn entry(iter: &DbIterator) -> Option<(String, String)> {
+        iter.valid().then(|| {
+            (
+                String::from_utf8(iter.key().to_vec()).unwrap(),
+                String::from_utf8(iter.value().to_vec()).unwrap(),
+            )
+        })
+    }
+
+    fn some_entry(key: &str, value: &str) -> Option<(String, String)> {
+        Some((key.to_string(), value.to_string()))
+    }
+
+    fn collect_forward(iter: &mut DbIterator) -> Vec<String> {
+        let mut keys = Vec::new();
+        iter.seek_to_first();
+        while iter.valid() {
+            keys.push(String::from_utf8(iter.key().to_vec()).unwrap());
+            iter.next();
+        }
+        assert_eq!(iter.status(), Ok(()));
+        keys
+    }
+
+    fn collect_backward(iter: &mut DbIterator) -> Vec<String> {
+        let mut keys = Vec::new();
+        iter.seek_to_last();
+        while iter.valid() {
+            keys.push(String::from_utf8(iter.key().to_vec()).unwrap());
+            iter.prev();
+        }
+        assert_eq!(iter.status(), Ok(()));
+        keys
+    }
+
+    #[test]
+    fn test_iterate() {
+        let mem_tables = mem_tables();
+        let mut iter = db_iter(&mem_tables, 9, None, None);
+        assert_eq!(collect_forward(&mut iter), ["a", "c", "d", "f"]);
+        assert_eq!(collect_backward(&mut iter), ["f", "d", "c", "a"]);
+
+        let mut iter = db_iter(&mem_tables, 5, None, None);
+        assert_eq!(collect_forward(&mut iter), ["a", "b", "c", "d", "e"]);
+        assert_eq!(collect_backward(&mut iter), ["e", "d", "c", "b", "a"]);
+    }
+
+    #[test]
+    fn test_prev_after_seek() {
+        let mem_tables = mem_tables();
+        let mut iter = db_iter(&mem_tables, 9, None, None);
+        iter.seek(b"c");
+        assert_eq!(entry(&iter), some_entry("c", "c2"));
+        // the deleted b is skipped backward as well
+        iter.prev();
+        assert_eq!(entry(&iter), some_entry("a", "a1"));
+        iter.prev();
+        assert_eq!(entry(&iter), None);
+
+        // seeking to a deleted key lands on the next key, and prev goes before the deleted one
+        iter.seek(b"b");
+        assert_eq!(entry(&iter), some_entry("c", "c2"));
+        iter.prev();
+        assert_eq!(entry(&iter), some_entry("a", "a1"));
+
+        // the older versions of c are not returned after turning around
+        iter.seek(b"d");
+        iter.prev();
+        assert_eq!(entry(&iter), some_entry("c", "c2"));
+        iter.next();
+        assert_eq!(entry(&iter), some_entry("d", "d1"));
+
+        // the entries newer than the sequence are not seen
+        let mut iter = db_iter(&mem_tables, 5, None, None);
+        iter.seek(b"c");
+        assert_eq!(entry(&iter), some_entry("c", "c1"));
+        iter.prev();
+        assert_eq!(entry(&iter), some_entry("b", "b1"));
+    }
+
+    #[test]
+    fn test_next_after_seek_to_last() {
+        let mem_tables = mem_tables();
+        let mut iter = db_iter(&mem_tables, 9, None, None);
+        iter.seek_to_last();
+        assert_eq!(entry(&iter), some_entry("f", "f1"));
+        iter.next();
+        assert_eq!(entry(&iter), None);
+
+        iter.seek_to_last();
+        iter.prev();
+        assert_eq!(entry(&iter), some_entry("d", "d1"));
+        iter.next();
+        assert_eq!(entry(&iter), some_entry("f", "f1"));
+
+        // the upper bound is not crossed by next
+        let mut iter = db_iter(&mem_tables, 9, None, Some(b"d"));
+        iter.seek_to_last();
+        assert_eq!(entry(&iter), some_entry("c", "c2"));
+        iter.next();
+        assert_eq!(entry(&iter), None);
+        assert_eq!(iter.status(), Ok(()));
+    }
+
+    #[test]
+    fn test_deletions_at_bounds() {
+        let mem_tables = mem_tables();
+        // b right at the lower bound and e right below the upper bound are deleted
+        let mut iter = db_iter(&mem_tables, 9, Some(b"b"), Some(b"f"));
+        assert_eq!(collect_forward(&mut iter), ["c", "d"]);
+        assert_eq!(collect_backward(&mut iter), ["d", "c"]);
+
+        iter.seek_to_first();
+        assert_eq!(entry(&iter), some_entry("c", "c2"));
+        iter.prev();
+        assert_eq!(entry(&iter), None);
+
+        iter.seek_to_last();
+        assert_eq!(entry(&iter), some_entry("d", "d1"));
+        iter.next();
+        assert_eq!(entry(&iter), None);
+
+        // a seek before the lower bound starts at the lower bound
+        iter.seek(b"a");
+        assert_eq!(entry(&iter), some_entry("c", "c2"));
+        iter.seek(b"e");
+        assert_eq!(entry(&iter), None);
+
+        // the deleted keys are visible at an older sequence within the same bounds
+        let mut iter = db_iter(&mem_tables, 5, Some(b"b"), Some(b"f"));
+        assert_eq!(collect_forward(&mut iter), ["b", "c", "d", "e"]);
+        assert_eq!(collect_backward(&mut iter), ["e", "d", "c", "b"]);
+    }
+
+    #[test]
+    fn test_empty_range() {
+        let mem_tables = mem_tables();
+        for (lower_bound, upper_bound) in [
+            // only a deleted key is in the range
+            (&b"b"[..], &b"c"[..]),
+            (b"e", b"f"),
+            // no key is in the range
+            (b"d", b"d"),
+            (b"bb", b"bc"),
+            (b"x", b"y"),
+        ] {
+            let mut iter = db_iter(&mem_tables, 9, Some(lower_bound), Some(upper_bound));
+            assert!(collect_forward(&mut iter).is_empty());
+            assert!(collect_backward(&mut iter).is_empty());
+            iter.seek(lower_bound);
+            assert!(!iter.valid());
+        }
+
+        // nothing is visible before the first sequence
+        let mut iter = db_iter(&mem_tables, 0, None, None);
+        assert!(collect_forward(&mut iter).is_empty());
+        assert!(collect_backward(&mut iter).is_empty());
+
+        let mut iter = db_iter(&[], 9, None, None);
+        assert!(collect_forward(&mut iter).is_empty());
+        assert!(collect_backward(&mut iter).is_empty());
+    }
+}
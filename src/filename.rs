@@ -0,0 +1,111 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FileType {
+    Log,
+    Table,
+    Temp,
+    Descriptor,
+    Current,
+}
+
+pub fn log_file_name(db_path: &Path, number: u64) -> PathBuf {
+    db_path.join(format!("{:06}.log", number))
+}
+
+pub fn table_file_name(db_path: &Path, number: u64) -> PathBuf {
+    db_path.join(format!("{:06}.ldb", number))
+}
+
+pub fn temp_file_name(db_path: &Path, number: u64) -> PathBuf {
+    db_path.join(format!("{:06}.dbtmp", number))
+}
+
+pub fn descriptor_file_name(db_path: &Path, number: u64) -> PathBuf {
+    db_path.join(format!("MANIFEST-{:06}", number))
+}
+
+// legacy_log_file_name returns the name of the only log of a db written before the logs were numbered
+pub fn legacy_log_file_name(db_path: &Path) -> PathBuf {
+    db_path.join("espikey.wal")
+}
+
+// current_file_name returns the name of the file which contains the name of the current manifest
+pub fn current_file_name(db_path: &Path) -> PathBuf {
+    db_path.join("CURRENT")
+}
+
+// set_current_file atomically points CURRENT to the manifest numbered descriptor_number
+pub fn set_current_file(db_path: &Path, descriptor_number: u64) -> std::io::Result<()> {
+    let manifest = descriptor_file_name(Path::new(""), descriptor_number);
+    let temp_path = temp_file_name(db_path, descriptor_number);
+
+    let mut file = File::create(&temp_path)?;
+    let result = file
+        .write_all(format!("{}\n", manifest.display()).as_bytes())
+        .and_then(|_| file.sync_all())
+        .and_then(|_| std::fs::rename(&temp_path, current_file_name(db_path)));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+// parse_file_name returns the number and type of a file which lives in the db directory
+pub fn parse_file_name(file_name: &str) -> Option<(u64, FileType)> {
+    if file_name == "CURRENT" {
+        return Some((0, FileType::Current));
+    }
+    if let Some(number) = file_name.strip_prefix("MANIFEST-") {
+        if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        return Some((number.parse().ok()?, FileType::Descriptor));
+    }
+
+    let (number, suffix) = file_name.split_once('.')?;
+    if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let number = number.parse().ok()?;
+
+    match suffix {
+        "log" => Some((number, FileType::Log)),
+        "ldb" => Some((number, FileType::Table)),
+        "dbtmp" => Some((number, FileType::Temp)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_file_name() {
+        let path = log_file_name(Path::new("/tmp/db"), 7);
+        assert_eq!(path, PathBuf::from("/tmp/db/000007.log"));
+
+        assert_eq!(parse_file_name("000007.log"), Some((7, FileType::Log)));
+        assert_eq!(
+            parse_file_name("1234567.log"),
+            Some((1234567, FileType::Log))
+        );
+        assert_eq!(parse_file_name("000008.ldb"), Some((8, FileType::Table)));
+        assert_eq!(parse_file_name("000009.dbtmp"), Some((9, FileType::Temp)));
+        assert_eq!(
+            parse_file_name("MANIFEST-000010"),
+            Some((10, FileType::Descriptor))
+        );
+        assert_eq!(parse_file_name("CURRENT"), Some((0, FileType::Current)));
+        assert_eq!(parse_file_name("MANIFEST-"), None);
+        assert_eq!(parse_file_name("MANIFEST-1.log"), None);
+        // the legacy log is renamed to a numbered one on open, so it's not a file of the db
+        assert_eq!(parse_file_name("espikey.wal"), None);
+        assert_eq!(parse_file_name(".log"), None);
+        assert_eq!(parse_file_name("+1.log"), None);
+        assert_eq!(parse_file_name("000007.ldb.tmp"), None);
+    }
+}
@@ -0,0 +1,197 @@
+use std::sync::Arc;
+
+use crate::filter_policy::FilterPolicy;
+use crate::{decode_fixed32, put_fixed32};
+
+// a filter is generated for every 2KB range of data block offsets
+const FILTER_BASE_LG: u8 = 11;
+const FILTER_BASE: u64 = 1 << FILTER_BASE_LG;
+
+// FilterBlockBuilder builds the filter block of a table.
+//
+// filter block format:
+//     [filter 0]
+//     ...
+//     [filter N-1]
+//     [offset of filter 0]: fixed32
+//     ...
+//     [offset of filter N-1]: fixed32
+//     [offset of the offset array]: fixed32
+//     [base lg]: 1-byte
+// filter i summarizes the keys of the data blocks which start in [i * base, (i + 1) * base).
+pub struct FilterBlockBuilder {
+    policy: Arc<dyn FilterPolicy>,
+    // the flattened keys for the next filter, and the start offset of each key
+    keys: Vec<u8>,
+    starts: Vec<usize>,
+    result: Vec<u8>,
+    filter_offsets: Vec<u32>,
+}
+
+impl FilterBlockBuilder {
+    pub fn new(policy: Arc<dyn FilterPolicy>) -> Self {
+        FilterBlockBuilder {
+            policy,
+            keys: Vec::new(),
+            starts: Vec::new(),
+            result: Vec::new(),
+            filter_offsets: Vec::new(),
+        }
+    }
+
+    // start_block must be called before the keys of the data block at block_offset are added
+    pub fn start_block(&mut self, block_offset: u64) {
+        let filter_index = block_offset / FILTER_BASE;
+        assert!(filter_index >= self.filter_offsets.len() as u64);
+        while filter_index > self.filter_offsets.len() as u64 {
+            self.generate_filter();
+        }
+    }
+
+    pub fn add_key(&mut self, key: &[u8]) {
+        self.starts.push(self.keys.len());
+        self.keys.extend_from_slice(key);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        if !self.starts.is_empty() {
+            self.generate_filter();
+        }
+
+        let array_offset = self.result.len() as u32;
+        for offset in std::mem::take(&mut self.filter_offsets) {
+            put_fixed32(&mut self.result, offset);
+        }
+        put_fixed32(&mut self.result, array_offset);
+        self.result.push(FILTER_BASE_LG);
+        self.result
+    }
+
+    fn generate_filter(&mut self) {
+        self.filter_offsets.push(self.result.len() as u32);
+        if self.starts.is_empty() {
+            // an empty filter for a range without data blocks
+            return;
+        }
+
+        let keys = self
+            .starts
+            .iter()
+            .enumerate()
+            .map(|(i, &start)| {
+                let limit = self.starts.get(i + 1).copied().unwrap_or(self.keys.len());
+                &self.keys[start..limit]
+            })
+            .collect::<Vec<_>>();
+        self.policy.create_filter(&keys, &mut self.result);
+
+        self.keys.clear();
+        self.starts.clear();
+    }
+}
+
+// FilterBlockReader looks up the filters in a filter block
+#[derive(Debug)]
+pub struct FilterBlockReader {
+    policy: Arc<dyn FilterPolicy>,
+    data: Vec<u8>,
+    // the offset of the offset array
+    array_offset: usize,
+    num_filters: usize,
+    base_lg: u8,
+}
+
+impl FilterBlockReader {
+    // new returns None if contents is not a valid filter block
+    pub fn new(policy: Arc<dyn FilterPolicy>, contents: Vec<u8>) -> Option<Self> {
+        // the offset of the offset array and the base lg
+        if contents.len() < 5 {
+            return None;
+        }
+        let base_lg = contents[contents.len() - 1];
+        let array_offset = decode_fixed32(&contents[contents.len() - 5..]) as usize;
+        if array_offset > contents.len() - 5 {
+            return None;
+        }
+        let num_filters = (contents.len() - 5 - array_offset) / 4;
+        Some(FilterBlockReader {
+            policy,
+            data: contents,
+            array_offset,
+            num_filters,
+            base_lg,
+        })
+    }
+
+    // key_may_match returns false if key is surely not in the data block at block_offset
+    pub fn key_may_match(&self, block_offset: u64, key: &[u8]) -> bool {
+        let index = (block_offset >> self.base_lg) as usize;
+        if index >= self.num_filters {
+            // errors are treated as potential matches
+            return true;
+        }
+        let offset = |i: usize| decode_fixed32(&self.data[self.array_offset + i * 4..]) as usize;
+        let start = offset(index);
+        let limit = if index + 1 < self.num_filters {
+            offset(index + 1)
+        } else {
+            self.array_offset
+        };
+        if start < limit && limit <= self.array_offset {
+            self.policy.key_may_match(key, &self.data[start..limit])
+        } else {
+            // an empty filter doesn't match any key
+            start != limit
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter_policy::BloomFilterPolicy;
+
+    #[test]
+    fn test_filter_block() {
+        let policy: Arc<dyn FilterPolicy> = Arc::new(BloomFilterPolicy::new(10));
+
+        // empty builder
+        let block = FilterBlockBuilder::new(policy.clone()).finish();
+        assert_eq!(block, vec![0, 0, 0, 0, FILTER_BASE_LG]);
+        let reader = FilterBlockReader::new(policy.clone(), block).unwrap();
+        assert!(reader.key_may_match(0, b"foo"));
+        assert!(reader.key_may_match(100000, b"foo"));
+
+        let mut builder = FilterBlockBuilder::new(policy.clone());
+        // first filter
+        builder.start_block(0);
+        builder.add_key(b"foo");
+        builder.add_key(b"bar");
+        builder.start_block(2000);
+        builder.add_key(b"box");
+        // second filter
+        builder.start_block(3100);
+        builder.add_key(b"box");
+        // third filter is empty, and last filter
+        builder.start_block(9000);
+        builder.add_key(b"box");
+        builder.add_key(b"hello");
+        let reader = FilterBlockReader::new(policy, builder.finish()).unwrap();
+
+        assert!(reader.key_may_match(0, b"foo"));
+        assert!(reader.key_may_match(2000, b"bar"));
+        assert!(reader.key_may_match(0, b"box"));
+        assert!(!reader.key_may_match(0, b"hello"));
+
+        assert!(reader.key_may_match(3100, b"box"));
+        assert!(!reader.key_may_match(3100, b"foo"));
+        assert!(!reader.key_may_match(3100, b"hello"));
+
+        assert!(!reader.key_may_match(4100, b"foo"));
+        assert!(!reader.key_may_match(4100, b"box"));
+
+        assert!(reader.key_may_match(9000, b"box"));
+        assert!(reader.key_may_match(9000, b"hello"));
+        assert!(!reader.key_may_match(9000, b"foo"));
+    }
+}
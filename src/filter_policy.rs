@@ -0,0 +1,181 @@
+use std::fmt;
+
+use crate::decode_fixed32;
+
+// FilterPolicy creates a small filter from a set of keys, which tells whether a key may be in the set.
+// a table stores a filter per data block range, so that a lookup can skip blocks which don't have the key.
+pub trait FilterPolicy: Send + Sync {
+    // name identifies the filter encoding. it's stored in the table, so it must change
+    // if the encoding changes in an incompatible way.
+    fn name(&self) -> &str;
+
+    // create_filter appends a filter which summarizes keys to dst
+    fn create_filter(&self, keys: &[&[u8]], dst: &mut Vec<u8>);
+
+    // key_may_match must return true if key was in the keys passed to create_filter.
+    // it may return true for other keys too, but should return false with a high probability.
+    fn key_may_match(&self, key: &[u8], filter: &[u8]) -> bool;
+}
+
+impl fmt::Debug for dyn FilterPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("FilterPolicy").field(&self.name()).finish()
+    }
+}
+
+// BloomFilterPolicy is the bloom filter of LevelDB, and its filters are compatible with LevelDB ones.
+#[derive(Debug, Clone)]
+pub struct BloomFilterPolicy {
+    bits_per_key: usize,
+    // the number of probes per key
+    k: usize,
+}
+
+impl BloomFilterPolicy {
+    // new returns a bloom filter with bits_per_key bits per key.
+    // 10 bits per key give a false positive rate of about 1%.
+    pub fn new(bits_per_key: usize) -> Self {
+        // 0.69 =~ ln(2) minimizes the false positive rate
+        let k = ((bits_per_key as f64 * 0.69) as usize).clamp(1, 30);
+        BloomFilterPolicy { bits_per_key, k }
+    }
+}
+
+impl FilterPolicy for BloomFilterPolicy {
+    fn name(&self) -> &str {
+        "leveldb.BuiltinBloomFilter2"
+    }
+
+    fn create_filter(&self, keys: &[&[u8]], dst: &mut Vec<u8>) {
+        // a very small filter has a high false positive rate, so it's at least 64 bits
+        let bits = std::cmp::max(keys.len() * self.bits_per_key, 64);
+        let bytes = bits.div_ceil(8);
+        let bits = bytes * 8;
+
+        let offset = dst.len();
+        dst.resize(offset + bytes, 0);
+        // the number of probes is stored, so that filters made with other parameters can be read
+        dst.push(self.k as u8);
+
+        let array = &mut dst[offset..offset + bytes];
+        for key in keys {
+            // double hashing generates the sequence of hash values
+            let mut h = bloom_hash(key);
+            let delta = h.rotate_right(17);
+            for _ in 0..self.k {
+                let bit = h as usize % bits;
+                array[bit / 8] |= 1 << (bit % 8);
+                h = h.wrapping_add(delta);
+            }
+        }
+    }
+
+    fn key_may_match(&self, key: &[u8], filter: &[u8]) -> bool {
+        if filter.len() < 2 {
+            return false;
+        }
+        let (array, k) = filter.split_at(filter.len() - 1);
+        let k = k[0];
+        if k > 30 {
+            // reserved for new encodings of short filters, so it's considered a match
+            return true;
+        }
+        let bits = array.len() * 8;
+
+        let mut h = bloom_hash(key);
+        let delta = h.rotate_right(17);
+        for _ in 0..k {
+            let bit = h as usize % bits;
+            if array[bit / 8] & (1 << (bit % 8)) == 0 {
+                return false;
+            }
+            h = h.wrapping_add(delta);
+        }
+        true
+    }
+}
+
+fn bloom_hash(key: &[u8]) -> u32 {
+    hash(key, 0xbc9f1d34)
+}
+
+// hash is the hash function of LevelDB, which is similar to murmur hash
+fn hash(data: &[u8], seed: u32) -> u32 {
+    const M: u32 = 0xc6a4a793;
+    const R: u32 = 24;
+    let mut h = seed ^ (data.len() as u32).wrapping_mul(M);
+
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        h = h.wrapping_add(decode_fixed32(chunk));
+        h = h.wrapping_mul(M);
+        h ^= h >> 16;
+    }
+
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, byte) in rest.iter().enumerate() {
+            h = h.wrapping_add((*byte as u32) << (8 * i));
+        }
+        h = h.wrapping_mul(M);
+        h ^= h >> R;
+    }
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash() {
+        // the values are the ones of LevelDB
+        assert_eq!(hash(&[], 0xbc9f1d34), 0xbc9f1d34);
+        assert_eq!(hash(&[0x62], 0xbc9f1d34), 0xef1345c4);
+        assert_eq!(hash(&[0xc3, 0x97], 0xbc9f1d34), 0x5b663814);
+        assert_eq!(hash(&[0xe2, 0x99, 0xa5], 0xbc9f1d34), 0x323c078f);
+        assert_eq!(hash(&[0xe1, 0x80, 0xb9, 0x32], 0xbc9f1d34), 0xed21633a);
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        let policy = BloomFilterPolicy::new(10);
+
+        let mut filter = Vec::new();
+        policy.create_filter(&[], &mut filter);
+        assert!(!policy.key_may_match(b"hello", &filter));
+        assert!(!policy.key_may_match(b"", &[]));
+
+        let mut filter = Vec::new();
+        policy.create_filter(&[b"hello", b"world"], &mut filter);
+        assert!(policy.key_may_match(b"hello", &filter));
+        assert!(policy.key_may_match(b"world", &filter));
+        assert!(!policy.key_may_match(b"x", &filter));
+        assert!(!policy.key_may_match(b"foo", &filter));
+
+        for length in [1, 10, 100, 1000, 10000] {
+            let keys = (0..length as u32)
+                .map(|i| i.to_le_bytes())
+                .collect::<Vec<_>>();
+            let keys = keys.iter().map(|k| k.as_slice()).collect::<Vec<_>>();
+            let mut filter = Vec::new();
+            policy.create_filter(&keys, &mut filter);
+            assert!(filter.len() <= length * 10 / 8 + 40);
+
+            // no false negatives
+            for key in &keys {
+                assert!(policy.key_may_match(key, &filter));
+            }
+
+            // the false positive rate is about 1%
+            let false_positives = (0..10000u32)
+                .filter(|i| policy.key_may_match(&(i + 1_000_000_000).to_le_bytes(), &filter))
+                .count();
+            assert!(
+                false_positives <= 200,
+                "{} false positives",
+                false_positives
+            );
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use crate::Result;
+
+// InternalIterator is a cursor over sorted key/value pairs which can move in both directions.
+//
+// an iterator is not valid until it's positioned by one of the seek methods.
+// key and value may only be called while the iterator is valid.
+// once an error happens, the iterator becomes invalid and status returns the error.
+pub trait InternalIterator {
+    fn valid(&self) -> bool;
+
+    fn seek_to_first(&mut self);
+
+    fn seek_to_last(&mut self);
+
+    // seek positions at the first entry whose key is not less than target
+    fn seek(&mut self, target: &[u8]);
+
+    fn next(&mut self);
+
+    fn prev(&mut self);
+
+    fn key(&self) -> &[u8];
+
+    fn value(&self) -> &[u8];
+
+    fn status(&self) -> Result<()>;
+}
+
+impl<I: InternalIterator + ?Sized> InternalIterator for Box<I> {
+    fn valid(&self) -> bool {
+        (**self).valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        (**self).seek_to_first()
+    }
+
+    fn seek_to_last(&mut self) {
+        (**self).seek_to_last()
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        (**self).seek(target)
+    }
+
+    fn next(&mut self) {
+        (**self).next()
+    }
+
+    fn prev(&mut self) {
+        (**self).prev()
+    }
+
+    fn key(&self) -> &[u8] {
+        (**self).key()
+    }
+
+    fn value(&self) -> &[u8] {
+        (**self).value()
+    }
+
+    fn status(&self) -> Result<()> {
+        (**self).status()
+    }
+}
@@ -1,14 +1,51 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
 use std::fs::File;
-use std::path::PathBuf;
-use std::{collections::HashMap, io::Write};
-
-use crate::write_batch::{ValueTypeCode, WriteBatch};
-use itertools::Itertools;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+pub use crate::cache::BlockCache;
+pub use crate::compression::CompressionType;
+pub use crate::db_iter::DbIterator;
+use crate::filename::{
+    current_file_name, legacy_log_file_name, log_file_name, parse_file_name, table_file_name,
+    temp_file_name, FileType,
+};
+pub use crate::filter_policy::{BloomFilterPolicy, FilterPolicy};
+use crate::iterator::InternalIterator;
+pub use crate::memtable::MemTable;
+use crate::merger::MergingIterator;
+pub use crate::snapshot::Snapshot;
+use crate::snapshot::SnapshotList;
+pub use crate::statistics::Statistics;
+use crate::table_cache::TableCache;
+use crate::version_edit::{FileMetaData, VersionEdit};
+use crate::version_set::{
+    Compaction, Version, VersionSet, L0_SLOWDOWN_WRITES_TRIGGER, L0_STOP_WRITES_TRIGGER,
+};
+use crate::write_batch::ValueTypeCode;
+pub use crate::write_batch::WriteBatch;
 use thiserror::Error;
 
+pub mod cache;
+pub mod compression;
+pub mod db_iter;
+pub mod filename;
+pub mod filter_block;
+pub mod filter_policy;
+pub mod iterator;
 pub mod log;
+pub mod memtable;
+pub mod merger;
+pub mod skiplist;
+pub mod snapshot;
+pub mod statistics;
 pub mod table;
+pub mod table_cache;
 pub mod version_edit;
+pub mod version_set;
 pub mod write_batch;
 
 pub type Result<T> = anyhow::Result<T, Status>;
@@ -17,13 +54,13 @@ pub type Result<T> = anyhow::Result<T, Status>;
 pub enum Status {
     #[error("Not found")]
     NotFound,
-    #[error("Corruption")]
-    Corruption,
+    #[error("Corruption: {0}")]
+    Corruption(String),
     #[error("Not supported")]
     NotSupported,
     #[error("Invalid argument")]
     InvalidArgument,
-    #[error("IO error")]
+    #[error("IO error: {0}")]
     IOError(#[from] std::io::Error),
 }
 
@@ -31,7 +68,7 @@ impl PartialEq<Self> for Status {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (Status::NotFound, Status::NotFound) => true,
-            (Status::Corruption, Status::Corruption) => true,
+            (Status::Corruption(m1), Status::Corruption(m2)) => m1 == m2,
             (Status::NotSupported, Status::NotSupported) => true,
             (Status::InvalidArgument, Status::InvalidArgument) => true,
             (Status::IOError(e1), Status::IOError(e2)) => {
@@ -44,6 +81,19 @@ impl PartialEq<Self> for Status {
 
 impl Eq for Status {}
 
+// io errors are not cloneable, so the clone keeps the kind and the message
+impl Clone for Status {
+    fn clone(&self) -> Self {
+        match self {
+            Status::NotFound => Status::NotFound,
+            Status::Corruption(msg) => Status::Corruption(msg.clone()),
+            Status::NotSupported => Status::NotSupported,
+            Status::InvalidArgument => Status::InvalidArgument,
+            Status::IOError(e) => Status::IOError(std::io::Error::new(e.kind(), e.to_string())),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum ValueType<'a> {
     Deletion(&'a [u8]),
@@ -60,87 +110,927 @@ impl<'a> ValueType<'a> {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct Options {
+    // amount of data to build up in memory before converting to a sorted on-disk table
+    pub write_buffer_size: usize,
+    // approximate size of user data packed per table block
+    pub block_size: usize,
+    // number of keys between restart points for delta encoding of keys
+    pub block_restart_interval: usize,
+    // compaction switches to a new table file when the current one reaches this size
+    pub max_file_size: u64,
+    // if true, the checksums of all blocks read by compactions are verified
+    pub paranoid_checks: bool,
+    // compression of the table blocks
+    pub compression: CompressionType,
+    // compression of the table blocks in each level, which overrides compression if it's not empty.
+    // the levels beyond the end use the last one.
+    pub compression_per_level: Vec<CompressionType>,
+    // if it's not zero, tables compressed with zstd by compactions have a dictionary of at most
+    // this size, which is trained from their data blocks. flushed memtables have no dictionary.
+    pub zstd_max_dict_bytes: usize,
+    // if it's given, tables have filters of their keys, which let lookups skip data blocks
+    pub filter_policy: Option<Arc<dyn FilterPolicy>>,
+    // counters of the events of the db
+    pub statistics: Arc<Statistics>,
+    // if it's given, data blocks read from tables are cached in it
+    pub block_cache: Option<Arc<BlockCache>>,
+    // number of files which the db can keep open. the tables beyond it are closed, and opened again when they are read.
+    pub max_open_files: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            write_buffer_size: 4 * 1024 * 1024,
+            block_size: 4 * 1024,
+            block_restart_interval: 16,
+            max_file_size: 2 * 1024 * 1024,
+            paranoid_checks: false,
+            compression: CompressionType::Snappy,
+            compression_per_level: Vec::new(),
+            zstd_max_dict_bytes: 0,
+            filter_policy: None,
+            statistics: Arc::new(Statistics::default()),
+            block_cache: Some(Arc::new(BlockCache::new(8 * 1024 * 1024))),
+            max_open_files: 1000,
+        }
+    }
+}
+
+impl Options {
+    // validate returns InvalidArgument if the options can't make a working db
+    fn validate(&self) -> Result<()> {
+        if self.write_buffer_size == 0
+            || self.block_size == 0
+            || self.block_restart_interval == 0
+            || self.max_file_size == 0
+        {
+            return Err(Status::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    // compression_for_level returns the compression of the tables in level
+    pub fn compression_for_level(&self, level: usize) -> CompressionType {
+        match self.compression_per_level.last() {
+            Some(last) => *self.compression_per_level.get(level).unwrap_or(last),
+            None => self.compression,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ReadOptions<'a> {
+    // reads see the state as of the snapshot, or the latest state if it's none
+    pub snapshot: Option<&'a Snapshot>,
+    // iterators only return keys which are not less than lower_bound
+    pub lower_bound: Option<&'a [u8]>,
+    // iterators only return keys which are less than upper_bound
+    pub upper_bound: Option<&'a [u8]>,
+    // if true, the checksums of all blocks read from tables are verified
+    pub verify_checksums: bool,
+    // if true, the blocks read from tables are added to the block cache.
+    // bulk scans may turn it off, so that they don't evict the blocks of other reads.
+    pub fill_cache: bool,
+}
+
+impl Default for ReadOptions<'_> {
+    fn default() -> Self {
+        ReadOptions {
+            snapshot: None,
+            lower_bound: None,
+            upper_bound: None,
+            verify_checksums: false,
+            fill_cache: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    // if true, the log is synced to the disk before the write returns,
+    // so that the write survives a crash of the machine as well as of the process
+    pub sync: bool,
+}
+
+// DB is a handle to an open db. it's Send and Sync, so that threads can share it through an Arc.
+//
+// writers are serialized by a mutex which owns the log, and the state shared with readers and the
+// background thread is kept behind another mutex which is held only briefly:
+// a read takes the memtables and the current version under the lock, and searches them without it.
+// flushes of the immutable memtable and compactions run on a dedicated background thread.
 #[derive(Debug)]
 pub struct DB {
-    mem_table: MemTable,
-    log_writer: log::Writer,
+    inner: Arc<DbInner>,
+    background_thread: Option<JoinHandle<()>>,
+}
 
-    sequence: u64,
-    wb: WriteBatch,
+#[derive(Debug)]
+struct DbInner {
+    db_path: PathBuf,
+    options: Options,
+    table_cache: Arc<TableCache>,
+    snapshots: Arc<SnapshotList>,
+
+    // written by the leader of a group of writers
+    log_writer: Mutex<log::Writer>,
+    state: Mutex<DbState>,
+    // signaled when there is background work or the db is closed
+    background_work: Condvar,
+    // signaled when the background thread finishes a piece of work
+    background_work_finished: Condvar,
+}
+
+#[derive(Debug)]
+struct DbState {
+    // the writers waiting to be written. the one at the front leads the next group.
+    writers: VecDeque<Arc<Writer>>,
+
+    mem_table: Arc<MemTable>,
+    // imm is the memtable being written to a table
+    imm: Option<Arc<MemTable>>,
+    // the number of the log which has the writes of mem_table
+    log_number: u64,
+
+    versions: VersionSet,
+    // the first error of the background work. it fails all later writes,
+    // since the state on the disk may no longer match the one in memory.
+    background_error: Option<Status>,
+    // true while the background thread is working, so that waiters know if more work may come
+    background_running: bool,
+    shutting_down: bool,
+}
+
+// Writer is a write waiting in the queue of writers
+#[derive(Debug)]
+struct Writer {
+    batch: WriteBatch,
+    sync: bool,
+    // set by the leader of the group which wrote the batch
+    result: Mutex<Option<Result<()>>>,
+    // signaled when the write is done, or when the writer comes to the front of the queue
+    cv: Condvar,
+}
+
+// the maximum size of the batches which a group commits together
+const MAX_WRITE_GROUP_SIZE: usize = 1024 * 1024;
+// a group of a small batch grows only by this size, so that a small write isn't slowed down much
+const SMALL_WRITE_GROUP_GROWTH: usize = 128 * 1024;
+
+// build_batch_group merges the batches of the writers from the front of the queue into one batch.
+// it returns the batch, the number of the writers merged, and whether the batch must be synced.
+fn build_batch_group(writers: &VecDeque<Arc<Writer>>) -> (WriteBatch, usize, bool) {
+    let leader = &writers[0];
+    let size = leader.batch.approximate_size();
+    let max_size = if size <= SMALL_WRITE_GROUP_GROWTH {
+        size + SMALL_WRITE_GROUP_GROWTH
+    } else {
+        MAX_WRITE_GROUP_SIZE
+    };
+
+    let mut batch = WriteBatch::new();
+    batch.append(&leader.batch);
+    let mut group_size = 1;
+    for writer in writers.iter().skip(1) {
+        // a sync write isn't put in a group which is not synced
+        if writer.sync && !leader.sync {
+            break;
+        }
+        if batch.approximate_size() + writer.batch.approximate_size() > max_size {
+            break;
+        }
+        batch.append(&writer.batch);
+        group_size += 1;
+    }
+    (batch, group_size, leader.sync)
 }
 
 impl DB {
     pub fn open(db_path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_options(db_path, Options::default())
+    }
+
+    pub fn open_with_options(db_path: impl Into<PathBuf>, options: Options) -> Result<Self> {
+        options.validate()?;
         let db_path = db_path.into();
+        std::fs::create_dir_all(&db_path)?;
+        if !current_file_name(&db_path).exists() {
+            VersionSet::create_new_db(&db_path)?;
+        }
 
-        let log_file = File::create(db_path.join("espikey.wal"))?;
+        let mut versions = VersionSet::new(&db_path, options.max_file_size);
+        versions.recover()?;
+
+        // the log of a db written before the logs were numbered becomes a numbered one,
+        // and it's recovered as the oldest log, since the db has written nothing else yet.
+        // the rename is atomic, so the log is replayed even if the db crashes in the middle of opening.
+        let legacy_log = legacy_log_file_name(&db_path);
+        if legacy_log.exists() {
+            let number = versions.new_file_number();
+            std::fs::rename(&legacy_log, log_file_name(&db_path, number))?;
+        }
+
+        // logs which are not older than the one recorded in the manifest are not written to tables yet
+        let mut log_numbers = Vec::new();
+        for entry in std::fs::read_dir(&db_path)? {
+            let file_name = entry?.file_name();
+            if let Some((number, FileType::Log)) = file_name.to_str().and_then(parse_file_name) {
+                if number >= versions.log_number() || number == versions.prev_log_number() {
+                    log_numbers.push(number);
+                }
+            }
+        }
+        log_numbers.sort();
+
+        // replay every log in the order it was written
+        let mem_table = MemTable::default();
+        let mut sequence = versions.last_sequence();
+        for &number in &log_numbers {
+            versions.mark_file_number_used(number);
+            let last_sequence =
+                recover_log_file(&log_file_name(&db_path, number), &options, &mem_table)?;
+            sequence = std::cmp::max(sequence, last_sequence);
+        }
+        versions.set_last_sequence(sequence);
+
+        // the recovered data is written to a table, so that the old logs can be removed
+        let mut edit = VersionEdit::default();
+        if !mem_table.is_empty() {
+            let number = versions.new_file_number();
+            if let Some(meta) = build_table(&db_path, &options, number, &mem_table)? {
+                edit.new_files.push((0, meta));
+            }
+        }
+
+        // fresh writes always go to a new log, so a torn tail of the old one is never appended to
+        let log_number = versions.new_file_number();
+        let log_file = File::create(log_file_name(&db_path, log_number))?;
         let log_writer = log::Writer::new(log_file);
-        Ok(DB {
-            mem_table: MemTable::default(),
-            log_writer,
 
-            // TODO: from manifest
-            sequence: 0,
-            wb: WriteBatch::new(),
+        edit.log_number = Some(log_number);
+        edit.prev_log_number = Some(0);
+        versions.log_and_apply(&mut edit)?;
+
+        let table_cache = Arc::new(TableCache::new(&db_path, &options));
+        let inner = Arc::new(DbInner {
+            db_path,
+            options,
+            table_cache,
+            snapshots: Arc::default(),
+
+            log_writer: Mutex::new(log_writer),
+            state: Mutex::new(DbState {
+                writers: VecDeque::new(),
+
+                mem_table: Arc::default(),
+                imm: None,
+                log_number,
+
+                versions,
+                background_error: None,
+                background_running: false,
+                shutting_down: false,
+            }),
+            background_work: Condvar::new(),
+            background_work_finished: Condvar::new(),
+        });
+        inner.remove_obsolete_files(&inner.state.lock().unwrap())?;
+
+        // the background thread starts with the compactions which the recovered state needs
+        let background_thread = std::thread::Builder::new()
+            .name("espikey-background".to_string())
+            .spawn({
+                let inner = inner.clone();
+                move || inner.background_loop()
+            })?;
+        Ok(DB {
+            inner,
+            background_thread: Some(background_thread),
         })
     }
 
-    pub fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
-        self.mem_table
-            .get(key)
-            .map(|v| v.to_vec())
-            .ok_or(Status::NotFound)
+    fn read_sequence(&self, options: &ReadOptions, state: &DbState) -> u64 {
+        match options.snapshot {
+            Some(snapshot) => snapshot.sequence(),
+            None => state.versions.last_sequence(),
+        }
     }
 
-    pub fn put(&mut self, key: &[u8], value: &[u8], sync: bool) -> Result<()> {
-        self.wb.clear();
-        self.wb.put(key, value);
-        self.write(sync)?;
-        Ok(())
+    // read_state returns the memtables, the current version and the sequence which a read sees.
+    // the lock is held only while they are taken, and they are searched without it.
+    fn read_state(
+        &self,
+        options: &ReadOptions,
+    ) -> (Arc<MemTable>, Option<Arc<MemTable>>, Arc<Version>, u64) {
+        let state = self.inner.state.lock().unwrap();
+        (
+            state.mem_table.clone(),
+            state.imm.clone(),
+            state.versions.current(),
+            self.read_sequence(options, &state),
+        )
     }
 
-    pub fn delete(&mut self, key: &[u8], sync: bool) -> Result<()> {
-        self.wb.clear();
-        self.wb.delete(key);
-        self.write(sync)?;
-        Ok(())
+    pub fn get(&self, options: &ReadOptions, key: &[u8]) -> Result<Vec<u8>> {
+        let (mem_table, imm, version, sequence) = self.read_state(options);
+        let lookup_key = InternalKey::new(key, sequence, ValueTypeCode::Value);
+
+        // newer data shadows older one: memtable, immutable memtable, and then tables from the newest
+        if let Some(item) = mem_table.get(&lookup_key) {
+            return value_or_not_found(item.map(|v| v.to_vec()));
+        }
+        if let Some(item) = imm.as_ref().and_then(|imm| imm.get(&lookup_key)) {
+            return value_or_not_found(item.map(|v| v.to_vec()));
+        }
+
+        match version.get(&self.inner.table_cache, options, &lookup_key)? {
+            Some(item) => value_or_not_found(item),
+            None => Err(Status::NotFound),
+        }
+    }
+
+    // multi_get looks up all keys as of one state of the db, and returns the results in the order of keys.
+    // the keys which fall in the same table are looked up together, so that a data block is read once.
+    pub fn multi_get(&self, options: &ReadOptions, keys: &[&[u8]]) -> Vec<Result<Vec<u8>>> {
+        let (mem_table, imm, version, sequence) = self.read_state(options);
+
+        let mut results = (0..keys.len())
+            .map(|_| Err(Status::NotFound))
+            .collect::<Vec<_>>();
+        let mut lookup_keys = Vec::new();
+        let mut indices = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            let lookup_key = InternalKey::new(key, sequence, ValueTypeCode::Value);
+            if let Some(item) = mem_table.get(&lookup_key) {
+                results[i] = value_or_not_found(item.map(|v| v.to_vec()));
+            } else if let Some(item) = imm.as_ref().and_then(|imm| imm.get(&lookup_key)) {
+                results[i] = value_or_not_found(item.map(|v| v.to_vec()));
+            } else {
+                lookup_keys.push(lookup_key);
+                indices.push(i);
+            }
+        }
+
+        let items = version.multi_get(&self.inner.table_cache, options, &lookup_keys);
+        for (i, item) in indices.into_iter().zip(items) {
+            results[i] = match item {
+                Ok(Some(item)) => value_or_not_found(item),
+                Ok(None) => Err(Status::NotFound),
+                Err(e) => Err(e),
+            };
+        }
+        results
+    }
+
+    // iter returns an iterator over the keys in the db as of the snapshot of options.
+    // the iterator is not valid until it's positioned by one of the seek methods.
+    pub fn iter(&self, options: &ReadOptions) -> Result<DbIterator> {
+        let (mem_table, imm, version, sequence) = self.read_state(options);
+
+        let mut children: Vec<Box<dyn InternalIterator + Send>> = vec![Box::new(mem_table.iter())];
+        if let Some(imm) = &imm {
+            children.push(Box::new(imm.iter()));
+        }
+        version.add_iterators(&self.inner.table_cache, options, &mut children)?;
+
+        let iter = MergingIterator::new(children, compare_internal_key);
+        Ok(DbIterator::new(
+            iter,
+            sequence,
+            options.lower_bound,
+            options.upper_bound,
+            version,
+        ))
     }
 
-    fn write(&mut self, sync: bool) -> Result<()> {
-        let mut last_sequence = self.sequence;
+    // snapshot returns a handle to the current state of the db.
+    // reads with the snapshot don't see the writes made after this call.
+    pub fn snapshot(&self) -> Snapshot {
+        let state = self.inner.state.lock().unwrap();
+        self.inner.snapshots.acquire(state.versions.last_sequence())
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8], sync: bool) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        self.write(&WriteOptions { sync }, batch)
+    }
 
-        self.wb.set_sequence(last_sequence + 1);
-        last_sequence += self.wb.get_count() as u64;
-        self.log_writer.append(self.wb.get_contents())?;
+    pub fn delete(&self, key: &[u8], sync: bool) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write(&WriteOptions { sync }, batch)
+    }
+
+    // write applies all updates in batch atomically.
+    // they are written to the log as one record, so either all or none of them are recovered after a crash,
+    // and they get consecutive sequence numbers, so that reads see either all or none of them.
+    //
+    // concurrent writes are committed in groups: writers wait in a queue, and the one at the front
+    // writes the batches of the writers behind it together with its own as one log record with one sync.
+    pub fn write(&self, options: &WriteOptions, batch: WriteBatch) -> Result<()> {
+        if batch.get_count() == 0 {
+            return Ok(());
+        }
+        let writer = Arc::new(Writer {
+            batch,
+            sync: options.sync,
+            result: Mutex::new(None),
+            cv: Condvar::new(),
+        });
+
+        let mut state = self.inner.state.lock().unwrap();
+        state.writers.push_back(writer.clone());
+        loop {
+            // the batch may have been written by the leader of a group
+            if let Some(result) = writer.result.lock().unwrap().take() {
+                return result;
+            }
+            if Arc::ptr_eq(state.writers.front().unwrap(), &writer) {
+                break;
+            }
+            state = writer.cv.wait(state).unwrap();
+        }
+
+        // this writer leads a group of the writers at the front of the queue
+        let (state, room) = self.make_room_for_write(state);
+        let (mut state, group_size, result) = match room {
+            Ok(mem_table) => {
+                let (mut batch, group_size, sync) = build_batch_group(&state.writers);
+                let mut last_sequence = state.versions.last_sequence();
+                batch.set_sequence(last_sequence + 1);
+                last_sequence += batch.get_count() as u64;
+                drop(state);
+
+                // the log and the memtable are written without the state lock, so that readers and
+                // the next writers joining the queue are not blocked by the sync.
+                // readers don't see the new entries until the last sequence is published.
+                let result = self.write_to_log(&batch, sync).and_then(|()| {
+                    let statistics = &self.inner.options.statistics;
+                    statistics.record_write_group(group_size, sync);
+                    batch.apply_to(&mem_table)
+                });
+
+                let mut state = self.inner.state.lock().unwrap();
+                match &result {
+                    Ok(()) => state.versions.set_last_sequence(last_sequence),
+                    // the log may have the record while the memtable doesn't,
+                    // so the db refuses later writes rather than letting them disagree
+                    Err(e) => {
+                        if state.background_error.is_none() {
+                            state.background_error = Some(e.clone());
+                        }
+                    }
+                }
+                (state, group_size, result)
+            }
+            Err(e) => (state, 1, Err(e)),
+        };
 
+        // the writers in the group are done, and the next writer in the queue leads the next group
+        for _ in 0..group_size {
+            let done = state.writers.pop_front().unwrap();
+            if !Arc::ptr_eq(&done, &writer) {
+                *done.result.lock().unwrap() = Some(result.clone());
+                done.cv.notify_one();
+            }
+        }
+        if let Some(next) = state.writers.front() {
+            next.cv.notify_one();
+        }
+        result
+    }
+
+    fn write_to_log(&self, batch: &WriteBatch, sync: bool) -> Result<()> {
+        // only the leader of a group writes to the log, so the lock is never contended
+        let mut log_writer = self.inner.log_writer.lock().unwrap();
+        log_writer.append(batch.get_contents())?;
         if sync {
-            self.log_writer.sync()?;
+            log_writer.sync()?;
+        }
+        Ok(())
+    }
+
+    // make_room_for_write switches to a new memtable and log when the current memtable is full,
+    // and lets the background thread write the full one to a table.
+    // it returns the memtable to write to.
+    // writes are slowed down and then stopped as level 0 grows, so that the compaction keeps up with them.
+    fn make_room_for_write<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, DbState>,
+    ) -> (MutexGuard<'a, DbState>, Result<Arc<MemTable>>) {
+        let statistics = &self.inner.options.statistics;
+        let mut allow_delay = true;
+        loop {
+            if let Some(e) = &state.background_error {
+                let e = e.clone();
+                return (state, Err(e));
+            }
+            let level0_files = state.versions.current().num_level_files(0);
+            if allow_delay && level0_files >= L0_SLOWDOWN_WRITES_TRIGGER {
+                // rather than stopping a write for seconds when the stop trigger is hit,
+                // every write is delayed by 1ms once, which also gives the background thread the cpu.
+                // the lock is released, so that the compaction is not blocked by the delay.
+                drop(state);
+                let start = Instant::now();
+                std::thread::sleep(Duration::from_millis(1));
+                statistics.record_write_slowdown(start.elapsed());
+                allow_delay = false;
+                state = self.inner.state.lock().unwrap();
+                continue;
+            }
+            if state.mem_table.approximate_memory_usage() < self.inner.options.write_buffer_size {
+                let mem_table = state.mem_table.clone();
+                return (state, Ok(mem_table));
+            }
+            if state.imm.is_some() || level0_files >= L0_STOP_WRITES_TRIGGER {
+                // the previous memtable is still being written, or level 0 has too many files
+                if state.imm.is_none() {
+                    ::log::info!("too many level 0 files: {}, waiting", level0_files);
+                }
+                let start = Instant::now();
+                state = self.inner.background_work_finished.wait(state).unwrap();
+                statistics.record_write_stop(start.elapsed());
+                continue;
+            }
+
+            let log_number = state.versions.new_file_number();
+            let log_file = match File::create(log_file_name(&self.inner.db_path, log_number)) {
+                Ok(file) => file,
+                Err(e) => return (state, Err(e.into())),
+            };
+            *self.inner.log_writer.lock().unwrap() = log::Writer::new(log_file);
+            state.log_number = log_number;
+            state.imm = Some(std::mem::take(&mut state.mem_table));
+            self.inner.background_work.notify_one();
+        }
+    }
+}
+
+impl Drop for DB {
+    // the background thread finishes the work in progress and stops.
+    // a memtable which is not written yet is recovered from its log on the next open.
+    fn drop(&mut self) {
+        self.inner.state.lock().unwrap().shutting_down = true;
+        self.inner.background_work.notify_all();
+        if let Some(thread) = self.background_thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl DbInner {
+    fn has_background_work(&self, state: &DbState) -> bool {
+        state.imm.is_some() || state.versions.current().needs_compaction()
+    }
+
+    // background_loop runs on the background thread, and writes the immutable memtable to a table
+    // or runs a compaction whenever they are needed, until the db is closed
+    fn background_loop(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.shutting_down {
+                return;
+            }
+            // the background work stops at the first error
+            if state.background_error.is_some() || !self.has_background_work(&state) {
+                state = self.background_work.wait(state).unwrap();
+                continue;
+            }
+
+            state.background_running = true;
+            let result = if state.imm.is_some() {
+                self.compact_mem_table(state)
+            } else {
+                self.compact(state)
+            };
+            state = match result {
+                Ok(state) => state,
+                Err(e) => {
+                    ::log::error!("background work failed: {}", e);
+                    let mut state = self.state.lock().unwrap();
+                    state.background_error = Some(e);
+                    state
+                }
+            };
+            state.background_running = false;
+            self.background_work_finished.notify_all();
         }
-        self.wb.apply_to(&mut self.mem_table)?;
+    }
+
+    // compact_mem_table writes the immutable memtable to a table in level 0.
+    // the lock is released while the table is written.
+    fn compact_mem_table<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, DbState>,
+    ) -> Result<MutexGuard<'a, DbState>> {
+        let number = state.versions.new_file_number();
+        let imm = state.imm.clone().expect("no immutable memtable to compact");
+        // the logs older than the current one are no longer needed once the table is recorded
+        let mut edit = VersionEdit {
+            log_number: Some(state.log_number),
+            prev_log_number: Some(0),
+            ..VersionEdit::default()
+        };
+        drop(state);
 
-        self.sequence = last_sequence;
+        let meta = build_table(&self.db_path, &self.options, number, &imm)?;
+
+        let mut state = self.state.lock().unwrap();
+        // an empty memtable leaves no table, but its log is still done with
+        if let Some(meta) = meta {
+            edit.new_files.push((0, meta));
+        }
+        state.versions.log_and_apply(&mut edit)?;
+        state.imm = None;
+        self.remove_obsolete_files(&state)?;
+        Ok(state)
+    }
+
+    // compact runs the compaction of the level which needs it most
+    fn compact<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, DbState>,
+    ) -> Result<MutexGuard<'a, DbState>> {
+        let Some(mut compaction) = state.versions.pick_compaction() else {
+            return Ok(state);
+        };
+        if compaction.is_trivial_move() {
+            // nothing to merge with, so the file is just moved to the next level
+            let file = compaction.inputs[0][0].clone();
+            compaction.add_input_deletions();
+            compaction
+                .edit
+                .new_files
+                .push((compaction.level + 1, file.as_ref().clone()));
+            state.versions.log_and_apply(&mut compaction.edit)?;
+        } else {
+            // versions visible to the oldest live snapshot must be kept
+            let smallest_snapshot = self
+                .snapshots
+                .oldest()
+                .unwrap_or_else(|| state.versions.last_sequence());
+            drop(state);
+            self.do_compaction_work(&mut compaction, smallest_snapshot)?;
+            state = self.state.lock().unwrap();
+            state.versions.log_and_apply(&mut compaction.edit)?;
+        }
+        self.remove_obsolete_files(&state)?;
+        Ok(state)
+    }
+
+    // do_compaction_work merges the input files into new files in the next level, and adds them to the edit.
+    // older versions of a key and deletion markers which no reader can observe are dropped.
+    // it runs without the lock, since only the background thread changes the versions.
+    fn do_compaction_work(
+        &self,
+        compaction: &mut Compaction,
+        smallest_snapshot: u64,
+    ) -> Result<()> {
+        let read_options = ReadOptions {
+            verify_checksums: self.options.paranoid_checks,
+            // the inputs are read once, and they are removed after the compaction
+            fill_cache: false,
+            ..ReadOptions::default()
+        };
+        let mut input = compaction.make_input_iterator(&self.table_cache, &read_options)?;
+        input.seek_to_first();
+
+        let mut outputs = Vec::new();
+        let mut builder: Option<CompactionOutput> = None;
+        let mut current_user_key: Option<Vec<u8>> = None;
+        let mut last_sequence_for_key = MAX_SEQUENCE_NUMBER;
+        while input.valid() {
+            let ikey = InternalKey::decode_from(input.key());
+
+            let first_occurrence = current_user_key.as_deref() != Some(ikey.user_key());
+            if first_occurrence {
+                current_user_key = Some(ikey.user_key().to_vec());
+                last_sequence_for_key = MAX_SEQUENCE_NUMBER;
+
+                // an output is only cut between user keys, so that a user key never spans two files.
+                // it's cut when it's full, or when it overlaps too much of the grandparent level.
+                let overlaps_grandparents = compaction.should_stop_before(input.key());
+                if builder.as_ref().is_some_and(|output| {
+                    overlaps_grandparents
+                        || output.builder.file_size() >= self.options.max_file_size
+                }) {
+                    outputs.push(builder.take().unwrap().finish(&self.db_path)?);
+                }
+            }
+
+            let drop = if last_sequence_for_key <= smallest_snapshot {
+                // hidden by a newer entry for the same user key
+                true
+            } else {
+                ikey.value_type_code()? == ValueTypeCode::Deletion
+                    && ikey.sequence() <= smallest_snapshot
+                    && compaction.is_base_level_for_key(ikey.user_key())
+            };
+            last_sequence_for_key = ikey.sequence();
+
+            if !drop {
+                if builder.is_none() {
+                    let number = self.state.lock().unwrap().versions.new_file_number();
+                    builder = Some(CompactionOutput::new(
+                        &self.db_path,
+                        &self.options,
+                        compaction.level + 1,
+                        number,
+                    )?);
+                }
+                builder.as_mut().unwrap().add(ikey, input.value())?;
+            }
+            input.next();
+        }
+        input.status()?;
+        if let Some(output) = builder {
+            outputs.push(output.finish(&self.db_path)?);
+        }
+
+        compaction.add_input_deletions();
+        for output in outputs {
+            compaction
+                .edit
+                .new_files
+                .push((compaction.level + 1, output));
+        }
+        Ok(())
+    }
+
+    // remove_obsolete_files deletes files which are not referenced by the current state.
+    // it's called only by the background thread and the open, so no table is being written meanwhile.
+    fn remove_obsolete_files(&self, state: &DbState) -> Result<()> {
+        let live_files = state.versions.live_files();
+        for entry in std::fs::read_dir(&self.db_path)? {
+            let entry = entry?;
+            let Some((number, file_type)) = entry.file_name().to_str().and_then(parse_file_name)
+            else {
+                continue;
+            };
+            let keep = match file_type {
+                FileType::Log => {
+                    number >= state.versions.log_number()
+                        || number == state.versions.prev_log_number()
+                }
+                FileType::Descriptor => number >= state.versions.manifest_file_number(),
+                FileType::Table => live_files.contains(&number),
+                // left by an unfinished flush
+                FileType::Temp => false,
+                FileType::Current => true,
+            };
+            if !keep {
+                if file_type == FileType::Table {
+                    self.table_cache.evict(number);
+                }
+                std::fs::remove_file(entry.path())?;
+            }
+        }
         Ok(())
     }
 }
 
-pub(crate) fn put_varint32(buf: &mut Vec<u8>, mut value: u32) -> usize {
-    let mut cnt = 0;
-    while {
-        let mut byte = (value & 0x7f) as u8;
-        value >>= 7;
-        if value != 0 {
-            byte |= 0x80;
+fn value_or_not_found(item: ValueItem<Vec<u8>>) -> Result<Vec<u8>> {
+    match item {
+        ValueItem::Value(v) => Ok(v),
+        ValueItem::Deletion => Err(Status::NotFound),
+    }
+}
+
+// CompactionOutput is a table file being written by a compaction
+struct CompactionOutput {
+    number: u64,
+    builder: table::TableBuilder,
+    smallest: Option<InternalKey>,
+    largest: Option<InternalKey>,
+}
+
+impl CompactionOutput {
+    fn new(db_path: &Path, options: &Options, level: usize, number: u64) -> Result<Self> {
+        let compression = options.compression_for_level(level);
+        let file = File::create(temp_file_name(db_path, number))?;
+        Ok(CompactionOutput {
+            number,
+            builder: table::TableBuilder::with_compression(options, compression, file)
+                .with_zstd_dictionary(),
+            smallest: None,
+            largest: None,
+        })
+    }
+
+    fn add(&mut self, key: InternalKey, value: &[u8]) -> Result<()> {
+        self.builder.add(key.get_contents(), value)?;
+        if self.smallest.is_none() {
+            self.smallest = Some(key.clone());
         }
-        buf.push(byte);
-        cnt += 1;
+        self.largest = Some(key);
+        Ok(())
+    }
 
-        value != 0
-    } {}
-    cnt
+    fn finish(self, db_path: &Path) -> Result<FileMetaData> {
+        let file_size = self.builder.finish()?;
+        std::fs::rename(
+            temp_file_name(db_path, self.number),
+            table_file_name(db_path, self.number),
+        )?;
+        Ok(FileMetaData {
+            number: self.number,
+            file_size,
+            smallest: self.smallest.expect("an empty table is never finished"),
+            largest: self.largest.expect("an empty table is never finished"),
+        })
+    }
 }
 
-#[allow(dead_code)]
-pub(crate) fn put_varint64(buf: &mut Vec<u8>, mut value: u64) -> usize {
+// build_table writes the contents of mem_table to the table file numbered number.
+// the table is written to a temporary file first, so that a half-written table never becomes visible.
+// no table is written for an empty memtable, and it returns None.
+fn build_table(
+    db_path: &Path,
+    options: &Options,
+    number: u64,
+    mem_table: &MemTable,
+) -> Result<Option<FileMetaData>> {
+    if mem_table.is_empty() {
+        return Ok(None);
+    }
+    let temp_path = temp_file_name(db_path, number);
+    let file = File::create(&temp_path)?;
+
+    // a flushed table always goes to level 0
+    let mut builder =
+        table::TableBuilder::with_compression(options, options.compression_for_level(0), file);
+    let mut iter = mem_table.iter();
+    iter.seek_to_first();
+    let smallest = InternalKey::decode_from(iter.key());
+    let mut largest = smallest.clone();
+    while iter.valid() {
+        builder.add(iter.key(), iter.value())?;
+        largest = InternalKey::decode_from(iter.key());
+        iter.next();
+    }
+    let file_size = builder.finish()?;
+
+    std::fs::rename(&temp_path, table_file_name(db_path, number))?;
+    Ok(Some(FileMetaData {
+        number,
+        file_size,
+        smallest,
+        largest,
+    }))
+}
+
+// LogReporter logs the bytes dropped while a log file is recovered
+struct LogReporter {
+    path: PathBuf,
+}
+
+impl log::Reporter for LogReporter {
+    fn corruption(&mut self, bytes: usize, reason: &Status) {
+        ::log::warn!(
+            "{}: dropping {} bytes; {}",
+            self.path.display(),
+            bytes,
+            reason
+        );
+    }
+}
+
+// recover_log_file applies every batch in the log to mem_table, and returns the last sequence in it.
+// corrupted records fail the recovery with paranoid checks, and are skipped otherwise.
+fn recover_log_file(path: &Path, options: &Options, mem_table: &MemTable) -> Result<u64> {
+    let file = File::open(path)?;
+    let mode = if options.paranoid_checks {
+        log::RecoveryMode::Stop
+    } else {
+        log::RecoveryMode::SkipToNextBlock
+    };
+    let mut reader = log::Reader::with_reporter(
+        file,
+        Box::new(LogReporter {
+            path: path.to_path_buf(),
+        }),
+        mode,
+    );
+
+    let mut last_sequence = 0;
+    while let Some(record) = reader.read()? {
+        let wb = WriteBatch::from(record)?;
+        // an empty batch is never written, but it has no sequence to recover if it's there
+        if wb.get_count() == 0 {
+            continue;
+        }
+        wb.apply_to(mem_table)?;
+
+        let batch_last_sequence = wb.get_sequence() + wb.get_count() as u64 - 1;
+        last_sequence = std::cmp::max(last_sequence, batch_last_sequence);
+    }
+    Ok(last_sequence)
+}
+
+pub(crate) fn put_varint32(buf: &mut Vec<u8>, mut value: u32) -> usize {
     let mut cnt = 0;
     while {
         let mut byte = (value & 0x7f) as u8;
@@ -156,7 +1046,7 @@ pub(crate) fn put_varint64(buf: &mut Vec<u8>, mut value: u64) -> usize {
     cnt
 }
 
-pub(crate) fn write_varint32<W: Write>(writer: &mut W, mut value: u32) -> std::io::Result<usize> {
+pub(crate) fn put_varint64(buf: &mut Vec<u8>, mut value: u64) -> usize {
     let mut cnt = 0;
     while {
         let mut byte = (value & 0x7f) as u8;
@@ -164,12 +1054,12 @@ pub(crate) fn write_varint32<W: Write>(writer: &mut W, mut value: u32) -> std::i
         if value != 0 {
             byte |= 0x80;
         }
-        writer.write_all(&[byte])?;
+        buf.push(byte);
         cnt += 1;
 
         value != 0
     } {}
-    Ok(cnt)
+    cnt
 }
 
 pub(crate) fn decode_varint32(buf: &[u8]) -> Option<(u32, usize)> {
@@ -272,26 +1162,14 @@ fn test_decode_varint() {
     buf.clear();
 }
 
-#[allow(dead_code)]
 pub(crate) fn put_fixed32(buf: &mut Vec<u8>, value: u32) {
     buf.extend_from_slice(&value.to_le_bytes());
 }
 
-fn write_fixed32<W: Write>(writer: &mut W, value: u32) -> std::io::Result<()> {
-    writer.write_all(&value.to_le_bytes())?;
-    Ok(())
-}
-
-#[allow(dead_code)]
 pub(crate) fn put_fixed64(buf: &mut Vec<u8>, value: u64) {
     buf.extend_from_slice(&value.to_le_bytes());
 }
 
-fn write_fixed64<W: Write>(writer: &mut W, value: u64) -> std::io::Result<()> {
-    writer.write_all(&value.to_le_bytes())?;
-    Ok(())
-}
-
 pub(crate) fn encode_fixed32(buf: &mut [u8], value: u32) {
     assert!(
         buf.len() >= 4,
@@ -352,12 +1230,26 @@ fn test_length_prefixed_slice() {
     assert_eq!(offset, 6);
 }
 
-#[derive(Debug)]
+// sequence numbers are packed with a value type into 8 bytes, so the top 8 bits are not usable
+pub const MAX_SEQUENCE_NUMBER: u64 = (1 << 56) - 1;
+
+// InternalKey is a user key followed by fixed64(sequence << 8 | value type).
+// internal keys are ordered by user key ascending, and then by sequence descending,
+// so that the newest entry for a user key comes first.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct InternalKey {
     rep: Vec<u8>,
 }
 
 impl InternalKey {
+    pub fn new(user_key: &[u8], sequence: u64, value_type: ValueTypeCode) -> Self {
+        assert!(sequence <= MAX_SEQUENCE_NUMBER);
+        let mut rep = Vec::with_capacity(user_key.len() + 8);
+        rep.extend_from_slice(user_key);
+        put_fixed64(&mut rep, sequence << 8 | value_type as u64);
+        InternalKey { rep }
+    }
+
     pub fn user_key(&self) -> &[u8] {
         assert!(self.rep.len() >= 8);
         &self.rep[..self.rep.len() - 8]
@@ -374,7 +1266,7 @@ impl InternalKey {
         match self.rep[self.rep.len() - 8] {
             0 => Ok(ValueTypeCode::Deletion),
             1 => Ok(ValueTypeCode::Value),
-            _ => Err(Status::Corruption),
+            _ => Err(Status::Corruption("unknown value type".to_string())),
         }
     }
 
@@ -387,8 +1279,31 @@ impl InternalKey {
     }
 }
 
-#[allow(dead_code)]
-struct BlockBuilder {
+// parse_internal_key splits an encoded internal key into user key, sequence and value type
+pub(crate) fn parse_internal_key(ikey: &[u8]) -> Result<(&[u8], u64, ValueTypeCode)> {
+    if ikey.len() < 8 {
+        return Err(Status::Corruption("internal key too short".to_string()));
+    }
+    let (user_key, tag) = ikey.split_at(ikey.len() - 8);
+    let tag = decode_fixed64(tag);
+    let value_type = match tag & 0xff {
+        0 => ValueTypeCode::Deletion,
+        1 => ValueTypeCode::Value,
+        _ => return Err(Status::Corruption("unknown value type".to_string())),
+    };
+    Ok((user_key, tag >> 8, value_type))
+}
+
+pub(crate) fn compare_internal_key(a: &[u8], b: &[u8]) -> Ordering {
+    assert!(a.len() >= 8 && b.len() >= 8);
+    let (a_user_key, a_tag) = a.split_at(a.len() - 8);
+    let (b_user_key, b_tag) = b.split_at(b.len() - 8);
+    a_user_key
+        .cmp(b_user_key)
+        .then_with(|| decode_fixed64(b_tag).cmp(&decode_fixed64(a_tag)))
+}
+
+pub(crate) struct BlockBuilder {
     buf: Vec<u8>,
     restarts: Vec<u32>,
     last_key: Vec<u8>,
@@ -397,9 +1312,8 @@ struct BlockBuilder {
     block_restart_interval: usize,
 }
 
-#[allow(dead_code)]
 impl BlockBuilder {
-    fn new(block_restart_interval: usize) -> Self {
+    pub(crate) fn new(block_restart_interval: usize) -> Self {
         BlockBuilder {
             buf: Vec::new(),
             restarts: vec![0],
@@ -409,7 +1323,16 @@ impl BlockBuilder {
         }
     }
 
-    fn add(&mut self, key: &[u8], value: &[u8]) {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    // current_size_estimate returns the size of the block if it's finished now
+    pub(crate) fn current_size_estimate(&self) -> usize {
+        self.buf.len() + self.restarts.len() * 4 + 4
+    }
+
+    pub(crate) fn add(&mut self, key: &[u8], value: &[u8]) {
         let mut shared = 0;
         if self.counter < self.block_restart_interval {
             let min_length = std::cmp::min(key.len(), self.last_key.len());
@@ -433,7 +1356,7 @@ impl BlockBuilder {
         self.counter += 1;
     }
 
-    fn finish(mut self) -> Vec<u8> {
+    pub(crate) fn finish(mut self) -> Vec<u8> {
         // Write restarts
         for restart in self.restarts.iter() {
             self.buf.extend_from_slice(&restart.to_le_bytes());
@@ -457,151 +1380,1108 @@ impl<T> ValueItem<T> {
             ValueItem::Value(v) => ValueItem::Value(f(v)),
         }
     }
+}
 
-    fn as_ref(&self) -> ValueItem<&T> {
-        match self {
-            ValueItem::Deletion => ValueItem::Deletion,
-            ValueItem::Value(v) => ValueItem::Value(v),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl DB {
+        // wait_for_background_work waits until the background thread has nothing to do,
+        // and returns the error of the background work if any
+        fn wait_for_background_work(&self) -> Result<()> {
+            let mut state = self.inner.state.lock().unwrap();
+            while state.background_error.is_none()
+                && (state.background_running || self.inner.has_background_work(&state))
+            {
+                state = self.inner.background_work_finished.wait(state).unwrap();
+            }
+            match &state.background_error {
+                Some(e) => Err(e.clone()),
+                None => Ok(()),
+            }
+        }
+
+        fn last_sequence(&self) -> u64 {
+            self.inner.state.lock().unwrap().versions.last_sequence()
+        }
+
+        fn live_files(&self) -> std::collections::HashSet<u64> {
+            self.inner.state.lock().unwrap().versions.live_files()
+        }
+
+        fn current_version(&self) -> Arc<Version> {
+            self.inner.state.lock().unwrap().versions.current()
+        }
+
+        // table_entries returns the number of the entries of user_key in the tables,
+        // which counts the old versions and the deletions of it
+        fn table_entries(&self, user_key: &[u8]) -> usize {
+            let version = self.current_version();
+            let mut count = 0;
+            for file in version.files.iter().flatten() {
+                let mut iter = self
+                    .inner
+                    .table_cache
+                    .iter(&ReadOptions::default(), file.number)
+                    .unwrap();
+                iter.seek_to_first();
+                while iter.valid() {
+                    if InternalKey::decode_from(iter.key()).user_key() == user_key {
+                        count += 1;
+                    }
+                    iter.next();
+                }
+            }
+            count
+        }
+
+        // stop_background_work stops the background thread, so that the tables pile up in level 0
+        fn stop_background_work(&self) {
+            self.inner.state.lock().unwrap().shutting_down = true;
+            self.inner.background_work.notify_all();
+        }
+
+        // run_background_work does the background work on the calling thread
+        // as the background thread would do
+        fn run_background_work(&self) -> Result<()> {
+            let mut state = self.inner.state.lock().unwrap();
+            while self.inner.has_background_work(&state) {
+                state = if state.imm.is_some() {
+                    self.inner.compact_mem_table(state)?
+                } else {
+                    self.inner.compact(state)?
+                };
+                self.inner.background_work_finished.notify_all();
+            }
+            Ok(())
+        }
+
+        // add_level0_tables adds n tables to level 0, each of which has a key
+        fn add_level0_tables(&self, n: usize) {
+            let mut state = self.inner.state.lock().unwrap();
+            for i in 0..n {
+                let mem_table = MemTable::default();
+                let key = format!("level0-{}", i);
+                mem_table.add(1, ValueTypeCode::Value, key.as_bytes(), b"value");
+                let number = state.versions.new_file_number();
+                let meta =
+                    build_table(&self.inner.db_path, &self.inner.options, number, &mem_table)
+                        .unwrap()
+                        .unwrap();
+                let mut edit = VersionEdit::default();
+                edit.new_files.push((0, meta));
+                state.versions.log_and_apply(&mut edit).unwrap();
+            }
         }
     }
-}
 
-#[derive(Debug, Default)]
-pub struct MemTable {
-    total_bytes: usize,
-    entry_count: usize,
-    items: HashMap<Vec<u8>, ValueItem<Vec<u8>>>,
-}
+    #[test]
+    fn test_db_recover() {
+        let db_path = "/tmp/test_db_recover";
+        let _ = std::fs::remove_dir_all(db_path);
+
+        let db = DB::open(db_path).unwrap();
+        db.put(b"key1", b"value1", false).unwrap();
+        db.put(b"key2", b"value2", false).unwrap();
+        db.delete(b"key1", false).unwrap();
+        drop(db);
+
+        let db = DB::open(db_path).unwrap();
+        assert_eq!(db.last_sequence(), 3);
+        assert_eq!(
+            db.get(&ReadOptions::default(), b"key1"),
+            Err(Status::NotFound)
+        );
+        assert_eq!(
+            db.get(&ReadOptions::default(), b"key2"),
+            Ok(b"value2".to_vec())
+        );
 
-impl MemTable {
-    pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a [u8]> {
-        self.items.get(key).and_then(|v| match v {
-            ValueItem::Deletion => None,
-            ValueItem::Value(v) => Some(v.as_slice()),
-        })
+        db.put(b"key1", b"value3", false).unwrap();
+        drop(db);
+
+        let db = DB::open(db_path).unwrap();
+        assert_eq!(db.last_sequence(), 4);
+        assert_eq!(
+            db.get(&ReadOptions::default(), b"key1"),
+            Ok(b"value3".to_vec())
+        );
+        assert_eq!(
+            db.get(&ReadOptions::default(), b"key2"),
+            Ok(b"value2".to_vec())
+        );
+
+        // an empty batch with sequence 0 in the log doesn't move the last sequence
+        let empty = WriteBatch::new();
+        assert_eq!((empty.get_sequence(), empty.get_count()), (0, 0));
+        db.inner
+            .log_writer
+            .lock()
+            .unwrap()
+            .append(empty.get_contents())
+            .unwrap();
+        drop(db);
+
+        let db = DB::open(db_path).unwrap();
+        assert_eq!(db.last_sequence(), 4);
     }
 
-    pub fn set(&mut self, key: &[u8], value: &[u8]) {
-        self.items
-            .entry(key.to_vec())
-            .and_modify(|v| {
-                if let ValueItem::Value(v) = v {
-                    self.total_bytes -= v.len();
+    #[test]
+    fn test_db_recover_legacy_log() {
+        let db_path = Path::new("/tmp/test_db_recover_legacy_log");
+        let _ = std::fs::remove_dir_all(db_path);
+        std::fs::create_dir_all(db_path).unwrap();
+
+        // a db written before the logs were numbered has only its log
+        let mut contents = Vec::new();
+        for (sequence, key, value) in [
+            (1, b"key1", Some(b"value1")),
+            (2, b"key2", Some(b"value2")),
+            (3, b"key1", None),
+        ] {
+            let mut batch = WriteBatch::new();
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            }
+            batch.set_sequence(sequence);
+            contents.extend(log::encode_legacy_record(batch.get_contents()));
+        }
+        std::fs::write(legacy_log_file_name(db_path), contents).unwrap();
+
+        let check = |db: &DB| {
+            assert_eq!(db.last_sequence(), 3);
+            assert_eq!(
+                db.get(&ReadOptions::default(), b"key1"),
+                Err(Status::NotFound)
+            );
+            assert_eq!(
+                db.get(&ReadOptions::default(), b"key2"),
+                Ok(b"value2".to_vec())
+            );
+        };
+        let db = DB::open(db_path).unwrap();
+        check(&db);
+        assert!(!legacy_log_file_name(db_path).exists());
+        drop(db);
+
+        // the migrated data stays after the db is opened again
+        let db = DB::open(db_path).unwrap();
+        check(&db);
+    }
+
+    #[test]
+    fn test_db_write_batch() {
+        let db_path = "/tmp/test_db_write_batch";
+        let _ = std::fs::remove_dir_all(db_path);
+
+        let db = DB::open(db_path).unwrap();
+        db.put(b"key1", b"value1", false).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"key2", b"value2");
+        batch.delete(b"key1");
+        batch.put(b"key3", b"value3");
+        db.write(&WriteOptions { sync: true }, batch).unwrap();
+        assert_eq!(db.last_sequence(), 4);
+
+        // an empty batch doesn't consume a sequence number
+        db.write(&WriteOptions::default(), WriteBatch::new())
+            .unwrap();
+        assert_eq!(db.last_sequence(), 4);
+        drop(db);
+
+        // the batch is recovered as a whole
+        let db = DB::open(db_path).unwrap();
+        assert_eq!(db.last_sequence(), 4);
+        assert_eq!(
+            db.get(&ReadOptions::default(), b"key1"),
+            Err(Status::NotFound)
+        );
+        assert_eq!(
+            db.get(&ReadOptions::default(), b"key2"),
+            Ok(b"value2".to_vec())
+        );
+        assert_eq!(
+            db.get(&ReadOptions::default(), b"key3"),
+            Ok(b"value3".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_internal_key() {
+        let key = InternalKey::new(b"key", 100, ValueTypeCode::Deletion);
+        assert_eq!(key.user_key(), b"key");
+        assert_eq!(key.sequence(), 100);
+        assert_eq!(key.value_type_code(), Ok(ValueTypeCode::Deletion));
+
+        let older = InternalKey::new(b"key", 99, ValueTypeCode::Value);
+        let larger = InternalKey::new(b"key0", MAX_SEQUENCE_NUMBER, ValueTypeCode::Value);
+        let cmp = |a: &InternalKey, b: &InternalKey| {
+            compare_internal_key(a.get_contents(), b.get_contents())
+        };
+        assert_eq!(cmp(&key, &key), Ordering::Equal);
+        assert_eq!(cmp(&key, &older), Ordering::Less);
+        assert_eq!(cmp(&older, &larger), Ordering::Less);
+        assert_eq!(cmp(&larger, &key), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_db_invalid_options() {
+        let db_path = "/tmp/test_db_invalid_options";
+        let _ = std::fs::remove_dir_all(db_path);
+
+        for options in [
+            Options {
+                write_buffer_size: 0,
+                ..Options::default()
+            },
+            Options {
+                block_size: 0,
+                ..Options::default()
+            },
+            Options {
+                block_restart_interval: 0,
+                ..Options::default()
+            },
+            Options {
+                max_file_size: 0,
+                ..Options::default()
+            },
+        ] {
+            assert_eq!(
+                DB::open_with_options(db_path, options).err(),
+                Some(Status::InvalidArgument)
+            );
+        }
+        // nothing is created for the invalid options
+        assert!(!Path::new(db_path).exists());
+
+        // an empty memtable leaves no table
+        std::fs::create_dir_all(db_path).unwrap();
+        let meta = build_table(
+            Path::new(db_path),
+            &Options::default(),
+            1,
+            &MemTable::default(),
+        );
+        assert!(meta.unwrap().is_none());
+        assert_eq!(std::fs::read_dir(db_path).unwrap().count(), 0);
+
+        // the smallest valid write buffer flushes a memtable of one write before the next one
+        let options = Options {
+            write_buffer_size: 1,
+            ..Options::default()
+        };
+        let db = DB::open_with_options(db_path, options).unwrap();
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            db.put(key.as_bytes(), b"value", false).unwrap();
+        }
+        db.wait_for_background_work().unwrap();
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            assert_eq!(
+                db.get(&ReadOptions::default(), key.as_bytes()),
+                Ok(b"value".to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn test_db_flush() {
+        let db_path = "/tmp/test_db_flush";
+        let _ = std::fs::remove_dir_all(db_path);
+
+        let options = Options {
+            write_buffer_size: 1024,
+            block_size: 256,
+            ..Options::default()
+        };
+        let db = DB::open_with_options(db_path, options.clone()).unwrap();
+        for i in 0..1000 {
+            let key = format!("key{:04}", i % 300);
+            let value = format!("value{}", i);
+            db.put(key.as_bytes(), value.as_bytes(), false).unwrap();
+            if i % 7 == 0 {
+                db.delete(key.as_bytes(), false).unwrap();
+            }
+        }
+        assert!(!db.live_files().is_empty());
+
+        let check = |db: &DB| {
+            for i in 700..1000 {
+                let key = format!("key{:04}", i % 300);
+                let expected = if i % 7 == 0 {
+                    Err(Status::NotFound)
+                } else {
+                    Ok(format!("value{}", i).into_bytes())
+                };
+                assert_eq!(
+                    db.get(&ReadOptions::default(), key.as_bytes()),
+                    expected,
+                    "{}",
+                    key
+                );
+            }
+            assert_eq!(
+                db.get(&ReadOptions::default(), b"missing"),
+                Err(Status::NotFound)
+            );
+        };
+        check(&db);
+        drop(db);
+
+        let db = DB::open_with_options(db_path, options).unwrap();
+        check(&db);
+        db.wait_for_background_work().unwrap();
+
+        // flushed logs and old manifests are removed
+        let count_files = |expected_type: FileType| {
+            std::fs::read_dir(db_path)
+                .unwrap()
+                .filter_map(|entry| parse_file_name(entry.unwrap().file_name().to_str()?))
+                .filter(|(_, file_type)| *file_type == expected_type)
+                .count()
+        };
+        assert_eq!(count_files(FileType::Log), 1);
+        assert_eq!(count_files(FileType::Descriptor), 1);
+        assert_eq!(count_files(FileType::Table), db.live_files().len());
+    }
+
+    #[test]
+    fn test_db_shared_block_cache() {
+        let db_paths = [
+            "/tmp/test_db_shared_block_cache_a",
+            "/tmp/test_db_shared_block_cache_b",
+        ];
+        // the dbs share the block cache of the options, and their tables have the same file numbers
+        let options = Options {
+            write_buffer_size: 1024,
+            block_size: 256,
+            ..Options::default()
+        };
+        let dbs = db_paths.map(|db_path| {
+            let _ = std::fs::remove_dir_all(db_path);
+            let db = DB::open_with_options(db_path, options.clone()).unwrap();
+            for i in 0..100 {
+                let key = format!("key{:04}", i);
+                let value = format!("{}-value{}", &db_path[db_path.len() - 1..], i);
+                db.put(key.as_bytes(), value.as_bytes(), false).unwrap();
+            }
+            db.wait_for_background_work().unwrap();
+            db
+        });
+        assert_eq!(dbs[0].live_files(), dbs[1].live_files());
+
+        // every db reads its own blocks, whichever of them fills the cache first
+        for _ in 0..2 {
+            for (db, name) in dbs.iter().zip(["a", "b"]) {
+                for i in 0..100 {
+                    let key = format!("key{:04}", i);
+                    assert_eq!(
+                        db.get(&ReadOptions::default(), key.as_bytes()),
+                        Ok(format!("{}-value{}", name, i).into_bytes())
+                    );
                 }
-                self.total_bytes += value.len();
-                *v = ValueItem::Value(value.to_vec());
-            })
-            .or_insert_with(|| {
-                self.total_bytes += key.len() + value.len();
-                self.entry_count += 1;
-                ValueItem::Value(value.to_vec())
-            });
-    }
-
-    pub fn delete(&mut self, key: &[u8]) {
-        self.items
-            .entry(key.to_vec())
-            .and_modify(|v| {
-                if let ValueItem::Value(v) = v {
-                    self.total_bytes -= v.len();
+            }
+        }
+        assert!(options.block_cache.unwrap().hits() > 0);
+    }
+
+    #[test]
+    fn test_db_multi_get() {
+        let db_path = "/tmp/test_db_multi_get";
+        let _ = std::fs::remove_dir_all(db_path);
+
+        let options = Options {
+            write_buffer_size: 1024,
+            block_size: 256,
+            ..Options::default()
+        };
+        let db = DB::open_with_options(db_path, options).unwrap();
+        for i in 0..1000 {
+            let key = format!("key{:04}", i % 300);
+            db.put(key.as_bytes(), format!("value{}", i).as_bytes(), false)
+                .unwrap();
+            if i % 7 == 0 {
+                db.delete(key.as_bytes(), false).unwrap();
+            }
+        }
+        let snapshot = db.snapshot();
+        // these are not seen by the snapshot
+        db.put(b"key0001", b"new", false).unwrap();
+        db.delete(b"key0002", false).unwrap();
+
+        // keys in the memtable and in the tables, duplicated, and missing
+        let keys = (0..300)
+            .rev()
+            .map(|i| format!("key{:04}", i))
+            .chain(["key0001".to_string(), "missing".to_string()])
+            .collect::<Vec<_>>();
+        let keys = keys.iter().map(|k| k.as_bytes()).collect::<Vec<_>>();
+        for options in [
+            ReadOptions::default(),
+            ReadOptions {
+                snapshot: Some(&snapshot),
+                ..ReadOptions::default()
+            },
+        ] {
+            let results = db.multi_get(&options, &keys);
+            assert_eq!(results.len(), keys.len());
+            for (key, result) in keys.iter().zip(results) {
+                assert_eq!(result, db.get(&options, key), "{:?}", key);
+            }
+        }
+        assert_eq!(
+            db.multi_get(&ReadOptions::default(), &[b"key0001", b"key0002"]),
+            vec![Ok(b"new".to_vec()), Err(Status::NotFound)]
+        );
+    }
+
+    #[test]
+    fn test_db_compaction() {
+        let db_path = "/tmp/test_db_compaction";
+        let _ = std::fs::remove_dir_all(db_path);
+
+        let options = Options {
+            write_buffer_size: 2 * 1024,
+            block_size: 256,
+            max_file_size: 4 * 1024,
+            ..Options::default()
+        };
+        let db = DB::open_with_options(db_path, options.clone()).unwrap();
+        for i in 0..5000 {
+            let key = format!("key{:04}", (i * 7) % 1000);
+            let value = format!("value{}", i);
+            db.put(key.as_bytes(), value.as_bytes(), false).unwrap();
+        }
+        for i in 0..1000 {
+            if i % 3 == 0 {
+                let key = format!("key{:04}", i);
+                db.delete(key.as_bytes(), false).unwrap();
+            }
+        }
+
+        db.wait_for_background_work().unwrap();
+        let version = db.current_version();
+        assert!(version.files[0].len() < version_set::L0_COMPACTION_TRIGGER);
+        assert!(version.files[1].len() > 1);
+        // files in level 1 are disjoint
+        for pair in version.files[1].windows(2) {
+            assert!(pair[0].largest.user_key() < pair[1].smallest.user_key());
+        }
+        // only the latest version of each key survives in level 1
+        let num_entries = version.files[1]
+            .iter()
+            .map(|f| {
+                let file = File::open(table_file_name(Path::new(db_path), f.number)).unwrap();
+                let mut iter =
+                    Arc::new(table::Table::open(&Options::default(), file, f.number).unwrap())
+                        .iter(&ReadOptions::default());
+                let mut count = 0;
+                iter.seek_to_first();
+                while iter.valid() {
+                    count += 1;
+                    iter.next();
                 }
-                *v = ValueItem::Deletion;
+                count
             })
-            .or_insert_with(|| {
-                self.total_bytes += key.len();
-                self.entry_count += 1;
-                ValueItem::Deletion
-            });
+            .sum::<usize>();
+        assert!(num_entries <= 1000);
+
+        let check = |db: &DB| {
+            for i in 4000..5000 {
+                let key = format!("key{:04}", (i * 7) % 1000);
+                let expected = if (i * 7) % 1000 % 3 == 0 {
+                    Err(Status::NotFound)
+                } else {
+                    Ok(format!("value{}", i).into_bytes())
+                };
+                assert_eq!(
+                    db.get(&ReadOptions::default(), key.as_bytes()),
+                    expected,
+                    "{}",
+                    key
+                );
+            }
+        };
+        check(&db);
+        drop(db);
+
+        let db = DB::open_with_options(db_path, options).unwrap();
+        check(&db);
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&[u8], ValueItem<&[u8]>)> {
-        self.items
-            .iter()
-            .sorted_by(|(k1, _), (k2, _)| k1.cmp(k2))
-            .map(|(k, v)| (k.as_slice(), v.as_ref().map(|v| v.as_slice())))
+    #[test]
+    fn test_db_zstd_dictionary() {
+        let db_path = "/tmp/test_db_zstd_dictionary";
+        let _ = std::fs::remove_dir_all(db_path);
+
+        let options = Options {
+            write_buffer_size: 16 * 1024,
+            block_size: 256,
+            max_file_size: 64 * 1024,
+            compression: CompressionType::Zstd,
+            zstd_max_dict_bytes: 256,
+            ..Options::default()
+        };
+        let value = |i: usize| {
+            format!(
+                "{{\"id\":{},\"name\":\"user{}\",\"score\":{}}}",
+                i,
+                i * 7,
+                i % 13
+            )
+        };
+
+        // a flushed memtable is written without a dictionary
+        let mem_table = MemTable::default();
+        for i in 0..1000 {
+            let key = format!("key{:04}", i);
+            mem_table.add(
+                i as u64 + 1,
+                ValueTypeCode::Value,
+                key.as_bytes(),
+                value(i).as_bytes(),
+            );
+        }
+        std::fs::create_dir_all(db_path).unwrap();
+        build_table(Path::new(db_path), &options, 1, &mem_table)
+            .unwrap()
+            .unwrap();
+        let file = File::open(table_file_name(Path::new(db_path), 1)).unwrap();
+        assert!(!table::Table::open(&options, file, 1)
+            .unwrap()
+            .has_dictionary());
+        let _ = std::fs::remove_dir_all(db_path);
+
+        // the tables written by compactions have dictionaries
+        let db = DB::open_with_options(db_path, options.clone()).unwrap();
+        for i in 0..10000 {
+            let key = format!("key{:04}", (i * 7) % 2000);
+            db.put(key.as_bytes(), value(i).as_bytes(), false).unwrap();
+        }
+        db.wait_for_background_work().unwrap();
+        let version = db.current_version();
+        let has_dictionary = |f: &Arc<FileMetaData>| {
+            db.inner
+                .table_cache
+                .find_table(f.number)
+                .unwrap()
+                .has_dictionary()
+        };
+        assert!(!version.files[1].is_empty());
+        assert!(version.files[1].iter().any(has_dictionary));
+        assert!(!version.files[0].iter().any(has_dictionary));
+
+        for i in 8000..10000 {
+            let key = format!("key{:04}", (i * 7) % 2000);
+            assert_eq!(
+                db.get(&ReadOptions::default(), key.as_bytes()),
+                Ok(value(i).into_bytes())
+            );
+        }
     }
-}
 
-pub fn serialize_to_sstable<W: Write>(writer: &mut W, memtable: MemTable) -> anyhow::Result<()> {
-    write_fixed32(writer, memtable.entry_count as u32)?;
-    for (k, v) in memtable.iter() {
-        write_varint32(writer, k.len() as u32)?;
-        writer.write_all(k)?;
+    #[test]
+    fn test_db_snapshot() {
+        let db_path = "/tmp/test_db_snapshot";
+        let _ = std::fs::remove_dir_all(db_path);
+
+        let options = Options {
+            write_buffer_size: 2 * 1024,
+            block_size: 256,
+            // level 1 holds all keys, so that every compaction into it meets the old versions
+            max_file_size: 16 * 1024,
+            ..Options::default()
+        };
+        let db = DB::open_with_options(db_path, options).unwrap();
+        db.put(b"key", b"value1", false).unwrap();
+        db.put(b"deleted", b"value1", false).unwrap();
+        let snapshot1 = db.snapshot();
+        db.put(b"key", b"value2", false).unwrap();
+        db.delete(b"deleted", false).unwrap();
+        let snapshot2 = db.snapshot();
+        db.put(b"key", b"value3", false).unwrap();
+
+        fn get(db: &DB, snapshot: Option<&Snapshot>, key: &[u8]) -> Result<Vec<u8>> {
+            let options = ReadOptions {
+                snapshot,
+                ..ReadOptions::default()
+            };
+            db.get(&options, key)
+        }
+        fn check(db: &DB, snapshot1: Option<&Snapshot>, snapshot2: Option<&Snapshot>) {
+            assert_eq!(get(db, snapshot1, b"key"), Ok(b"value1".to_vec()));
+            assert_eq!(get(db, snapshot1, b"deleted"), Ok(b"value1".to_vec()));
+            assert_eq!(get(db, snapshot2, b"key"), Ok(b"value2".to_vec()));
+            assert_eq!(get(db, snapshot2, b"deleted"), Err(Status::NotFound));
+            assert_eq!(get(db, None, b"key"), Ok(b"value3".to_vec()));
+        }
+        check(&db, Some(&snapshot1), Some(&snapshot2));
 
-        match v {
-            ValueItem::Deletion => {
-                let tag = 0u64;
-                write_fixed64(writer, tag)?;
-                write_fixed32(writer, 0)?;
+        // the versions pinned by the snapshots survive flushes and compactions
+        for i in 0..5000 {
+            let key = format!("key{:04}", (i * 7) % 1000);
+            db.put(key.as_bytes(), b"value", false).unwrap();
+        }
+        db.wait_for_background_work().unwrap();
+        assert!(!db.current_version().files[1].is_empty());
+        check(&db, Some(&snapshot1), Some(&snapshot2));
+        assert_eq!(db.table_entries(b"key"), 3);
+        assert_eq!(db.table_entries(b"deleted"), 2);
+
+        // once the snapshots are released, compaction drops the old versions
+        drop(snapshot1);
+        drop(snapshot2);
+        assert_eq!(db.inner.snapshots.oldest(), None);
+        for i in 0..5000 {
+            let key = format!("key{:04}", (i * 7) % 1000);
+            db.put(key.as_bytes(), b"value", false).unwrap();
+        }
+        db.wait_for_background_work().unwrap();
+        assert_eq!(get(&db, None, b"key"), Ok(b"value3".to_vec()));
+        assert_eq!(get(&db, None, b"deleted"), Err(Status::NotFound));
+        assert_eq!(db.table_entries(b"key"), 1);
+        assert_eq!(db.table_entries(b"deleted"), 0);
+    }
+
+    #[test]
+    fn test_db_iter() {
+        let db_path = "/tmp/test_db_iter";
+        let _ = std::fs::remove_dir_all(db_path);
+
+        let options = Options {
+            write_buffer_size: 2 * 1024,
+            block_size: 256,
+            max_file_size: 4 * 1024,
+            ..Options::default()
+        };
+        let db = DB::open_with_options(db_path, options).unwrap();
+
+        // the expected state is kept in a BTreeMap
+        let mut model = std::collections::BTreeMap::new();
+        let mut snapshot = None;
+        for i in 0..3000 {
+            let key = format!("key{:04}", (i * 7) % 500).into_bytes();
+            if i % 5 == 0 {
+                db.delete(&key, false).unwrap();
+                model.remove(&key);
+            } else {
+                let value = format!("value{}", i).into_bytes();
+                db.put(&key, &value, false).unwrap();
+                model.insert(key, value);
+            }
+            if i == 2000 {
+                snapshot = Some((db.snapshot(), model.clone()));
+            }
+        }
+        db.wait_for_background_work().unwrap();
+        assert!(!db.current_version().files[1].is_empty());
+
+        fn collect(iter: &mut DbIterator, forward: bool) -> Vec<(Vec<u8>, Vec<u8>)> {
+            let mut entries = Vec::new();
+            while iter.valid() {
+                entries.push((iter.key().to_vec(), iter.value().to_vec()));
+                if forward {
+                    iter.next();
+                } else {
+                    iter.prev();
+                }
             }
-            ValueItem::Value(v) => {
-                let tag = 1u64;
-                write_fixed64(writer, tag)?;
-                write_fixed32(writer, v.len() as u32)?;
+            iter.status().unwrap();
+            entries
+        }
 
-                writer.write_all(v)?;
+        let check = |options: &ReadOptions, model: &std::collections::BTreeMap<_, _>| {
+            let lower = options.lower_bound.map(|b| b.to_vec());
+            let upper = options.upper_bound.map(|b| b.to_vec());
+            let expected = model
+                .iter()
+                .filter(|(k, _)| lower.as_ref().is_none_or(|l| *k >= l))
+                .filter(|(k, _)| upper.as_ref().is_none_or(|u| *k < u))
+                .map(|(k, v): (&Vec<u8>, &Vec<u8>)| (k.clone(), v.clone()))
+                .collect::<Vec<_>>();
+
+            let mut iter = db.iter(options).unwrap();
+            iter.seek_to_first();
+            assert_eq!(collect(&mut iter, true), expected);
+            iter.seek_to_last();
+            let mut backward = collect(&mut iter, false);
+            backward.reverse();
+            assert_eq!(backward, expected);
+
+            for target in ["", "key0100", "key0100x", "key0250", "key0499", "key9"] {
+                let position = expected.partition_point(|(k, _)| k.as_slice() < target.as_bytes());
+                iter.seek(target.as_bytes());
+                match expected.get(position) {
+                    Some((key, value)) => {
+                        assert_eq!(iter.key(), key.as_slice());
+                        assert_eq!(iter.value(), value.as_slice());
+                    }
+                    None => assert!(!iter.valid()),
+                }
+
+                // change the direction
+                if iter.valid() {
+                    iter.prev();
+                    match position.checked_sub(1).map(|p| &expected[p]) {
+                        Some((key, _)) => {
+                            assert_eq!(iter.key(), key.as_slice());
+                            iter.next();
+                            assert_eq!(iter.key(), expected[position].0.as_slice());
+                        }
+                        None => assert!(!iter.valid()),
+                    }
+                }
             }
+        };
+
+        check(&ReadOptions::default(), &model);
+        check(
+            &ReadOptions {
+                lower_bound: Some(b"key0100"),
+                upper_bound: Some(b"key0300"),
+                ..ReadOptions::default()
+            },
+            &model,
+        );
+
+        let (snapshot, snapshot_model) = snapshot.unwrap();
+        check(
+            &ReadOptions {
+                snapshot: Some(&snapshot),
+                ..ReadOptions::default()
+            },
+            &snapshot_model,
+        );
+
+        // an iterator keeps reading the files of its version while compactions replace them
+        let mut iter = db.iter(&ReadOptions::default()).unwrap();
+        for i in 0..3000 {
+            let key = format!("key{:04}", (i * 7) % 500);
+            db.put(key.as_bytes(), b"new", false).unwrap();
         }
+        iter.seek_to_first();
+        let expected = model.into_iter().collect::<Vec<_>>();
+        assert_eq!(collect(&mut iter, true), expected);
     }
-    Ok(())
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_db_concurrent() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<DB>();
+
+        let db_path = "/tmp/test_db_concurrent";
+        let _ = std::fs::remove_dir_all(db_path);
+
+        let options = Options {
+            write_buffer_size: 4 * 1024,
+            block_size: 256,
+            max_file_size: 8 * 1024,
+            ..Options::default()
+        };
+        let db = Arc::new(DB::open_with_options(db_path, options.clone()).unwrap());
+
+        // writers write disjoint keys while readers check that a key never goes back to an older value
+        let writers = (0..4)
+            .map(|t| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    for i in 0..2000 {
+                        let key = format!("key{}-{:04}", t, i % 500);
+                        db.put(key.as_bytes(), format!("{:04}", i).as_bytes(), i % 100 == 0)
+                            .unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let readers = (0..2)
+            .map(|_| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    let mut last = 0;
+                    for _ in 0..2000 {
+                        match db.get(&ReadOptions::default(), b"key0-0000") {
+                            Ok(value) => {
+                                let value = String::from_utf8(value).unwrap().parse().unwrap();
+                                assert!(value >= last);
+                                last = value;
+                            }
+                            Err(e) => assert_eq!(e, Status::NotFound),
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in writers.into_iter().chain(readers) {
+            thread.join().unwrap();
+        }
 
-    macro_rules! v {
-        ($e:expr) => {
-            $e.as_slice()
+        let check = |db: &DB| {
+            for t in 0..4 {
+                for i in 1500..2000 {
+                    let key = format!("key{}-{:04}", t, i % 500);
+                    assert_eq!(
+                        db.get(&ReadOptions::default(), key.as_bytes()),
+                        Ok(format!("{:04}", i).into_bytes())
+                    );
+                }
+            }
         };
+        db.wait_for_background_work().unwrap();
+        assert_eq!(db.last_sequence(), 4 * 2000);
+        assert!(!db.current_version().files[1].is_empty());
+        check(&db);
+        drop(db);
+
+        let db = DB::open_with_options(db_path, options).unwrap();
+        check(&db);
     }
 
     #[test]
-    fn test_memtable() {
-        let mut memtable = MemTable::default();
-        memtable.set(b"key1", b"value1");
-        memtable.set(b"key2", b"value2");
-        memtable.set(b"key0", b"value");
-        memtable.set(b"key0", b"value0");
-        memtable.delete(b"key1");
-
-        assert_eq!(memtable.get(b"key0"), Some(v!(b"value0")));
-        assert_eq!(memtable.get(b"key1"), None);
-        assert_eq!(memtable.entry_count, 3);
-
-        let iter = memtable.iter();
-        let kvs = iter.collect::<Vec<_>>();
-        assert_eq!(
-            kvs,
-            vec![
-                (v!(b"key0"), ValueItem::Value(v!(b"value0"))),
-                (v!(b"key1"), ValueItem::Deletion),
-                (v!(b"key2"), ValueItem::Value(v!(b"value2"))),
-            ]
-        );
+    fn test_build_batch_group() {
+        let writer = |size: usize, sync: bool| {
+            let mut batch = WriteBatch::new();
+            batch.put(b"key", &vec![0; size]);
+            Arc::new(Writer {
+                batch,
+                sync,
+                result: Mutex::new(None),
+                cv: Condvar::new(),
+            })
+        };
+
+        let writers = VecDeque::from([writer(10, true), writer(10, false), writer(10, true)]);
+        let (batch, group_size, sync) = build_batch_group(&writers);
+        assert_eq!((batch.get_count(), group_size, sync), (3, 3, true));
+
+        // a sync write doesn't join a group which is not synced
+        let writers = VecDeque::from([writer(10, false), writer(10, false), writer(10, true)]);
+        let (batch, group_size, sync) = build_batch_group(&writers);
+        assert_eq!((batch.get_count(), group_size, sync), (2, 2, false));
+
+        // a small write grows the group by a limited size
+        let writers = VecDeque::from([
+            writer(10, false),
+            writer(100 * 1024, false),
+            writer(100 * 1024, false),
+        ]);
+        assert_eq!(build_batch_group(&writers).1, 2);
+        let writers = VecDeque::from([
+            writer(512 * 1024, false),
+            writer(256 * 1024, false),
+            writer(512 * 1024, false),
+        ]);
+        assert_eq!(build_batch_group(&writers).1, 2);
+    }
+
+    #[test]
+    fn test_db_group_commit() {
+        let db_path = "/tmp/test_db_group_commit";
+        let _ = std::fs::remove_dir_all(db_path);
+
+        let statistics = Arc::new(Statistics::default());
+        let options = Options {
+            statistics: statistics.clone(),
+            ..Options::default()
+        };
+        let db = Arc::new(DB::open_with_options(db_path, options).unwrap());
+
+        // the log is held, so that the writers pile up in the queue behind the first leader
+        let log_writer = db.inner.log_writer.lock().unwrap();
+        let writers = (0..16)
+            .map(|i| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    let key = format!("key{:02}", i);
+                    db.put(key.as_bytes(), b"value", true)
+                })
+            })
+            .collect::<Vec<_>>();
+        while db.inner.state.lock().unwrap().writers.len() < 16 {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        drop(log_writer);
+        for writer in writers {
+            writer.join().unwrap().unwrap();
+        }
+
+        // the first leader may have written alone, and the next one wrote all the others
+        assert!(statistics.write_groups() <= 2);
+        assert_eq!(statistics.grouped_writes(), 16);
+        assert!(statistics.log_syncs() <= 2);
+        assert_eq!(db.last_sequence(), 16);
+        drop(db);
+
+        let db = DB::open(db_path).unwrap();
+        for i in 0..16 {
+            let key = format!("key{:02}", i);
+            assert_eq!(
+                db.get(&ReadOptions::default(), key.as_bytes()),
+                Ok(b"value".to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn test_db_write_stall() {
+        let db_path = "/tmp/test_db_write_stall";
+        let _ = std::fs::remove_dir_all(db_path);
+
+        let statistics = Arc::new(Statistics::default());
+        let options = Options {
+            statistics: statistics.clone(),
+            ..Options::default()
+        };
+        let db = Arc::new(DB::open_with_options(db_path, options).unwrap());
+
+        // a write is not delayed below the slowdown trigger
+        db.stop_background_work();
+        db.add_level0_tables(L0_SLOWDOWN_WRITES_TRIGGER - 1);
+        db.put(b"key1", b"value1", false).unwrap();
+        assert_eq!(statistics.write_slowdowns(), 0);
+
+        // every write is delayed once at the slowdown trigger
+        db.add_level0_tables(1);
+        db.put(b"key2", b"value2", false).unwrap();
+        db.put(b"key3", b"value3", false).unwrap();
+        assert_eq!(statistics.write_slowdowns(), 2);
+        assert!(statistics.write_slowdown_micros() >= 2000);
+        assert_eq!(statistics.write_stops(), 0);
+
+        // at the stop trigger, a write waits for the compaction once the memtable is full
+        db.add_level0_tables(L0_STOP_WRITES_TRIGGER - L0_SLOWDOWN_WRITES_TRIGGER);
+        let value = vec![b'x'; Options::default().write_buffer_size];
+        db.put(b"key4", &value, false).unwrap();
+        let writer = {
+            let db = db.clone();
+            std::thread::spawn(move || db.put(b"key5", b"value5", false))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!writer.is_finished());
+
+        db.run_background_work().unwrap();
+        writer.join().unwrap().unwrap();
+        assert!(statistics.write_stops() >= 1);
+        assert!(statistics.write_stop_micros() > 0);
+        assert!(db.current_version().num_level_files(0) < L0_STOP_WRITES_TRIGGER);
+        for (key, value) in [
+            (&b"key1"[..], &b"value1"[..]),
+            (b"key4", &value),
+            (b"key5", b"value5"),
+        ] {
+            assert_eq!(db.get(&ReadOptions::default(), key), Ok(value.to_vec()));
+        }
     }
 
     #[test]
-    #[rustfmt::skip]
-    fn test_serialize_memtable() {
-        let mut memtable = MemTable::default();
-        memtable.set(b"key1", b"value1");
-        memtable.set(b"key2", b"value2");
-        memtable.set(b"key0", b"value0");
-        memtable.delete(b"key1");
+    fn test_db_paranoid_checks() {
+        let db_path = "/tmp/test_db_paranoid_checks";
+
+        // corrupt_table fills level 0 with one file less than the compaction trigger,
+        // and flips a byte of a value in one of the files
+        let corrupt_table = |options: &Options| {
+            let _ = std::fs::remove_dir_all(db_path);
+            let db = DB::open_with_options(db_path, options.clone()).unwrap();
+            let mut i = 0;
+            while db.current_version().files[0].len() < version_set::L0_COMPACTION_TRIGGER - 1 {
+                let key = format!("key{:06}", i);
+                db.put(key.as_bytes(), format!("value{:06}", i).as_bytes(), false)
+                    .unwrap();
+                // a full memtable is flushed before the next check, so that level 0 stops at the count
+                db.wait_for_background_work().unwrap();
+                i += 1;
+            }
 
-        let mut buf = Vec::new();
-        assert!(serialize_to_sstable(&mut buf, memtable).is_ok());
+            let file = &db.current_version().files[0][0];
+            let path = table_file_name(Path::new(db_path), file.number);
+            let mut contents = std::fs::read(&path).unwrap();
+            let position = contents
+                .windows(11)
+                .position(|w| w == b"value000000")
+                .unwrap();
+            contents[position + 10] = b'X';
+            std::fs::write(&path, contents).unwrap();
+            (db, file.number)
+        };
 
+        // the values are searched in the table files, so they are not compressed
+        let options = Options {
+            write_buffer_size: 1024,
+            block_size: 256,
+            compression: CompressionType::No,
+            ..Options::default()
+        };
+
+        // reads verify the checksums only if they are asked to
+        let (db, number) = corrupt_table(&options);
+        let verify = ReadOptions {
+            verify_checksums: true,
+            ..ReadOptions::default()
+        };
+        // the corrupted block is not cached, so that the verified read reads it again
+        let no_verify = ReadOptions {
+            fill_cache: false,
+            ..ReadOptions::default()
+        };
         assert_eq!(
-            buf,
-            vec![
-                3, 0, 0, 0, // entry count
-                4, b'k', b'e', b'y', b'0', 1, 0, 0, 0, 0, 0, 0, 0, 6, 0, 0, 0, b'v', b'a', b'l', b'u', b'e', b'0',
-                4, b'k', b'e', b'y', b'1', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                4, b'k', b'e', b'y', b'2', 1, 0, 0, 0, 0, 0, 0, 0, 6, 0, 0, 0, b'v', b'a', b'l', b'u', b'e', b'2',
-            ]
-        )
+            db.get(&no_verify, b"key000000"),
+            Ok(b"value00000X".to_vec())
+        );
+        assert_eq!(
+            db.get(&verify, b"key000000"),
+            Err(Status::Corruption(format!(
+                "table {:06}: block checksum mismatch at offset 0",
+                number
+            )))
+        );
+
+        // compaction copies the corrupted block without paranoid checks
+        for i in 0..1000 {
+            db.put(format!("key{:06}", i).as_bytes(), b"value", false)
+                .unwrap();
+        }
+        assert_eq!(db.wait_for_background_work(), Ok(()));
+        let version = db.current_version();
+        assert!(version.files[0].len() < version_set::L0_COMPACTION_TRIGGER);
+        assert!(version.files.iter().flatten().all(|f| f.number != number));
+        drop(db);
+
+        // with paranoid checks, compaction refuses to read the corrupted block
+        let options = Options {
+            paranoid_checks: true,
+            ..options
+        };
+        let (db, _) = corrupt_table(&options);
+        let result =
+            (0..1000).try_for_each(|i| db.put(format!("key{:06}", i).as_bytes(), b"value", false));
+        assert!(matches!(result, Err(Status::Corruption(_))));
+    }
+
+    #[test]
+    fn test_compression_for_level() {
+        let options = Options {
+            compression: CompressionType::Snappy,
+            ..Options::default()
+        };
+        assert_eq!(options.compression_for_level(0), CompressionType::Snappy);
+        assert_eq!(options.compression_for_level(6), CompressionType::Snappy);
+
+        let options = Options {
+            compression_per_level: vec![
+                CompressionType::No,
+                CompressionType::Lz4,
+                CompressionType::Zstd,
+            ],
+            ..options
+        };
+        assert_eq!(options.compression_for_level(0), CompressionType::No);
+        assert_eq!(options.compression_for_level(1), CompressionType::Lz4);
+        assert_eq!(options.compression_for_level(2), CompressionType::Zstd);
+        assert_eq!(options.compression_for_level(6), CompressionType::Zstd);
     }
 
     #[test]
@@ -613,6 +2493,7 @@ mod tests {
         block_builder.add(b"key0", b"value0");
 
         let block = block_builder.finish();
+        #[allow(clippy::identity_op)]
         let restart_offset = 1 /* varint32 bytes */ * 3 /* three field */ * restart_interval as u8
             + b"key1value1".len() as u8
             + b"2value2".len() as u8;
@@ -1,14 +1,16 @@
 use std::fs::File;
 use std::io::{Read, Write};
+use std::ops::Range;
 
 use zerocopy::{AsBytes, FromBytes, FromZeroes, Unaligned};
 
+use crate::table::{mask_crc, unmask_crc};
 use crate::{Result, Status};
 
 const BLOCK_SIZE: usize = 32768;
 const HEADER_SIZE: usize = 7;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub(crate) enum RecordType {
     #[allow(dead_code)]
@@ -19,6 +21,7 @@ pub(crate) enum RecordType {
     Last = 4,
 }
 
+// the checksum is the masked crc32c of the record type and the payload
 #[derive(Debug, Unaligned, AsBytes, FromBytes, FromZeroes)]
 #[repr(C, packed)]
 struct WalHeader {
@@ -27,6 +30,30 @@ struct WalHeader {
     record_type: u8,
 }
 
+fn record_checksum(record_type: u8, message: &[u8]) -> u32 {
+    crc32c::crc32c_append(crc32c::crc32c(&[record_type]), message)
+}
+
+// ChecksumFormat is the format of the checksums in a log file.
+// logs written before the checksums were verified have the plain crc32 of the payload instead.
+// a log is never appended to after the db is reopened, so all records of a file have the same format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChecksumFormat {
+    MaskedCrc32c,
+    Legacy,
+}
+
+impl ChecksumFormat {
+    fn matches(self, header: &WalHeader, message: &[u8]) -> bool {
+        match self {
+            ChecksumFormat::MaskedCrc32c => {
+                unmask_crc(header.checksum) == record_checksum(header.record_type, message)
+            }
+            ChecksumFormat::Legacy => header.checksum == crc32fast::hash(message),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct Writer {
     file: File,
@@ -88,7 +115,7 @@ impl Writer {
 
         let length = message.len();
         let wal_header = WalHeader {
-            checksum: crc32fast::hash(message),
+            checksum: mask_crc(record_checksum(record_type as u8, message)),
             length: length as u16,
             record_type: record_type as u8,
         };
@@ -102,8 +129,35 @@ impl Writer {
     }
 }
 
+// Reporter is told about the bytes which are dropped by Reader because of corruption
+pub trait Reporter {
+    fn corruption(&mut self, bytes: usize, reason: &Status);
+}
+
+// RecoveryMode decides what Reader does when it finds a corrupted record
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryMode {
+    // read returns the corruption error
+    #[default]
+    Stop,
+    // the rest of the block which holds the corrupted record is dropped, and reading goes on
+    SkipToNextBlock,
+}
+
+// Physical is a physical record read from the buffer
+enum Physical {
+    Record(RecordType, Range<usize>),
+    // bytes of the buffer were dropped because of reason
+    Bad(usize, &'static str),
+    Eof,
+}
+
 pub struct Reader {
     file: File,
+    reporter: Option<Box<dyn Reporter>>,
+    mode: RecoveryMode,
+    // the format of the checksums, which is decided by the first intact record of the file
+    format: Option<ChecksumFormat>,
 
     buffer_offset: usize,
     buffer_length: usize,
@@ -116,6 +170,9 @@ impl Reader {
     pub fn new(file: File) -> Reader {
         Reader {
             file,
+            reporter: None,
+            mode: RecoveryMode::Stop,
+            format: None,
             buffer_offset: 0,
             buffer_length: 0,
             buffer: [0; BLOCK_SIZE],
@@ -123,54 +180,93 @@ impl Reader {
         }
     }
 
+    pub fn with_reporter(file: File, reporter: Box<dyn Reporter>, mode: RecoveryMode) -> Reader {
+        Reader {
+            reporter: Some(reporter),
+            mode,
+            ..Reader::new(file)
+        }
+    }
+
     pub fn read(&mut self) -> Result<Option<Vec<u8>>> {
         let mut in_fragmented_record = false;
 
         let mut fragment = Vec::new();
         loop {
-            let (record_type, message) = match self.read_physical()? {
-                Some(v) => v,
-                // EOF
-                None => return Ok(None),
+            let (record_type, range) = match self.read_physical()? {
+                Physical::Record(record_type, range) => (record_type, range),
+                Physical::Bad(bytes, reason) => {
+                    // the fragments read so far can't be completed
+                    let dropped = bytes + fragment.len();
+                    in_fragmented_record = false;
+                    fragment.clear();
+                    self.report(dropped, reason)?;
+                    continue;
+                }
+                // a record cut by the end of the file is the result of a torn write, so it's not reported
+                Physical::Eof => return Ok(None),
             };
 
             match record_type {
                 RecordType::Full => {
                     if in_fragmented_record {
-                        return Err(Status::Corruption);
+                        self.report(fragment.len(), "partial record without end")?;
                     }
-                    return Ok(Some(message.to_vec()));
+                    return Ok(Some(self.buffer[range].to_vec()));
                 }
                 RecordType::First => {
                     if in_fragmented_record {
-                        return Err(Status::Corruption);
+                        self.report(fragment.len(), "partial record without end")?;
                     }
                     in_fragmented_record = true;
-                    fragment.extend(message);
+                    fragment.clear();
+                    fragment.extend(&self.buffer[range]);
                 }
                 RecordType::Middle => {
                     if !in_fragmented_record {
-                        return Err(Status::Corruption);
+                        self.report(range.len(), "missing start of fragmented record")?;
+                        continue;
                     }
-                    fragment.extend(message);
+                    fragment.extend(&self.buffer[range]);
                 }
                 RecordType::Last => {
                     if !in_fragmented_record {
-                        return Err(Status::Corruption);
+                        self.report(range.len(), "missing start of fragmented record")?;
+                        continue;
                     }
-                    fragment.extend(message);
+                    fragment.extend(&self.buffer[range]);
                     return Ok(Some(fragment));
                 }
-                _ => return Err(Status::Corruption),
+                RecordType::Zero => unreachable!("zero record type is never returned"),
             }
         }
     }
 
-    fn read_physical(&mut self) -> Result<Option<(RecordType, &[u8])>> {
+    // report tells the reporter about the dropped bytes, and returns the error if reading must stop
+    fn report(&mut self, bytes: usize, reason: &str) -> Result<()> {
+        let status = Status::Corruption(reason.to_string());
+        if let Some(reporter) = self.reporter.as_mut() {
+            reporter.corruption(bytes, &status);
+        }
+        match self.mode {
+            RecoveryMode::Stop => Err(status),
+            RecoveryMode::SkipToNextBlock => Ok(()),
+        }
+    }
+
+    // drop_buffer drops the rest of the current block, and returns the number of the dropped bytes
+    fn drop_buffer(&mut self) -> usize {
+        let bytes = self.buffer_length - self.buffer_offset;
+        self.buffer_offset = 0;
+        self.buffer_length = 0;
+        bytes
+    }
+
+    fn read_physical(&mut self) -> Result<Physical> {
         loop {
             if self.buffer_length - self.buffer_offset < HEADER_SIZE {
                 if self.eof {
-                    return Ok(None);
+                    return Ok(Physical::Eof);
                 }
                 self.buffer_offset = 0;
                 let nreads = match self.file.read(&mut self.buffer) {
@@ -192,22 +288,33 @@ impl Reader {
             let length = header.length as usize;
 
             if length + HEADER_SIZE > self.buffer_length - self.buffer_offset {
-                self.buffer_offset = 0;
-                self.buffer_length = 0;
+                let bytes = self.drop_buffer();
                 if !self.eof {
-                    return Err(Status::Corruption);
+                    return Ok(Physical::Bad(bytes, "bad record length"));
                 }
-                return Ok(None);
+                return Ok(Physical::Eof);
             }
             if header.record_type == RecordType::Zero as u8 && length == 0 {
-                self.buffer_offset = 0;
-                self.buffer_length = 0;
-                return Err(Status::Corruption);
+                let bytes = self.drop_buffer();
+                return Ok(Physical::Bad(bytes, "zero length record"));
             }
 
-            // TODO: check checksum
-
             let record_offset = self.buffer_offset + HEADER_SIZE;
+            let record = record_offset..record_offset + length;
+
+            // the length may be corrupted too, so the rest of the block can't be trusted
+            let message = &self.buffer[record.clone()];
+            let format = match self.format {
+                Some(format) => format.matches(&header, message).then_some(format),
+                None => [ChecksumFormat::MaskedCrc32c, ChecksumFormat::Legacy]
+                    .into_iter()
+                    .find(|format| format.matches(&header, message)),
+            };
+            let Some(format) = format else {
+                let bytes = self.drop_buffer();
+                return Ok(Physical::Bad(bytes, "checksum mismatch"));
+            };
+            self.format = Some(format);
             self.buffer_offset += HEADER_SIZE + length;
 
             let record_type = match header.record_type {
@@ -215,27 +322,49 @@ impl Reader {
                 x if x == RecordType::First as u8 => RecordType::First,
                 x if x == RecordType::Middle as u8 => RecordType::Middle,
                 x if x == RecordType::Last as u8 => RecordType::Last,
-                _ => return Err(Status::Corruption),
+                _ => return Ok(Physical::Bad(HEADER_SIZE + length, "unknown record type")),
             };
 
-            return Ok(Some((
-                record_type,
-                &self.buffer[record_offset..record_offset + length],
-            )));
+            return Ok(Physical::Record(record_type, record));
         }
     }
 }
 
+// encode_legacy_record returns a full record of message in the legacy format
+#[cfg(test)]
+pub(crate) fn encode_legacy_record(message: &[u8]) -> Vec<u8> {
+    let header = WalHeader {
+        checksum: crc32fast::hash(message),
+        length: message.len() as u16,
+        record_type: RecordType::Full as u8,
+    };
+    [header.as_bytes(), message].concat()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::fs::OpenOptions;
+    use std::sync::{Arc, Mutex};
+
+    // RecordingReporter keeps the reported corruptions
+    #[derive(Clone, Default)]
+    struct RecordingReporter {
+        dropped: Arc<Mutex<Vec<(usize, Status)>>>,
+    }
+
+    impl Reporter for RecordingReporter {
+        fn corruption(&mut self, bytes: usize, reason: &Status) {
+            self.dropped.lock().unwrap().push((bytes, reason.clone()));
+        }
+    }
 
     #[test]
     fn test_writer() {
         let file = OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(true)
             .open("/tmp/test_writer.log")
             .unwrap();
         let mut writer = Writer::new(file);
@@ -253,6 +382,7 @@ mod tests {
         let file = OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(false)
             .open("/tmp/test_writer.log")
             .unwrap();
 
@@ -265,6 +395,7 @@ mod tests {
         let file = OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(true)
             .open("/tmp/test_reader.log")
             .unwrap();
         let mut writer = Writer::new(file);
@@ -286,6 +417,7 @@ mod tests {
         let file = OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(true)
             .open("/tmp/test_wal.log")
             .unwrap();
         let table = [1, HEADER_SIZE - 1, BLOCK_SIZE - 1, BLOCK_SIZE * 2 - 1];
@@ -318,4 +450,114 @@ mod tests {
         }
         assert_eq!(reader.read(), Ok(None));
     }
+
+    #[test]
+    fn test_reader_legacy_checksum() {
+        // a log written before the checksums were verified
+        let path = "/tmp/test_reader_legacy_checksum.log";
+        let legacy_record = encode_legacy_record;
+        let mut contents = [legacy_record(b"first"), legacy_record(b"second")].concat();
+        let mut corrupted = legacy_record(b"third");
+        corrupted[HEADER_SIZE] = b'T';
+        contents.extend(corrupted);
+        std::fs::write(path, contents).unwrap();
+
+        let reporter = RecordingReporter::default();
+        let mut reader = Reader::with_reporter(
+            File::open(path).unwrap(),
+            Box::new(reporter.clone()),
+            RecoveryMode::SkipToNextBlock,
+        );
+        assert_eq!(reader.read(), Ok(Some(b"first".to_vec())));
+        assert_eq!(reader.read(), Ok(Some(b"second".to_vec())));
+        // the legacy checksum is verified too
+        assert_eq!(reader.read(), Ok(None));
+        assert_eq!(
+            *reporter.dropped.lock().unwrap(),
+            vec![(
+                HEADER_SIZE + 5,
+                Status::Corruption("checksum mismatch".to_string())
+            )]
+        );
+
+        // a record of the other format is not accepted in a log with the current one
+        let mut writer = Writer::new(File::create(path).unwrap());
+        writer.append(b"first").unwrap();
+        drop(writer);
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(&legacy_record(b"second")).unwrap();
+        drop(file);
+
+        let mut reader = Reader::new(File::open(path).unwrap());
+        assert_eq!(reader.read(), Ok(Some(b"first".to_vec())));
+        assert_eq!(
+            reader.read(),
+            Err(Status::Corruption("checksum mismatch".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_reader_corruption() {
+        let path = "/tmp/test_reader_corruption.log";
+        let mut writer = Writer::new(File::create(path).unwrap());
+        // c fills up the rest of the first block, and d is in the second block
+        let a = vec![b'a'; 100];
+        let b = vec![b'b'; 100];
+        let c = vec![b'c'; BLOCK_SIZE - 3 * HEADER_SIZE - 200];
+        let d = vec![b'd'; 100];
+        for message in [&a, &b, &c, &d] {
+            writer.append(message).unwrap();
+        }
+        drop(writer);
+        let contents = std::fs::read(path).unwrap();
+
+        let open = |contents: &[u8], mode| {
+            std::fs::write(path, contents).unwrap();
+            let reporter = RecordingReporter::default();
+            let reader =
+                Reader::with_reporter(File::open(path).unwrap(), Box::new(reporter.clone()), mode);
+            (reader, reporter.dropped)
+        };
+        let corruption = |reason: &str| Status::Corruption(reason.to_string());
+
+        // a corrupted payload of b drops the rest of the first block
+        let mut corrupted = contents.clone();
+        corrupted[100 + 2 * HEADER_SIZE] = b'x';
+
+        let (mut reader, dropped) = open(&corrupted, RecoveryMode::SkipToNextBlock);
+        assert_eq!(reader.read(), Ok(Some(a.clone())));
+        assert_eq!(reader.read(), Ok(Some(d.clone())));
+        assert_eq!(reader.read(), Ok(None));
+        assert_eq!(
+            *dropped.lock().unwrap(),
+            vec![(
+                BLOCK_SIZE - 100 - HEADER_SIZE,
+                corruption("checksum mismatch")
+            )]
+        );
+
+        let (mut reader, dropped) = open(&corrupted, RecoveryMode::Stop);
+        assert_eq!(reader.read(), Ok(Some(a.clone())));
+        assert_eq!(reader.read(), Err(corruption("checksum mismatch")));
+        assert_eq!(dropped.lock().unwrap().len(), 1);
+
+        // the checksum covers the record type
+        let mut corrupted = contents.clone();
+        corrupted[HEADER_SIZE - 1] = RecordType::Last as u8;
+
+        let (mut reader, dropped) = open(&corrupted, RecoveryMode::Stop);
+        assert_eq!(reader.read(), Err(corruption("checksum mismatch")));
+        assert_eq!(
+            *dropped.lock().unwrap(),
+            vec![(BLOCK_SIZE, corruption("checksum mismatch"))]
+        );
+
+        // a record cut by the end of the file is not reported
+        let (mut reader, dropped) = open(&contents[..contents.len() - 1], RecoveryMode::Stop);
+        for message in [&a, &b, &c] {
+            assert_eq!(reader.read(), Ok(Some(message.clone())));
+        }
+        assert_eq!(reader.read(), Ok(None));
+        assert!(dropped.lock().unwrap().is_empty());
+    }
 }
@@ -1,20 +1,107 @@
 use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 
 use clap::Parser;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{transport::Server, Request, Response, Status};
 
-use ::espikey::DB;
+use ::espikey::{ReadOptions, WriteBatch, WriteOptions, DB};
 use espikey::kv_service_server::{KvService, KvServiceServer};
-use espikey::{GetRequest, GetResponse, SetRequest, SetResponse};
+use espikey::{
+    batch_operation, BatchRequest, BatchResponse, DeleteRequest, DeleteResponse, GetRequest,
+    GetResponse, MultiGetRequest, MultiGetResponse, ScanRequest, ScanResponse, SetRequest,
+    SetResponse,
+};
 
 pub mod espikey {
     tonic::include_proto!("espikey");
 }
 
+// proto_status maps an error of the db to the status reported in responses
+fn proto_status(status: &::espikey::Status) -> espikey::Status {
+    match status {
+        ::espikey::Status::NotFound => espikey::Status::NotFound,
+        ::espikey::Status::Corruption(_) => espikey::Status::Corruption,
+        ::espikey::Status::NotSupported => espikey::Status::NotSupported,
+        ::espikey::Status::InvalidArgument => espikey::Status::InvalidArgument,
+        ::espikey::Status::IOError(_) => espikey::Status::IoError,
+    }
+}
+
+// error_message returns the message reported with the status in responses.
+// a missing key is a result rather than an error, so it has no message.
+fn error_message(status: &::espikey::Status) -> String {
+    match status {
+        ::espikey::Status::NotFound => String::new(),
+        status => status.to_string(),
+    }
+}
+
+// rpc_status maps an error of the db to the gRPC status which fails an RPC
+fn rpc_status(status: &::espikey::Status) -> Status {
+    let code = match status {
+        ::espikey::Status::NotFound => tonic::Code::NotFound,
+        ::espikey::Status::Corruption(_) => tonic::Code::DataLoss,
+        ::espikey::Status::NotSupported => tonic::Code::Unimplemented,
+        ::espikey::Status::InvalidArgument => tonic::Code::InvalidArgument,
+        ::espikey::Status::IOError(_) => tonic::Code::Unavailable,
+    };
+    Status::new(code, status.to_string())
+}
+
+// prefix_successor returns the smallest key which is greater than all keys with prefix,
+// or None if there is no such key, that is, prefix consists of 0xff only
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let end = prefix.iter().rposition(|&b| b != 0xff)?;
+    let mut successor = prefix[..=end].to_vec();
+    successor[end] += 1;
+    Some(successor)
+}
+
+// scan_range returns the range [lower, upper) of the keys which a scan yields. none means no bound.
+fn scan_range(request: &ScanRequest) -> (Vec<u8>, Option<Vec<u8>>) {
+    let mut lower = std::cmp::max(&request.start, &request.prefix).clone();
+    // the resume token is the smallest key after the ones returned by the previous scan
+    if lower < request.resume_token {
+        lower = request.resume_token.clone();
+    }
+
+    let end = (!request.end.is_empty()).then(|| request.end.clone());
+    let prefix_end = if request.prefix.is_empty() {
+        None
+    } else {
+        // the scan stops at the first key without the prefix even if there is no upper bound
+        prefix_successor(&request.prefix)
+    };
+    let upper = match (end, prefix_end) {
+        (Some(end), Some(prefix_end)) => Some(std::cmp::min(end, prefix_end)),
+        (end, prefix_end) => end.or(prefix_end),
+    };
+    (lower, upper)
+}
+
+// the number of responses which a scan reads ahead of the client
+const SCAN_CHANNEL_SIZE: usize = 128;
+
 #[derive(Debug)]
 struct EspikeyServer {
-    storage: Arc<RwLock<DB>>,
+    storage: Arc<DB>,
+}
+
+impl EspikeyServer {
+    // blocking runs f on a thread for blocking work, so that disk IO and syncs of the db
+    // don't stall the threads which serve the other requests
+    async fn blocking<T, F>(&self, f: F) -> Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&DB) -> T + Send + 'static,
+    {
+        let storage = self.storage.clone();
+        tokio::task::spawn_blocking(move || f(&storage))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))
+    }
 }
 
 #[tonic::async_trait]
@@ -22,29 +109,174 @@ impl KvService for EspikeyServer {
     async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
         let request = request.into_inner();
 
-        let storage = self.storage.read().unwrap();
-        let response = match storage.get(&request.key) {
+        let result = self
+            .blocking(move |storage| storage.get(&ReadOptions::default(), &request.key))
+            .await?;
+        let response = match result {
             Ok(v) => espikey::GetResponse {
                 status: espikey::Status::Ok.into(),
                 value: Some(v),
+                error: String::new(),
             },
-            Err(_status) => espikey::GetResponse {
-                status: espikey::Status::NotFound.into(),
+            // a missing key is a result of the lookup, and the other errors are failures of the db
+            Err(status @ ::espikey::Status::NotFound) => espikey::GetResponse {
+                status: proto_status(&status).into(),
                 value: None,
+                error: error_message(&status),
             },
+            Err(status) => return Err(rpc_status(&status)),
         };
         Ok(Response::new(response))
     }
 
+    async fn multi_get(
+        &self,
+        request: Request<MultiGetRequest>,
+    ) -> Result<Response<MultiGetResponse>, Status> {
+        let request = request.into_inner();
+        let results = self
+            .blocking(move |storage| {
+                let keys = request
+                    .keys
+                    .iter()
+                    .map(|k| k.as_slice())
+                    .collect::<Vec<_>>();
+                storage.multi_get(&ReadOptions::default(), &keys)
+            })
+            .await?;
+        let results = results
+            .into_iter()
+            .map(|result| match result {
+                Ok(v) => espikey::KeyResult {
+                    status: espikey::Status::Ok.into(),
+                    value: Some(v),
+                    error: String::new(),
+                },
+                Err(status) => espikey::KeyResult {
+                    status: proto_status(&status).into(),
+                    value: None,
+                    error: error_message(&status),
+                },
+            })
+            .collect();
+        Ok(Response::new(espikey::MultiGetResponse { results }))
+    }
+
     async fn set(&self, request: Request<SetRequest>) -> Result<Response<SetResponse>, Status> {
         let request = request.into_inner();
-        {
-            let mut storage = self.storage.write().unwrap();
-            let _ = storage.put(&request.key, &request.value, true);
-        }
+        self.blocking(move |storage| storage.put(&request.key, &request.value, true))
+            .await?
+            .map_err(|status| rpc_status(&status))?;
 
         let response = espikey::SetResponse {
             status: espikey::Status::Ok.into(),
+            error: String::new(),
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let request = request.into_inner();
+        self.blocking(move |storage| storage.delete(&request.key, request.sync))
+            .await?
+            .map_err(|status| rpc_status(&status))?;
+
+        let response = espikey::DeleteResponse {
+            status: espikey::Status::Ok.into(),
+            error: String::new(),
+        };
+        Ok(Response::new(response))
+    }
+
+    type ScanStream = ReceiverStream<Result<ScanResponse, Status>>;
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> Result<Response<Self::ScanStream>, Status> {
+        let request = request.into_inner();
+        let (lower, upper) = scan_range(&request);
+
+        let storage = self.storage.clone();
+        let (tx, rx) = mpsc::channel(SCAN_CHANNEL_SIZE);
+        tokio::task::spawn_blocking(move || {
+            // the iterator reads the state of the db at this point
+            let options = ReadOptions {
+                lower_bound: Some(&lower),
+                upper_bound: upper.as_deref(),
+                ..ReadOptions::default()
+            };
+            let mut iter = match storage.iter(&options) {
+                Ok(iter) => iter,
+                Err(status) => {
+                    let _ = tx.blocking_send(Err(rpc_status(&status)));
+                    return;
+                }
+            };
+
+            let limit = request.limit as usize;
+            let mut count = 0;
+            iter.seek_to_first();
+            while iter.valid() && iter.key().starts_with(&request.prefix) {
+                let mut response = ScanResponse {
+                    key: iter.key().to_vec(),
+                    value: if request.keys_only {
+                        Vec::new()
+                    } else {
+                        iter.value().to_vec()
+                    },
+                    resume_token: Vec::new(),
+                };
+                count += 1;
+                iter.next();
+                if count == limit {
+                    if iter.valid() && iter.key().starts_with(&request.prefix) {
+                        // the smallest key after the current one
+                        response.resume_token = response.key.clone();
+                        response.resume_token.push(0);
+                    }
+                    let _ = tx.blocking_send(Ok(response));
+                    return;
+                }
+                if tx.blocking_send(Ok(response)).is_err() {
+                    // the client is gone
+                    return;
+                }
+            }
+            if let Err(status) = iter.status() {
+                let _ = tx.blocking_send(Err(rpc_status(&status)));
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn batch(
+        &self,
+        request: Request<BatchRequest>,
+    ) -> Result<Response<BatchResponse>, Status> {
+        let request = request.into_inner();
+
+        // the batch is rejected as a whole if an operation is invalid
+        let mut batch = WriteBatch::new();
+        for operation in &request.operations {
+            match &operation.operation {
+                Some(batch_operation::Operation::Put(put)) => batch.put(&put.key, &put.value),
+                Some(batch_operation::Operation::Delete(delete)) => batch.delete(&delete.key),
+                None => return Err(Status::invalid_argument("empty operation in the batch")),
+            }
+        }
+
+        let options = WriteOptions { sync: request.sync };
+        self.blocking(move |storage| storage.write(&options, batch))
+            .await?
+            .map_err(|status| rpc_status(&status))?;
+
+        let response = espikey::BatchResponse {
+            status: espikey::Status::Ok.into(),
+            error: String::new(),
         };
         Ok(Response::new(response))
     }
@@ -63,12 +295,13 @@ struct EspikeyCli {
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    env_logger::init();
     let args = EspikeyCli::parse();
     println!("Starting Espikey server on port {}", args.port);
 
     let addr = format!("[::1]:{}", args.port).parse()?;
     let espikey_svc = EspikeyServer {
-        storage: Arc::new(RwLock::new(DB::open(args.dir).unwrap())),
+        storage: Arc::new(DB::open(args.dir)?),
     };
 
     Server::builder()
@@ -77,3 +310,50 @@ async fn main() -> anyhow::Result<()> {
         .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_message() {
+        assert_eq!(error_message(&::espikey::Status::NotFound), "");
+        let status = ::espikey::Status::Corruption("bad block".to_string());
+        assert_eq!(error_message(&status), status.to_string());
+        assert_eq!(proto_status(&status), espikey::Status::Corruption);
+    }
+
+    #[test]
+    fn test_scan_range() {
+        assert_eq!(prefix_successor(b"abc"), Some(b"abd".to_vec()));
+        assert_eq!(prefix_successor(b"a\xff\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_successor(b"\xff"), None);
+
+        let request = |start: &[u8], end: &[u8], prefix: &[u8], resume_token: &[u8]| ScanRequest {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            prefix: prefix.to_vec(),
+            resume_token: resume_token.to_vec(),
+            ..ScanRequest::default()
+        };
+        assert_eq!(scan_range(&request(b"", b"", b"", b"")), (vec![], None));
+        assert_eq!(
+            scan_range(&request(b"b", b"d", b"", b"")),
+            (b"b".to_vec(), Some(b"d".to_vec()))
+        );
+        // the range is the intersection of [start, end) and the keys with the prefix
+        assert_eq!(
+            scan_range(&request(b"a", b"", b"ab", b"")),
+            (b"ab".to_vec(), Some(b"ac".to_vec()))
+        );
+        assert_eq!(
+            scan_range(&request(b"abc", b"abb", b"ab", b"")),
+            (b"abc".to_vec(), Some(b"abb".to_vec()))
+        );
+        // the scan continues from the resume token
+        assert_eq!(
+            scan_range(&request(b"a", b"d", b"", b"b\x00")),
+            (b"b\x00".to_vec(), Some(b"d".to_vec()))
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::iterator::InternalIterator;
+use crate::skiplist::{self, SkipList};
+use crate::write_batch::ValueTypeCode;
+use crate::{compare_internal_key, parse_internal_key, InternalKey, Result, ValueItem};
+
+// MemTable keeps recent writes in memory, ordered by internal key.
+// every version of a key is kept, so that a read can see the value as of any sequence.
+pub struct MemTable {
+    // shared with iterators, so that an iterator can outlive a borrow of the memtable
+    table: Arc<SkipList>,
+    memory_usage: AtomicUsize,
+}
+
+impl std::fmt::Debug for MemTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemTable")
+            .field("memory_usage", &self.approximate_memory_usage())
+            .finish()
+    }
+}
+
+impl Default for MemTable {
+    fn default() -> Self {
+        MemTable {
+            table: Arc::new(SkipList::new(compare_internal_key)),
+            memory_usage: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl MemTable {
+    pub fn add(&self, sequence: u64, value_type: ValueTypeCode, key: &[u8], value: &[u8]) {
+        let ikey = InternalKey::new(key, sequence, value_type);
+        self.memory_usage
+            .fetch_add(ikey.get_contents().len() + value.len(), Ordering::Relaxed);
+        self.table.insert(ikey.get_contents(), value);
+    }
+
+    // get returns the newest entry for the user key of lookup_key whose sequence is not greater than
+    // the one of lookup_key. a deleted key is returned as ValueItem::Deletion,
+    // and None means the memtable knows nothing about the key.
+    pub fn get(&self, lookup_key: &InternalKey) -> Option<ValueItem<Vec<u8>>> {
+        let mut iter = self.table.iter();
+        iter.seek(lookup_key.get_contents());
+        if !iter.valid() {
+            return None;
+        }
+
+        let (user_key, _, value_type) = parse_internal_key(iter.key()).ok()?;
+        if user_key != lookup_key.user_key() {
+            return None;
+        }
+        match value_type {
+            ValueTypeCode::Value => Some(ValueItem::Value(iter.value().to_vec())),
+            ValueTypeCode::Deletion => Some(ValueItem::Deletion),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let mut iter = self.table.iter();
+        iter.seek_to_first();
+        !iter.valid()
+    }
+
+    pub fn approximate_memory_usage(&self) -> usize {
+        self.memory_usage.load(Ordering::Relaxed)
+    }
+
+    // iter returns an iterator over the entries keyed by internal key.
+    // entries added after this call may or may not be seen by the iterator.
+    pub fn iter(&self) -> MemTableIterator {
+        MemTableIterator {
+            iter: skiplist::Iter::new(self.table.clone()),
+        }
+    }
+}
+
+pub struct MemTableIterator {
+    iter: skiplist::Iter<Arc<SkipList>>,
+}
+
+impl InternalIterator for MemTableIterator {
+    fn valid(&self) -> bool {
+        self.iter.valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.iter.seek_to_first();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.iter.seek_to_last();
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        self.iter.seek(target);
+    }
+
+    fn next(&mut self) {
+        self.iter.next();
+    }
+
+    fn prev(&mut self) {
+        self.iter.prev();
+    }
+
+    fn key(&self) -> &[u8] {
+        self.iter.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    // reading a memtable never fails
+    fn status(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! v {
+        ($e:expr) => {
+            $e.to_vec()
+        };
+    }
+
+    #[test]
+    fn test_memtable() {
+        let memtable = MemTable::default();
+        memtable.add(1, ValueTypeCode::Value, b"key1", b"value1");
+        memtable.add(2, ValueTypeCode::Value, b"key2", b"value2");
+        memtable.add(3, ValueTypeCode::Value, b"key0", b"value");
+        memtable.add(4, ValueTypeCode::Value, b"key0", b"value0");
+        memtable.add(5, ValueTypeCode::Deletion, b"key1", b"");
+
+        let get = |key: &[u8], sequence| {
+            memtable.get(&InternalKey::new(key, sequence, ValueTypeCode::Value))
+        };
+        assert_eq!(get(b"key0", 5), Some(ValueItem::Value(b"value0".to_vec())));
+        assert_eq!(get(b"key1", 5), Some(ValueItem::Deletion));
+        assert_eq!(get(b"key3", 5), None);
+
+        // older versions are still visible at older sequences
+        assert_eq!(get(b"key0", 3), Some(ValueItem::Value(b"value".to_vec())));
+        assert_eq!(get(b"key0", 2), None);
+        assert_eq!(get(b"key1", 4), Some(ValueItem::Value(b"value1".to_vec())));
+
+        let mut iter = memtable.iter();
+        iter.seek_to_first();
+        let mut kvs = Vec::new();
+        while iter.valid() {
+            let (user_key, sequence, _) = parse_internal_key(iter.key()).unwrap();
+            kvs.push((user_key.to_vec(), sequence, iter.value().to_vec()));
+            iter.next();
+        }
+        assert_eq!(
+            kvs,
+            vec![
+                (v!(b"key0"), 4, v!(b"value0")),
+                (v!(b"key0"), 3, v!(b"value")),
+                (v!(b"key1"), 5, v!(b"")),
+                (v!(b"key1"), 1, v!(b"value1")),
+                (v!(b"key2"), 2, v!(b"value2")),
+            ]
+        );
+
+        iter.seek(InternalKey::new(b"key1", 3, ValueTypeCode::Value).get_contents());
+        assert_eq!(parse_internal_key(iter.key()).unwrap().1, 1);
+        iter.prev();
+        assert_eq!(parse_internal_key(iter.key()).unwrap().1, 5);
+        iter.seek_to_last();
+        assert_eq!(parse_internal_key(iter.key()).unwrap().0, b"key2");
+    }
+}
@@ -0,0 +1,273 @@
+use std::cmp::Ordering;
+
+use crate::iterator::InternalIterator;
+use crate::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Reverse,
+}
+
+// MergingIterator merges sorted iterators into one sorted iterator.
+// keys must be unique across the children, which holds for internal keys.
+pub struct MergingIterator<I> {
+    children: Vec<I>,
+    // the child which holds the current entry
+    current: Option<usize>,
+    direction: Direction,
+    compare: fn(&[u8], &[u8]) -> Ordering,
+}
+
+impl<I: InternalIterator> MergingIterator<I> {
+    pub fn new(children: Vec<I>, compare: fn(&[u8], &[u8]) -> Ordering) -> Self {
+        MergingIterator {
+            children,
+            current: None,
+            direction: Direction::Forward,
+            compare,
+        }
+    }
+
+    // the number of children is small, so a linear scan is enough to find the smallest
+    fn find_smallest(&mut self) {
+        let mut smallest: Option<usize> = None;
+        for (i, child) in self.children.iter().enumerate() {
+            if !child.valid() {
+                continue;
+            }
+            match smallest {
+                Some(s) if (self.compare)(child.key(), self.children[s].key()).is_ge() => {}
+                _ => smallest = Some(i),
+            }
+        }
+        self.current = smallest;
+    }
+
+    fn find_largest(&mut self) {
+        let mut largest: Option<usize> = None;
+        for (i, child) in self.children.iter().enumerate() {
+            if !child.valid() {
+                continue;
+            }
+            match largest {
+                Some(l) if (self.compare)(child.key(), self.children[l].key()).is_lt() => {}
+                _ => largest = Some(i),
+            }
+        }
+        self.current = largest;
+    }
+}
+
+impl<I: InternalIterator> InternalIterator for MergingIterator<I> {
+    fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    fn seek_to_first(&mut self) {
+        for child in &mut self.children {
+            child.seek_to_first();
+        }
+        self.find_smallest();
+        self.direction = Direction::Forward;
+    }
+
+    fn seek_to_last(&mut self) {
+        for child in &mut self.children {
+            child.seek_to_last();
+        }
+        self.find_largest();
+        self.direction = Direction::Reverse;
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        for child in &mut self.children {
+            child.seek(target);
+        }
+        self.find_smallest();
+        self.direction = Direction::Forward;
+    }
+
+    fn next(&mut self) {
+        let current = self.current.expect("next on an invalid iterator");
+
+        // all the non-current children must be positioned after the current key.
+        // after a reverse move they are before it, so they are moved forward first.
+        if self.direction != Direction::Forward {
+            let key = self.children[current].key().to_vec();
+            for (i, child) in self.children.iter_mut().enumerate() {
+                if i == current {
+                    continue;
+                }
+                child.seek(&key);
+                if child.valid() && (self.compare)(&key, child.key()).is_eq() {
+                    child.next();
+                }
+            }
+            self.direction = Direction::Forward;
+        }
+
+        self.children[current].next();
+        self.find_smallest();
+    }
+
+    fn prev(&mut self) {
+        let current = self.current.expect("prev on an invalid iterator");
+
+        // all the non-current children must be positioned before the current key.
+        // after a forward move they are at or after it, so they are moved backward first.
+        if self.direction != Direction::Reverse {
+            let key = self.children[current].key().to_vec();
+            for (i, child) in self.children.iter_mut().enumerate() {
+                if i == current {
+                    continue;
+                }
+                child.seek(&key);
+                if child.valid() {
+                    // the child is at the first entry >= key
+                    child.prev();
+                } else {
+                    // the child has no entry >= key, so its last entry is before key
+                    child.seek_to_last();
+                }
+            }
+            self.direction = Direction::Reverse;
+        }
+
+        self.children[current].prev();
+        self.find_largest();
+    }
+
+    fn key(&self) -> &[u8] {
+        let current = self.current.expect("key on an invalid iterator");
+        self.children[current].key()
+    }
+
+    fn value(&self) -> &[u8] {
+        let current = self.current.expect("value on an invalid iterator");
+        self.children[current].value()
+    }
+
+    fn status(&self) -> Result<()> {
+        for child in &self.children {
+            child.status()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // VecIterator is an iterator over sorted entries in memory
+    struct VecIterator {
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+        position: usize,
+    }
+
+    impl VecIterator {
+        fn new(keys: &[&str], value: &str) -> Self {
+            let entries = keys
+                .iter()
+                .map(|k| (k.as_bytes().to_vec(), value.as_bytes().to_vec()))
+                .collect::<Vec<_>>();
+            let position = entries.len();
+            VecIterator { entries, position }
+        }
+    }
+
+    impl InternalIterator for VecIterator {
+        fn valid(&self) -> bool {
+            self.position < self.entries.len()
+        }
+
+        fn seek_to_first(&mut self) {
+            self.position = 0;
+        }
+
+        fn seek_to_last(&mut self) {
+            self.position = self.entries.len().saturating_sub(1);
+        }
+
+        fn seek(&mut self, target: &[u8]) {
+            self.position = self.entries.partition_point(|(k, _)| k.as_slice() < target);
+        }
+
+        fn next(&mut self) {
+            self.position += 1;
+        }
+
+        fn prev(&mut self) {
+            // wraps around to an invalid position before the first entry
+            self.position = self.position.checked_sub(1).unwrap_or(self.entries.len());
+        }
+
+        fn key(&self) -> &[u8] {
+            &self.entries[self.position].0
+        }
+
+        fn value(&self) -> &[u8] {
+            &self.entries[self.position].1
+        }
+
+        fn status(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn entry(iter: &impl InternalIterator) -> String {
+        format!(
+            "{}:{}",
+            String::from_utf8_lossy(iter.key()),
+            String::from_utf8_lossy(iter.value())
+        )
+    }
+
+    #[test]
+    fn test_merging_iterator() {
+        let children = vec![
+            VecIterator::new(&["b", "d", "f"], "0"),
+            VecIterator::new(&[], "1"),
+            VecIterator::new(&["a", "bb", "g"], "2"),
+            VecIterator::new(&["c"], "3"),
+        ];
+        let mut iter = MergingIterator::new(children, |a, b| a.cmp(b));
+
+        let mut forward = Vec::new();
+        iter.seek_to_first();
+        while iter.valid() {
+            forward.push(entry(&iter));
+            iter.next();
+        }
+        assert_eq!(
+            forward,
+            vec!["a:2", "b:0", "bb:2", "c:3", "d:0", "f:0", "g:2"]
+        );
+
+        let mut backward = Vec::new();
+        iter.seek_to_last();
+        while iter.valid() {
+            backward.push(entry(&iter));
+            iter.prev();
+        }
+        forward.reverse();
+        assert_eq!(backward, forward);
+
+        // change the direction in the middle
+        iter.seek(b"c");
+        assert_eq!(entry(&iter), "c:3");
+        iter.prev();
+        assert_eq!(entry(&iter), "bb:2");
+        iter.prev();
+        assert_eq!(entry(&iter), "b:0");
+        iter.next();
+        assert_eq!(entry(&iter), "bb:2");
+        iter.next();
+        assert_eq!(entry(&iter), "c:3");
+        iter.seek(b"e");
+        assert_eq!(entry(&iter), "f:0");
+        iter.seek(b"h");
+        assert!(!iter.valid());
+    }
+}
@@ -0,0 +1,385 @@
+use std::cmp::Ordering;
+use std::ops::Deref;
+use std::ptr;
+use std::sync::atomic::{self, AtomicPtr, AtomicUsize};
+use std::sync::Mutex;
+
+const MAX_HEIGHT: usize = 12;
+// each node is linked at the next level with probability 1/BRANCHING
+const BRANCHING: u32 = 4;
+
+struct Node {
+    key: Box<[u8]>,
+    value: Box<[u8]>,
+    // next[i] is the next node at level i. the length is the height of the node
+    next: Box<[AtomicPtr<Node>]>,
+}
+
+impl Node {
+    fn new(key: &[u8], value: &[u8], height: usize) -> *mut Node {
+        let next = (0..height)
+            .map(|_| AtomicPtr::new(ptr::null_mut()))
+            .collect();
+        Box::into_raw(Box::new(Node {
+            key: key.into(),
+            value: value.into(),
+            next,
+        }))
+    }
+
+    // acquire load, so that a reader observes a fully initialized node
+    fn next(&self, level: usize) -> *mut Node {
+        self.next[level].load(atomic::Ordering::Acquire)
+    }
+
+    // release store, so that the node is fully initialized before it's published
+    fn set_next(&self, level: usize, node: *mut Node) {
+        self.next[level].store(node, atomic::Ordering::Release)
+    }
+}
+
+// SkipList is an ordered set of keys with attached values.
+//
+// inserts are serialized by an internal lock, while reads never block: a node is published by
+// an atomic pointer store after it's fully built, and no node is freed until the list is dropped.
+// keys must be unique.
+pub struct SkipList {
+    head: *mut Node,
+    max_height: AtomicUsize,
+    compare: fn(&[u8], &[u8]) -> Ordering,
+
+    // serializes inserts, and holds the state of the random generator for node heights
+    rnd: Mutex<u32>,
+}
+
+// nodes are only reachable through the list, and they are immutable once published
+unsafe impl Send for SkipList {}
+unsafe impl Sync for SkipList {}
+
+impl SkipList {
+    pub fn new(compare: fn(&[u8], &[u8]) -> Ordering) -> Self {
+        SkipList {
+            head: Node::new(&[], &[], MAX_HEIGHT),
+            max_height: AtomicUsize::new(1),
+            compare,
+            rnd: Mutex::new(0xdeadbeef),
+        }
+    }
+
+    fn max_height(&self) -> usize {
+        self.max_height.load(atomic::Ordering::Relaxed)
+    }
+
+    fn random_height(rnd: &mut u32) -> usize {
+        let mut height = 1;
+        while height < MAX_HEIGHT && next_random(rnd).is_multiple_of(BRANCHING) {
+            height += 1;
+        }
+        height
+    }
+
+    fn key_is_after_node(&self, key: &[u8], node: *mut Node) -> bool {
+        !node.is_null() && (self.compare)(unsafe { &(*node).key }, key).is_lt()
+    }
+
+    // find_greater_or_equal returns the first node whose key is not less than key, or null.
+    // if prev is given, prev[level] is filled with the last node before key at each level.
+    fn find_greater_or_equal(
+        &self,
+        key: &[u8],
+        mut prev: Option<&mut [*mut Node; MAX_HEIGHT]>,
+    ) -> *mut Node {
+        let mut x = self.head;
+        let mut level = self.max_height() - 1;
+        loop {
+            let next = unsafe { (*x).next(level) };
+            if self.key_is_after_node(key, next) {
+                x = next;
+            } else {
+                if let Some(prev) = prev.as_mut() {
+                    prev[level] = x;
+                }
+                if level == 0 {
+                    return next;
+                }
+                level -= 1;
+            }
+        }
+    }
+
+    // find_less_than returns the last node whose key is less than key, or head.
+    fn find_less_than(&self, key: &[u8]) -> *mut Node {
+        let mut x = self.head;
+        let mut level = self.max_height() - 1;
+        loop {
+            let next = unsafe { (*x).next(level) };
+            if self.key_is_after_node(key, next) {
+                x = next;
+            } else {
+                if level == 0 {
+                    return x;
+                }
+                level -= 1;
+            }
+        }
+    }
+
+    // find_last returns the last node in the list, or head if the list is empty.
+    fn find_last(&self) -> *mut Node {
+        let mut x = self.head;
+        let mut level = self.max_height() - 1;
+        loop {
+            let next = unsafe { (*x).next(level) };
+            if !next.is_null() {
+                x = next;
+            } else {
+                if level == 0 {
+                    return x;
+                }
+                level -= 1;
+            }
+        }
+    }
+
+    pub fn insert(&self, key: &[u8], value: &[u8]) {
+        let mut rnd = self.rnd.lock().unwrap();
+
+        let mut prev = [ptr::null_mut(); MAX_HEIGHT];
+        let x = self.find_greater_or_equal(key, Some(&mut prev));
+        assert!(
+            x.is_null() || (self.compare)(key, unsafe { &(*x).key }).is_ne(),
+            "duplicate key is inserted into skiplist"
+        );
+
+        let height = Self::random_height(&mut rnd);
+        let max_height = self.max_height();
+        if height > max_height {
+            prev[max_height..height].fill(self.head);
+            // a reader which observes the new height before the new node just sees null from head
+            self.max_height.store(height, atomic::Ordering::Relaxed);
+        }
+
+        let node = Node::new(key, value, height);
+        for (level, prev) in prev.iter().enumerate().take(height) {
+            unsafe {
+                // the node isn't published yet, so its own pointers need no barrier
+                (*node).next[level].store((**prev).next(level), atomic::Ordering::Relaxed);
+                (**prev).set_next(level, node);
+            }
+        }
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        let x = self.find_greater_or_equal(key, None);
+        !x.is_null() && (self.compare)(key, unsafe { &(*x).key }).is_eq()
+    }
+
+    pub fn iter(&self) -> Iter<&SkipList> {
+        Iter::new(self)
+    }
+}
+
+impl Drop for SkipList {
+    fn drop(&mut self) {
+        let mut x = self.head;
+        while !x.is_null() {
+            let node = unsafe { Box::from_raw(x) };
+            x = node.next(0);
+        }
+    }
+}
+
+// next_random is the Park-Miller minimal standard generator
+fn next_random(seed: &mut u32) -> u32 {
+    const M: u64 = 2147483647;
+    const A: u64 = 16807;
+    let mut s = *seed as u64 % M;
+    if s == 0 || s == M {
+        s = 1;
+    }
+    *seed = ((s * A) % M) as u32;
+    *seed
+}
+
+// Iter is a cursor over the list. it's not valid until it's positioned by a seek.
+// the list is held by L, which is a reference or a shared pointer such as Arc<SkipList>.
+pub struct Iter<L> {
+    list: L,
+    node: *mut Node,
+}
+
+// the cursor only reads published nodes, which are never freed while the list is held
+unsafe impl<L: Send> Send for Iter<L> {}
+unsafe impl<L: Sync> Sync for Iter<L> {}
+
+impl<L: Deref<Target = SkipList>> Iter<L> {
+    pub fn new(list: L) -> Self {
+        Iter {
+            list,
+            node: ptr::null_mut(),
+        }
+    }
+
+    pub fn valid(&self) -> bool {
+        !self.node.is_null()
+    }
+
+    pub fn key(&self) -> &[u8] {
+        assert!(self.valid());
+        unsafe { &(*self.node).key }
+    }
+
+    pub fn value(&self) -> &[u8] {
+        assert!(self.valid());
+        unsafe { &(*self.node).value }
+    }
+
+    pub fn next(&mut self) {
+        assert!(self.valid());
+        self.node = unsafe { (*self.node).next(0) };
+    }
+
+    pub fn prev(&mut self) {
+        assert!(self.valid());
+        // there are no back links, so search for the last node before the current key
+        self.node = self.list.find_less_than(unsafe { &(*self.node).key });
+        if self.node == self.list.head {
+            self.node = ptr::null_mut();
+        }
+    }
+
+    // seek positions at the first entry whose key is not less than target
+    pub fn seek(&mut self, target: &[u8]) {
+        self.node = self.list.find_greater_or_equal(target, None);
+    }
+
+    pub fn seek_to_first(&mut self) {
+        self.node = unsafe { (*self.list.head).next(0) };
+    }
+
+    pub fn seek_to_last(&mut self) {
+        self.node = self.list.find_last();
+        if self.node == self.list.head {
+            self.node = ptr::null_mut();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    fn compare(a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn encode(n: u64) -> Vec<u8> {
+        n.to_be_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_empty() {
+        let list = SkipList::new(compare);
+        assert!(!list.contains(b"a"));
+
+        let mut iter = list.iter();
+        assert!(!iter.valid());
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        iter.seek(b"a");
+        assert!(!iter.valid());
+        iter.seek_to_last();
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn test_insert_and_lookup() {
+        let list = SkipList::new(compare);
+        let mut keys = BTreeSet::new();
+        let mut rnd = 1000;
+        for _ in 0..2000 {
+            let key = next_random(&mut rnd) as u64 % 5000;
+            if keys.insert(key) {
+                list.insert(&encode(key), &encode(key * 2));
+            }
+        }
+
+        for i in 0..5000 {
+            assert_eq!(list.contains(&encode(i)), keys.contains(&i));
+        }
+
+        // forward iteration
+        let mut iter = list.iter();
+        iter.seek_to_first();
+        for key in &keys {
+            assert!(iter.valid());
+            assert_eq!(iter.key(), encode(*key));
+            assert_eq!(iter.value(), encode(*key * 2));
+            iter.next();
+        }
+        assert!(!iter.valid());
+
+        // backward iteration
+        iter.seek_to_last();
+        for key in keys.iter().rev() {
+            assert!(iter.valid());
+            assert_eq!(iter.key(), encode(*key));
+            iter.prev();
+        }
+        assert!(!iter.valid());
+
+        // seek
+        for i in 0..5000 {
+            iter.seek(&encode(i));
+            match keys.range(i..).next() {
+                Some(key) => assert_eq!(iter.key(), encode(*key)),
+                None => assert!(!iter.valid()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_concurrent_read_while_writing() {
+        let list = Arc::new(SkipList::new(compare));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let readers = (0..4)
+            .map(|_| {
+                let list = list.clone();
+                let done = done.clone();
+                std::thread::spawn(move || {
+                    while !done.load(atomic::Ordering::Acquire) {
+                        // every reader sees a sorted prefix of the inserted keys
+                        let mut iter = list.iter();
+                        iter.seek_to_first();
+                        let mut last: Option<Vec<u8>> = None;
+                        while iter.valid() {
+                            let key = iter.key().to_vec();
+                            assert_eq!(iter.value(), key.as_slice());
+                            if let Some(last) = &last {
+                                assert!(last < &key);
+                            }
+                            last = Some(key);
+                            iter.next();
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut rnd = 301;
+        for _ in 0..20000 {
+            let key = encode(next_random(&mut rnd) as u64);
+            if !list.contains(&key) {
+                list.insert(&key, &key);
+            }
+        }
+        done.store(true, atomic::Ordering::Release);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+// Snapshot is a consistent view of the db as of a sequence number.
+// the view is kept as long as the handle is alive, and it's released on drop.
+#[derive(Debug)]
+pub struct Snapshot {
+    sequence: u64,
+    list: Arc<SnapshotList>,
+}
+
+impl Snapshot {
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.list.release(self.sequence);
+    }
+}
+
+// SnapshotList tracks the sequence numbers pinned by live snapshots
+#[derive(Debug, Default)]
+pub(crate) struct SnapshotList {
+    // number of live snapshots for each sequence
+    sequences: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl SnapshotList {
+    pub(crate) fn acquire(self: &Arc<Self>, sequence: u64) -> Snapshot {
+        *self.sequences.lock().unwrap().entry(sequence).or_default() += 1;
+        Snapshot {
+            sequence,
+            list: self.clone(),
+        }
+    }
+
+    fn release(&self, sequence: u64) {
+        let mut sequences = self.sequences.lock().unwrap();
+        let count = sequences
+            .get_mut(&sequence)
+            .expect("released snapshot is not in the list");
+        *count -= 1;
+        if *count == 0 {
+            sequences.remove(&sequence);
+        }
+    }
+
+    // oldest returns the smallest sequence still pinned by a snapshot
+    pub(crate) fn oldest(&self) -> Option<u64> {
+        self.sequences.lock().unwrap().keys().next().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_list() {
+        let list = Arc::new(SnapshotList::default());
+        assert_eq!(list.oldest(), None);
+
+        let s1 = list.acquire(10);
+        let s2 = list.acquire(10);
+        let s3 = list.acquire(20);
+        assert_eq!(s3.sequence(), 20);
+        assert_eq!(list.oldest(), Some(10));
+
+        drop(s1);
+        assert_eq!(list.oldest(), Some(10));
+        drop(s2);
+        assert_eq!(list.oldest(), Some(20));
+        drop(s3);
+        assert_eq!(list.oldest(), None);
+    }
+}
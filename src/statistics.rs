@@ -0,0 +1,101 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Statistics counts the events of a db. it's shared through Options, so that
+// the counters are read while the db is running, and a set of dbs can share one.
+#[derive(Debug, Default)]
+pub struct Statistics {
+    filter_hits: AtomicU64,
+    filter_misses: AtomicU64,
+    write_groups: AtomicU64,
+    grouped_writes: AtomicU64,
+    log_syncs: AtomicU64,
+    write_slowdowns: AtomicU64,
+    write_slowdown_micros: AtomicU64,
+    write_stops: AtomicU64,
+    write_stop_micros: AtomicU64,
+}
+
+impl Statistics {
+    // record_filter records a filter lookup for a data block.
+    // may_match is false if the filter ruled the key out, and the block read was saved.
+    pub(crate) fn record_filter(&self, may_match: bool) {
+        let counter = if may_match {
+            &self.filter_hits
+        } else {
+            &self.filter_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // record_write_group records a group of writes which was written to the log as one record
+    pub(crate) fn record_write_group(&self, writes: usize, sync: bool) {
+        self.write_groups.fetch_add(1, Ordering::Relaxed);
+        self.grouped_writes
+            .fetch_add(writes as u64, Ordering::Relaxed);
+        if sync {
+            self.log_syncs.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // record_write_slowdown records a write which was delayed because level 0 has many files
+    pub(crate) fn record_write_slowdown(&self, duration: Duration) {
+        self.write_slowdowns.fetch_add(1, Ordering::Relaxed);
+        self.write_slowdown_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    // record_write_stop records a write which waited for the background work,
+    // because the memtables were full or level 0 had too many files
+    pub(crate) fn record_write_stop(&self, duration: Duration) {
+        self.write_stops.fetch_add(1, Ordering::Relaxed);
+        self.write_stop_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    // filter_hits is the number of lookups where the filter said the key may be in the data block
+    pub fn filter_hits(&self) -> u64 {
+        self.filter_hits.load(Ordering::Relaxed)
+    }
+
+    // filter_misses is the number of lookups where the filter ruled the key out
+    pub fn filter_misses(&self) -> u64 {
+        self.filter_misses.load(Ordering::Relaxed)
+    }
+
+    // write_groups is the number of log records written, each of which has the writes of a group
+    pub fn write_groups(&self) -> u64 {
+        self.write_groups.load(Ordering::Relaxed)
+    }
+
+    // grouped_writes is the number of writes in all groups.
+    // divided by write_groups, it's the average number of writes which share a log record and a sync.
+    pub fn grouped_writes(&self) -> u64 {
+        self.grouped_writes.load(Ordering::Relaxed)
+    }
+
+    // log_syncs is the number of syncs of the log
+    pub fn log_syncs(&self) -> u64 {
+        self.log_syncs.load(Ordering::Relaxed)
+    }
+
+    // write_slowdowns is the number of writes delayed by the slowdown trigger of level 0
+    pub fn write_slowdowns(&self) -> u64 {
+        self.write_slowdowns.load(Ordering::Relaxed)
+    }
+
+    // write_slowdown_micros is the total time of the delays of write_slowdowns
+    pub fn write_slowdown_micros(&self) -> u64 {
+        self.write_slowdown_micros.load(Ordering::Relaxed)
+    }
+
+    // write_stops is the number of times a write waited for a flush or a compaction
+    pub fn write_stops(&self) -> u64 {
+        self.write_stops.load(Ordering::Relaxed)
+    }
+
+    // write_stop_micros is the total time which writes were stopped for
+    pub fn write_stop_micros(&self) -> u64 {
+        self.write_stop_micros.load(Ordering::Relaxed)
+    }
+}
@@ -1,34 +1,81 @@
+use std::cmp::Ordering;
 use std::fs::File;
+use std::io::Write;
+use std::ops::Range;
 use std::os::unix::fs::FileExt;
+use std::sync::Arc;
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
-use crate::{decode_fixed32, decode_varint32, decode_varint64, put_varint64, Result, Status};
+use crate::cache::BlockCache;
+use crate::compression::{self, CompressionType};
+use crate::filter_block::{FilterBlockBuilder, FilterBlockReader};
+use crate::iterator::InternalIterator;
+use crate::{
+    compare_internal_key, decode_fixed32, decode_varint32, decode_varint64, put_fixed32,
+    put_fixed64, put_varint64, BlockBuilder, Options, ReadOptions, Result, Statistics, Status,
+};
 
-pub struct Block<'a> {
-    data: &'a [u8],
+// Block is a sorted sequence of entries, followed by the restart array.
+//
+// keys are prefix-compressed against the previous key, except at restart points where the full key
+// is stored. the offsets of the restart points are kept at the end of the block.
+#[derive(Debug, Clone)]
+pub struct Block {
+    data: Arc<[u8]>,
     restart_offset: usize,
+    num_restarts: usize,
 }
 
-impl<'a> Block<'a> {
-    pub fn new(data: &'a [u8]) -> Option<Block<'a>> {
+impl Block {
+    pub fn new(data: impl Into<Arc<[u8]>>) -> Option<Block> {
+        let data = data.into();
+        if data.len() < 4 {
+            return None;
+        }
+        let num_restarts = get_num_restarts(&data) as usize;
         let max_restarts_allowed = (data.len() - 4) / 4;
-        if get_num_restarts(data) as usize > max_restarts_allowed {
+        if num_restarts > max_restarts_allowed {
             return None;
         }
-        let restart_offset = data.len() - (1 + get_num_restarts(data)) as usize * 4;
+        let restart_offset = data.len() - (1 + num_restarts) * 4;
         Some(Block {
             data,
             restart_offset,
+            num_restarts,
         })
     }
 
-    pub fn iter(self) -> impl Iterator<Item = (Vec<u8>, &'a [u8])> {
-        BlockIterator::new(
-            self.data,
-            self.restart_offset as u32,
-            get_num_restarts(self.data),
-        )
-        .unwrap()
+    // size returns the size of the block contents
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    // new_iterator returns a cursor over the entries, which are ordered by compare
+    pub fn new_iterator(&self, compare: fn(&[u8], &[u8]) -> Ordering) -> BlockIterator {
+        BlockIterator {
+            block: self.clone(),
+            compare,
+            current: self.restart_offset,
+            restart_index: self.num_restarts,
+            key: Vec::new(),
+            value: 0..0,
+            status: None,
+        }
+    }
+
+    // iter returns all entries in order
+    pub fn iter(&self) -> impl Iterator<Item = (Vec<u8>, &[u8])> {
+        // a forward scan never compares keys
+        let mut iter = self.new_iterator(|a, b| a.cmp(b));
+        iter.seek_to_first();
+        std::iter::from_fn(move || {
+            if !iter.valid() {
+                return None;
+            }
+            let entry = (iter.key.clone(), &self.data[iter.value.clone()]);
+            iter.next();
+            Some(entry)
+        })
     }
 }
 
@@ -68,82 +115,188 @@ fn decode_entry(data: &[u8]) -> Option<(usize, u32, u32, u32)> {
     Some((key_offset, shared, non_shared, value_length))
 }
 
-pub struct BlockIterator<'a> {
-    block: &'a [u8],
-    restart_offset: usize,
-    num_restarts: usize,
+// BlockIterator is a cursor over the entries of a block.
+// seek binary-searches the restart points, and then scans the entries from the found one.
+pub struct BlockIterator {
+    block: Block,
+    compare: fn(&[u8], &[u8]) -> Ordering,
 
+    // offset of the current entry. the iterator is invalid if it's restart_offset
     current: usize,
-    current_restart_index: usize,
+    // index of the restart point which the current entry belongs to
+    restart_index: usize,
 
     key: Vec<u8>,
+    value: Range<usize>,
 
-    // value_offset is offset from the current offset
-    value_offset: usize,
-    value_size: usize,
+    status: Option<Status>,
 }
 
-impl<'a> BlockIterator<'a> {
-    pub fn new(
-        data: &'a [u8],
-        restart_offset: u32,
-        num_restarts: u32,
-    ) -> Option<BlockIterator<'a>> {
-        Some(BlockIterator {
-            block: data,
-            restart_offset: restart_offset as usize,
-            num_restarts: num_restarts as usize,
-
-            current: 0,
-            current_restart_index: 0,
+impl BlockIterator {
+    fn next_entry_offset(&self) -> usize {
+        self.value.end
+    }
 
-            key: Vec::new(),
-            value_offset: 0,
-            value_size: 0,
-        })
+    fn get_restart_point(&self, index: usize) -> usize {
+        assert!(index < self.block.num_restarts);
+        decode_fixed32(&self.block.data[self.block.restart_offset + index * 4..]) as usize
     }
 
-    fn next_entry_offset(&self) -> usize {
-        self.current + self.value_offset + self.value_size
+    fn seek_to_restart_point(&mut self, index: usize) {
+        self.key.clear();
+        self.restart_index = index;
+        // current is fixed by parse_next_key, which starts at the end of value
+        let offset = self.get_restart_point(index);
+        self.value = offset..offset;
     }
 
-    fn get_restart_point(&self, idx: usize) -> usize {
-        assert!(idx < self.num_restarts);
-        decode_fixed32(&self.block[self.restart_offset + idx * 4..]) as usize
+    fn invalidate(&mut self) {
+        self.current = self.block.restart_offset;
+        self.restart_index = self.block.num_restarts;
     }
-}
 
-impl<'a> Iterator for BlockIterator<'a> {
-    type Item = (Vec<u8>, &'a [u8]);
+    fn corruption_error(&mut self) {
+        self.invalidate();
+        self.status = Some(Status::Corruption("bad entry in block".to_string()));
+        self.key.clear();
+        self.value = 0..0;
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
+    // parse_next_key moves to the entry after the current one, and returns false at the end
+    fn parse_next_key(&mut self) -> bool {
         self.current = self.next_entry_offset();
-        if self.current >= self.restart_offset {
-            return None;
+        if self.current >= self.block.restart_offset {
+            self.invalidate();
+            return false;
         }
 
-        let (key_offset, shared, non_shared, value_length) =
-            decode_entry(&self.block[self.current..self.restart_offset])?;
+        let Some((key_offset, shared, non_shared, value_length)) =
+            decode_entry(&self.block.data[self.current..self.block.restart_offset])
+        else {
+            self.corruption_error();
+            return false;
+        };
+        if self.key.len() < shared as usize {
+            self.corruption_error();
+            return false;
+        }
 
-        self.key.resize(shared as usize, 0);
-        self.key.extend_from_slice(
-            &self.block[self.current + key_offset..self.current + key_offset + non_shared as usize],
-        );
-        self.value_offset = key_offset + non_shared as usize;
-        self.value_size = value_length as usize;
+        let key_start = self.current + key_offset;
+        let value_start = key_start + non_shared as usize;
+        self.key.truncate(shared as usize);
+        self.key
+            .extend_from_slice(&self.block.data[key_start..value_start]);
+        self.value = value_start..value_start + value_length as usize;
 
-        // Note: self.get_restart_point(self.current_restart_index + 1) < self.current
-        while self.current_restart_index + 1 < self.num_restarts
-            && self.get_restart_point(self.current_restart_index + 1) <= self.current
+        while self.restart_index + 1 < self.block.num_restarts
+            && self.get_restart_point(self.restart_index + 1) < self.current
         {
-            self.current_restart_index += 1;
+            self.restart_index += 1;
+        }
+        true
+    }
+}
+
+impl InternalIterator for BlockIterator {
+    fn valid(&self) -> bool {
+        self.current < self.block.restart_offset
+    }
+
+    fn seek_to_first(&mut self) {
+        if self.block.num_restarts == 0 {
+            self.invalidate();
+            return;
+        }
+        self.seek_to_restart_point(0);
+        self.parse_next_key();
+    }
+
+    fn seek_to_last(&mut self) {
+        if self.block.num_restarts == 0 {
+            self.invalidate();
+            return;
+        }
+        self.seek_to_restart_point(self.block.num_restarts - 1);
+        while self.parse_next_key() && self.next_entry_offset() < self.block.restart_offset {}
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        if self.block.num_restarts == 0 {
+            self.invalidate();
+            return;
+        }
+
+        // binary search for the last restart point whose key is less than target.
+        // the keys at restart points are not prefix-compressed, so they can be compared directly.
+        let mut left = 0;
+        let mut right = self.block.num_restarts - 1;
+        while left < right {
+            let mid = (left + right).div_ceil(2);
+            let region_offset = self.get_restart_point(mid);
+            let Some((key_offset, shared, non_shared, _)) =
+                decode_entry(&self.block.data[region_offset..self.block.restart_offset])
+            else {
+                self.corruption_error();
+                return;
+            };
+            if shared != 0 {
+                self.corruption_error();
+                return;
+            }
+            let key_start = region_offset + key_offset;
+            let mid_key = &self.block.data[key_start..key_start + non_shared as usize];
+            if (self.compare)(mid_key, target).is_lt() {
+                left = mid;
+            } else {
+                right = mid - 1;
+            }
+        }
+
+        // linear search in the restart interval for the first key which is not less than target
+        self.seek_to_restart_point(left);
+        while self.parse_next_key() {
+            if (self.compare)(&self.key, target).is_ge() {
+                return;
+            }
+        }
+    }
+
+    fn next(&mut self) {
+        assert!(self.valid());
+        self.parse_next_key();
+    }
+
+    fn prev(&mut self) {
+        assert!(self.valid());
+
+        // entries can only be decoded forward, so scan from the restart point before the current entry
+        let original = self.current;
+        while self.get_restart_point(self.restart_index) >= original {
+            if self.restart_index == 0 {
+                self.invalidate();
+                return;
+            }
+            self.restart_index -= 1;
         }
+        self.seek_to_restart_point(self.restart_index);
+        while self.parse_next_key() && self.next_entry_offset() < original {}
+    }
+
+    fn key(&self) -> &[u8] {
+        assert!(self.valid());
+        &self.key
+    }
+
+    fn value(&self) -> &[u8] {
+        assert!(self.valid());
+        &self.block.data[self.value.clone()]
+    }
 
-        Some((
-            self.key.to_vec(),
-            &self.block[self.current + self.value_offset
-                ..self.current + self.value_offset + self.value_size],
-        ))
+    fn status(&self) -> Result<()> {
+        match &self.status {
+            Some(e) => Err(e.clone()),
+            None => Ok(()),
+        }
     }
 }
 
@@ -152,10 +305,32 @@ pub const BLOCK_HANDLE_MAX_ENCODED_LENGTH: usize = 10 + 10;
 // type and crc32 size
 const BLOCK_TRAILER_SIZE: usize = 5;
 
+// the metaindex key of the filter block is this prefix followed by the name of the filter policy
+pub const FILTER_KEY_PREFIX: &str = "filter.";
+
+// the metaindex key of the block which holds the zstd dictionary of the data blocks
+pub const ZSTD_DICTIONARY_KEY: &str = "zstd.dictionary";
+
+// data blocks of this many times the dictionary size are collected to train the dictionary
+const ZSTD_TRAINING_BYTES_PER_DICTIONARY_BYTE: usize = 100;
+
 pub const FOOTER_ENCODED_LENGTH: usize = 2 * BLOCK_HANDLE_MAX_ENCODED_LENGTH + 8;
 
 const TABLE_MAGIC_NUMBER: u64 = 0xdb4775248b80fb57;
 
+const MASK_DELTA: u32 = 0xa282ead8;
+
+// mask_crc returns a masked representation of crc.
+// computing the CRC of a string that contains embedded CRCs is problematic, so stored CRCs are masked.
+pub(crate) fn mask_crc(crc: u32) -> u32 {
+    crc.rotate_right(15).wrapping_add(MASK_DELTA)
+}
+
+// unmask_crc returns the crc whose masked representation is masked_crc
+pub(crate) fn unmask_crc(masked_crc: u32) -> u32 {
+    masked_crc.wrapping_sub(MASK_DELTA).rotate_left(15)
+}
+
 // BlockHandle specifies a Block's location in a file.
 // but it's not contain a type(1-byte) and checksum(4-bytes)
 #[derive(Debug)]
@@ -166,8 +341,9 @@ pub struct BlockHandle {
 
 impl BlockHandle {
     pub fn decode_from(src: &[u8]) -> Result<(BlockHandle, usize)> {
-        let (offset, offset_bytes) = decode_varint64(src).ok_or(Status::Corruption)?;
-        let (size, size_bytes) = decode_varint64(&src[offset_bytes..]).ok_or(Status::Corruption)?;
+        let corruption = || Status::Corruption("bad block handle".to_string());
+        let (offset, offset_bytes) = decode_varint64(src).ok_or_else(corruption)?;
+        let (size, size_bytes) = decode_varint64(&src[offset_bytes..]).ok_or_else(corruption)?;
 
         Ok((BlockHandle { offset, size }, offset_bytes + size_bytes))
     }
@@ -178,22 +354,424 @@ impl BlockHandle {
     }
 }
 
+// read_block reads the block pointed by handle into scratch, and returns its contents.
+// if verify_checksums is true, the crc in the trailer is checked against the contents and the type.
 pub fn read_block<'a>(
     file: &File,
     handle: &BlockHandle,
     scratch: &'a mut Vec<u8>,
+    verify_checksums: bool,
+) -> Result<&'a [u8]> {
+    read_block_with_dictionary(file, handle, scratch, verify_checksums, None)
+}
+
+// read_block_with_dictionary is read_block for a block compressed with dictionary.
+// a compressed block is decompressed by the compressor registered for the type in the trailer.
+pub fn read_block_with_dictionary<'a>(
+    file: &File,
+    handle: &BlockHandle,
+    scratch: &'a mut Vec<u8>,
+    verify_checksums: bool,
+    dictionary: Option<&[u8]>,
 ) -> Result<&'a [u8]> {
-    scratch.resize(handle.size as usize + BLOCK_TRAILER_SIZE, 0);
+    let n = handle.size as usize;
+    scratch.resize(n + BLOCK_TRAILER_SIZE, 0);
     file.read_exact_at(scratch, handle.offset)?;
 
-    // TODO: check crc verify
+    if verify_checksums {
+        let crc = unmask_crc(decode_fixed32(&scratch[n + 1..]));
+        let actual = crc32c::crc32c(&scratch[..n + 1]);
+        if crc != actual {
+            return Err(Status::Corruption(format!(
+                "block checksum mismatch at offset {}",
+                handle.offset
+            )));
+        }
+    }
+
+    if scratch[n] == CompressionType::No.block_type() {
+        return Ok(&scratch[..n]);
+    }
+    let compressor = compression::compressor(scratch[n])
+        .ok_or_else(|| Status::Corruption(format!("bad block type at offset {}", handle.offset)))?;
+    *scratch = compressor
+        .decompress(&scratch[..n], dictionary)
+        .ok_or_else(|| {
+            Status::Corruption(format!(
+                "corrupted compressed block contents at offset {}",
+                handle.offset
+            ))
+        })?;
+    Ok(scratch)
+}
 
-    match scratch[handle.size as usize] {
-        // no compression
-        0 => Ok(&scratch[..handle.size as usize]),
-        // TODO: snappy
-        1 => todo!("snappy compression"),
-        _ => Err(Status::Corruption),
+// with_file_number adds the number of the table file to the message of a corruption
+fn with_file_number(e: Status, file_number: u64) -> Status {
+    match e {
+        Status::Corruption(msg) => Status::Corruption(format!("table {:06}: {}", file_number, msg)),
+        e => e,
+    }
+}
+
+// read_table_block reads a block of the table file numbered file_number.
+// a corruption is reported with the file number and the offset of the block.
+fn read_table_block(
+    file: &File,
+    file_number: u64,
+    handle: &BlockHandle,
+    verify_checksums: bool,
+    dictionary: Option<&[u8]>,
+) -> Result<Block> {
+    let mut scratch = Vec::new();
+    read_block_with_dictionary(file, handle, &mut scratch, verify_checksums, dictionary)
+        .and_then(|block| {
+            Block::new(block).ok_or_else(|| {
+                Status::Corruption(format!("bad block contents at offset {}", handle.offset))
+            })
+        })
+        .map_err(|e| with_file_number(e, file_number))
+}
+
+// Entry is an entry of a table, which is the internal key and the value
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+// Table is an open table file.
+// the index block is kept in memory, so that a lookup reads only the data block it needs.
+#[derive(Debug)]
+pub struct Table {
+    file: File,
+    file_number: u64,
+    index_block: Block,
+    // the dictionary which the data blocks are compressed with
+    dictionary: Option<Vec<u8>>,
+    // the filter of the keys, if the table has one for the filter policy in options
+    filter: Option<FilterBlockReader>,
+    statistics: Arc<Statistics>,
+    block_cache: Option<Arc<BlockCache>>,
+    // the key of the blocks of this table in block_cache. a table opened again gets a new one.
+    cache_id: u64,
+}
+
+impl Table {
+    // open reads the footer, the meta blocks and the index block of the table file numbered file_number.
+    // the checksums of them are always verified, because they are read only once.
+    pub fn open(options: &Options, file: File, file_number: u64) -> Result<Table> {
+        let file_size = file.metadata()?.len();
+        if file_size < FOOTER_ENCODED_LENGTH as u64 {
+            return Err(Status::Corruption(format!(
+                "table {:06}: file is too short to be a table",
+                file_number
+            )));
+        }
+        let mut buf = [0; FOOTER_ENCODED_LENGTH];
+        file.read_exact_at(&mut buf, file_size - FOOTER_ENCODED_LENGTH as u64)?;
+        let footer = Footer::decode_from(&buf).map_err(|e| with_file_number(e, file_number))?;
+
+        let metaindex_block =
+            read_table_block(&file, file_number, &footer.metaindex_handle, true, None)?;
+        let filter_key = options
+            .filter_policy
+            .as_ref()
+            .map(|policy| format!("{}{}", FILTER_KEY_PREFIX, policy.name()));
+        let mut dictionary = None;
+        let mut filter = None;
+        for (key, handle_encoding) in metaindex_block.iter() {
+            let read_meta_block = || {
+                let (handle, _) = BlockHandle::decode_from(handle_encoding)?;
+                let mut scratch = Vec::new();
+                read_block(&file, &handle, &mut scratch, true).map(|contents| contents.to_vec())
+            };
+            if key == ZSTD_DICTIONARY_KEY.as_bytes() {
+                let contents = read_meta_block().map_err(|e| with_file_number(e, file_number))?;
+                dictionary = Some(contents);
+            } else if filter_key.as_ref().is_some_and(|k| key == k.as_bytes()) {
+                let contents = read_meta_block().map_err(|e| with_file_number(e, file_number))?;
+                let policy = options.filter_policy.clone().unwrap();
+                // a broken filter is ignored, since it only saves reads
+                filter = FilterBlockReader::new(policy, contents);
+            }
+        }
+
+        let index_block = read_table_block(&file, file_number, &footer.index_handle, true, None)?;
+        Ok(Table {
+            file,
+            file_number,
+            index_block,
+            dictionary,
+            filter,
+            statistics: options.statistics.clone(),
+            block_cache: options.block_cache.clone(),
+            cache_id: options
+                .block_cache
+                .as_ref()
+                .map_or(0, |cache| cache.new_id()),
+        })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn has_dictionary(&self) -> bool {
+        self.dictionary.is_some()
+    }
+
+    // read_data_block returns the data block from the block cache, or reads it from the file.
+    // a block read from the file is added to the cache if fill_cache is true.
+    fn read_data_block(
+        &self,
+        handle_encoding: &[u8],
+        verify_checksums: bool,
+        fill_cache: bool,
+    ) -> Result<Block> {
+        let (handle, _) = BlockHandle::decode_from(handle_encoding)?;
+        if let Some(block) = self
+            .block_cache
+            .as_ref()
+            .and_then(|cache| cache.get(self.cache_id, handle.offset))
+        {
+            return Ok(block);
+        }
+
+        let block = read_table_block(
+            &self.file,
+            self.file_number,
+            &handle,
+            verify_checksums,
+            self.dictionary.as_deref(),
+        )?;
+        if let Some(cache) = self.block_cache.as_ref().filter(|_| fill_cache) {
+            cache.insert(self.cache_id, handle.offset, block.clone());
+        }
+        Ok(block)
+    }
+
+    // get returns the first entry whose internal key is not less than ikey.
+    // only the data block which may contain ikey is read.
+    pub fn get(&self, options: &ReadOptions, ikey: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.get_with_last_block(options, ikey, &mut None)
+    }
+
+    // multi_get looks up every key in ikeys as get does, and returns the results in the order of ikeys.
+    // the keys are looked up in sorted order, so that a data block which has several of them is read once.
+    pub fn multi_get(&self, options: &ReadOptions, ikeys: &[&[u8]]) -> Vec<Result<Option<Entry>>> {
+        let mut order = (0..ikeys.len()).collect::<Vec<_>>();
+        order.sort_by(|&a, &b| compare_internal_key(ikeys[a], ikeys[b]));
+
+        let mut results = (0..ikeys.len()).map(|_| Ok(None)).collect::<Vec<_>>();
+        let mut last_block = None;
+        for i in order {
+            results[i] = self.get_with_last_block(options, ikeys[i], &mut last_block);
+        }
+        results
+    }
+
+    // get_with_last_block is get which reuses last_block if ikey is in the same data block.
+    // last_block keeps the handle encoding and the contents of the last data block read.
+    fn get_with_last_block(
+        &self,
+        options: &ReadOptions,
+        ikey: &[u8],
+        last_block: &mut Option<(Vec<u8>, Block)>,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        // every index key is the last key of its data block
+        let mut index_iter = self.index_block.new_iterator(compare_internal_key);
+        index_iter.seek(ikey);
+        if !index_iter.valid() {
+            index_iter.status()?;
+            return Ok(None);
+        }
+
+        // the filter is consulted with the user key, so that it matches any sequence of the key
+        if let Some(filter) = &self.filter {
+            let (handle, _) = BlockHandle::decode_from(index_iter.value())
+                .map_err(|e| with_file_number(e, self.file_number))?;
+            let may_match = filter.key_may_match(handle.offset, &ikey[..ikey.len() - 8]);
+            self.statistics.record_filter(may_match);
+            if !may_match {
+                return Ok(None);
+            }
+        }
+
+        let block = match last_block {
+            Some((handle_encoding, block)) if handle_encoding == index_iter.value() => block,
+            _ => {
+                let block = self.read_data_block(
+                    index_iter.value(),
+                    options.verify_checksums,
+                    options.fill_cache,
+                )?;
+                &last_block.insert((index_iter.value().to_vec(), block)).1
+            }
+        };
+        let mut block_iter = block.new_iterator(compare_internal_key);
+        block_iter.seek(ikey);
+        if !block_iter.valid() {
+            block_iter.status()?;
+            return Ok(None);
+        }
+        Ok(Some((
+            block_iter.key().to_vec(),
+            block_iter.value().to_vec(),
+        )))
+    }
+
+    // iter returns an iterator over all entries of the table
+    pub fn iter(self: &Arc<Self>, options: &ReadOptions) -> TableIterator {
+        TableIterator {
+            table: self.clone(),
+            verify_checksums: options.verify_checksums,
+            fill_cache: options.fill_cache,
+            index_iter: self.index_block.new_iterator(compare_internal_key),
+            data_iter: None,
+            data_block_handle: Vec::new(),
+            status: None,
+        }
+    }
+}
+
+// TableIterator iterates over the entries of a table in both directions.
+// a data block is read when the iteration enters it.
+pub struct TableIterator {
+    table: Arc<Table>,
+    verify_checksums: bool,
+    fill_cache: bool,
+    index_iter: BlockIterator,
+    // iterator over the data block which index_iter points to
+    data_iter: Option<BlockIterator>,
+    // the handle of the data block in data_iter, so that the block isn't read again
+    data_block_handle: Vec<u8>,
+
+    status: Option<Status>,
+}
+
+impl TableIterator {
+    // init_data_block makes data_iter iterate over the data block which index_iter points to.
+    // on an error, the iterator becomes invalid.
+    fn init_data_block(&mut self) {
+        if !self.index_iter.valid() {
+            self.data_iter = None;
+            return;
+        }
+        let handle = self.index_iter.value();
+        if self.data_iter.is_some() && handle == self.data_block_handle.as_slice() {
+            return;
+        }
+
+        match self
+            .table
+            .read_data_block(handle, self.verify_checksums, self.fill_cache)
+        {
+            Ok(block) => {
+                self.data_block_handle = handle.to_vec();
+                self.data_iter = Some(block.new_iterator(compare_internal_key));
+            }
+            Err(e) => {
+                self.status = Some(e);
+                self.data_iter = None;
+            }
+        }
+    }
+
+    // data_block_exhausted returns true if the current data block has no more entries in the direction,
+    // and the next block should be tried
+    fn data_block_exhausted(&mut self) -> bool {
+        if self.status.is_some() {
+            return false;
+        }
+        if let Some(data_iter) = &self.data_iter {
+            if data_iter.valid() {
+                return false;
+            }
+            if let Err(e) = data_iter.status() {
+                self.status = Some(e);
+                return false;
+            }
+        }
+        self.index_iter.valid()
+    }
+
+    fn skip_empty_data_blocks_forward(&mut self) {
+        while self.data_block_exhausted() {
+            self.index_iter.next();
+            self.init_data_block();
+            if let Some(data_iter) = &mut self.data_iter {
+                data_iter.seek_to_first();
+            }
+        }
+    }
+
+    fn skip_empty_data_blocks_backward(&mut self) {
+        while self.data_block_exhausted() {
+            self.index_iter.prev();
+            self.init_data_block();
+            if let Some(data_iter) = &mut self.data_iter {
+                data_iter.seek_to_last();
+            }
+        }
+    }
+}
+
+impl InternalIterator for TableIterator {
+    fn valid(&self) -> bool {
+        self.status.is_none() && self.data_iter.as_ref().is_some_and(|iter| iter.valid())
+    }
+
+    fn seek_to_first(&mut self) {
+        self.index_iter.seek_to_first();
+        self.init_data_block();
+        if let Some(data_iter) = &mut self.data_iter {
+            data_iter.seek_to_first();
+        }
+        self.skip_empty_data_blocks_forward();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.index_iter.seek_to_last();
+        self.init_data_block();
+        if let Some(data_iter) = &mut self.data_iter {
+            data_iter.seek_to_last();
+        }
+        self.skip_empty_data_blocks_backward();
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        self.index_iter.seek(target);
+        self.init_data_block();
+        if let Some(data_iter) = &mut self.data_iter {
+            data_iter.seek(target);
+        }
+        self.skip_empty_data_blocks_forward();
+    }
+
+    fn next(&mut self) {
+        assert!(self.valid());
+        self.data_iter.as_mut().unwrap().next();
+        self.skip_empty_data_blocks_forward();
+    }
+
+    fn prev(&mut self) {
+        assert!(self.valid());
+        self.data_iter.as_mut().unwrap().prev();
+        self.skip_empty_data_blocks_backward();
+    }
+
+    fn key(&self) -> &[u8] {
+        assert!(self.valid());
+        self.data_iter.as_ref().unwrap().key()
+    }
+
+    fn value(&self) -> &[u8] {
+        assert!(self.valid());
+        self.data_iter.as_ref().unwrap().value()
+    }
+
+    fn status(&self) -> Result<()> {
+        self.index_iter.status()?;
+        if let Some(e) = &self.status {
+            return Err(e.clone());
+        }
+        match &self.data_iter {
+            Some(data_iter) => data_iter.status(),
+            None => Ok(()),
+        }
     }
 }
 
@@ -216,9 +794,12 @@ pub struct Footer {
 
 impl Footer {
     pub fn decode_from(src: &[u8]) -> Result<Footer> {
-        let footer = FooterDecoder::read_from(src).ok_or(Status::Corruption)?;
+        let footer = FooterDecoder::read_from(src)
+            .ok_or_else(|| Status::Corruption("bad footer".to_string()))?;
         if footer.magic != TABLE_MAGIC_NUMBER {
-            return Err(Status::Corruption);
+            return Err(Status::Corruption(
+                "not an sstable (bad magic number)".to_string(),
+            ));
         }
 
         let (metaindex_handle, offset) = BlockHandle::decode_from(&footer.rep[..])?;
@@ -229,4 +810,969 @@ impl Footer {
             index_handle,
         })
     }
+
+    pub fn encode_to(&self, dst: &mut Vec<u8>) {
+        let original_size = dst.len();
+        self.metaindex_handle.encode_to(dst);
+        self.index_handle.encode_to(dst);
+        // padding
+        dst.resize(original_size + 2 * BLOCK_HANDLE_MAX_ENCODED_LENGTH, 0);
+        put_fixed64(dst, TABLE_MAGIC_NUMBER);
+        assert_eq!(dst.len(), original_size + FOOTER_ENCODED_LENGTH);
+    }
+}
+
+// TableBuilder writes a sorted sequence of key/value pairs into a table file.
+//
+// table format:
+//     [data block 1]
+//     ...
+//     [data block N]
+//     [metaindex block]
+//     [index block]
+//     [footer]
+// every block is followed by a trailer which consists of a compression type(1-byte) and
+// a masked crc32c(4-bytes) of the block contents and the type.
+pub struct TableBuilder {
+    options: Options,
+    compression: CompressionType,
+    file: File,
+    offset: u64,
+    num_entries: usize,
+
+    data_block: BlockBuilder,
+    index_block: BlockBuilder,
+    last_key: Vec<u8>,
+
+    // while a zstd dictionary is being trained, the data blocks are kept uncompressed,
+    // and they are written once the dictionary is ready.
+    training: bool,
+    buffered_blocks: Vec<BufferedBlock>,
+    buffered_size: usize,
+    dictionary: Option<Vec<u8>>,
+
+    filter_block: Option<FilterBlockBuilder>,
+    // the user keys of the data block being built, which are added to the filter when it's written
+    filter_keys: Vec<Vec<u8>>,
+}
+
+// BufferedBlock is a finished data block which is not written yet
+struct BufferedBlock {
+    contents: Vec<u8>,
+    last_key: Vec<u8>,
+    filter_keys: Vec<Vec<u8>>,
+}
+
+impl TableBuilder {
+    pub fn new(options: &Options, file: File) -> Self {
+        Self::with_compression(options, options.compression, file)
+    }
+
+    // with_compression returns a builder which compresses the blocks with compression
+    // instead of the one in options
+    pub fn with_compression(options: &Options, compression: CompressionType, file: File) -> Self {
+        TableBuilder {
+            options: options.clone(),
+            compression,
+            file,
+            offset: 0,
+            num_entries: 0,
+
+            data_block: BlockBuilder::new(options.block_restart_interval),
+            index_block: BlockBuilder::new(1),
+            last_key: Vec::new(),
+
+            training: false,
+            buffered_blocks: Vec::new(),
+            buffered_size: 0,
+            dictionary: None,
+
+            filter_block: options.filter_policy.clone().map(FilterBlockBuilder::new),
+            filter_keys: Vec::new(),
+        }
+    }
+
+    // with_zstd_dictionary makes the builder train a zstd dictionary from the data blocks,
+    // if they are compressed with zstd and options.zstd_max_dict_bytes is not zero.
+    // compactions use it, since the training costs more than a memtable flush is worth.
+    pub fn with_zstd_dictionary(mut self) -> Self {
+        self.training =
+            self.compression == CompressionType::Zstd && self.options.zstd_max_dict_bytes > 0;
+        self
+    }
+
+    // add appends a key/value pair to the table.
+    // keys must be added in ascending order.
+    pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.num_entries += 1;
+        self.data_block.add(key, value);
+        if self.filter_block.is_some() {
+            // the filter is built on the user keys
+            self.filter_keys.push(key[..key.len() - 8].to_vec());
+        }
+
+        if self.data_block.current_size_estimate() >= self.options.block_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // flush writes the buffered data block and its index entry.
+    // the last key of the block is used as the index key, so that
+    // the block for a key is the first one whose index key is not less than it.
+    fn flush(&mut self) -> Result<()> {
+        if self.data_block.is_empty() {
+            return Ok(());
+        }
+        let data_block = std::mem::replace(
+            &mut self.data_block,
+            BlockBuilder::new(self.options.block_restart_interval),
+        );
+        let contents = data_block.finish();
+        let filter_keys = std::mem::take(&mut self.filter_keys);
+
+        if self.training {
+            self.buffered_size += contents.len();
+            self.buffered_blocks.push(BufferedBlock {
+                contents,
+                last_key: self.last_key.clone(),
+                filter_keys,
+            });
+            if self.buffered_size
+                >= self.options.zstd_max_dict_bytes * ZSTD_TRAINING_BYTES_PER_DICTIONARY_BYTE
+            {
+                self.train_dictionary()?;
+            }
+            return Ok(());
+        }
+        let last_key = std::mem::take(&mut self.last_key);
+        self.write_data_block(&contents, &last_key, &filter_keys)?;
+        self.last_key = last_key;
+        Ok(())
+    }
+
+    // train_dictionary trains the zstd dictionary from the buffered data blocks, and writes them.
+    // if the dictionary can't be trained, the blocks are compressed without it.
+    fn train_dictionary(&mut self) -> Result<()> {
+        self.training = false;
+        let blocks = std::mem::take(&mut self.buffered_blocks);
+        let samples = blocks
+            .iter()
+            .map(|block| block.contents.clone())
+            .collect::<Vec<_>>();
+        self.dictionary =
+            compression::train_zstd_dictionary(&samples, self.options.zstd_max_dict_bytes);
+        self.buffered_size = 0;
+
+        for block in blocks {
+            self.write_data_block(&block.contents, &block.last_key, &block.filter_keys)?;
+        }
+        Ok(())
+    }
+
+    // write_data_block writes a data block and its index entry, and adds the user keys of it
+    // to the filter for the offset where the block is written.
+    fn write_data_block(
+        &mut self,
+        contents: &[u8],
+        last_key: &[u8],
+        filter_keys: &[Vec<u8>],
+    ) -> Result<()> {
+        if let Some(filter_block) = &mut self.filter_block {
+            filter_block.start_block(self.offset);
+            for key in filter_keys {
+                filter_block.add_key(key);
+            }
+        }
+
+        let dictionary = self.dictionary.take();
+        let handle = self.write_block(contents, dictionary.as_deref());
+        self.dictionary = dictionary;
+
+        let mut handle_encoding = Vec::new();
+        handle?.encode_to(&mut handle_encoding);
+        self.index_block.add(last_key, &handle_encoding);
+        Ok(())
+    }
+
+    // write_block compresses the block, and writes it.
+    // the block is stored uncompressed if the compression saves less than 12.5%.
+    fn write_block(&mut self, contents: &[u8], dictionary: Option<&[u8]>) -> Result<BlockHandle> {
+        let compressed = self
+            .compression
+            .compressor()
+            .and_then(|compressor| compressor.compress(contents, dictionary))
+            .filter(|compressed| compressed.len() < contents.len() - contents.len() / 8);
+        match compressed {
+            Some(compressed) => self.write_raw_block(&compressed, self.compression),
+            None => self.write_raw_block(contents, CompressionType::No),
+        }
+    }
+
+    fn write_raw_block(&mut self, contents: &[u8], ty: CompressionType) -> Result<BlockHandle> {
+        let handle = BlockHandle {
+            offset: self.offset,
+            size: contents.len() as u64,
+        };
+        self.file.write_all(contents)?;
+
+        let mut trailer = Vec::with_capacity(BLOCK_TRAILER_SIZE);
+        trailer.push(ty.block_type());
+        let crc = crc32c::crc32c_append(crc32c::crc32c(contents), &trailer);
+        put_fixed32(&mut trailer, mask_crc(crc));
+        self.file.write_all(&trailer)?;
+
+        self.offset += (contents.len() + BLOCK_TRAILER_SIZE) as u64;
+        Ok(handle)
+    }
+
+    pub fn num_entries(&self) -> usize {
+        self.num_entries
+    }
+
+    // file_size returns the size of the file generated so far.
+    // the data blocks kept for the dictionary training are counted uncompressed.
+    pub fn file_size(&self) -> u64 {
+        self.offset + self.buffered_size as u64
+    }
+
+    // finish writes the remaining blocks and the footer, and syncs the file.
+    // it returns the size of the table file.
+    pub fn finish(mut self) -> Result<u64> {
+        self.flush()?;
+        if self.training {
+            self.train_dictionary()?;
+        }
+
+        // the meta blocks are stored uncompressed.
+        // the dictionary is needed to decompress the data blocks, and filters hardly compress.
+        let mut metaindex_block = BlockBuilder::new(self.options.block_restart_interval);
+        if let Some(filter_block) = self.filter_block.take() {
+            let handle = self.write_raw_block(&filter_block.finish(), CompressionType::No)?;
+            let mut handle_encoding = Vec::new();
+            handle.encode_to(&mut handle_encoding);
+            let policy = self.options.filter_policy.as_ref().unwrap();
+            let key = format!("{}{}", FILTER_KEY_PREFIX, policy.name());
+            metaindex_block.add(key.as_bytes(), &handle_encoding);
+        }
+        if let Some(dictionary) = self.dictionary.take() {
+            let handle = self.write_raw_block(&dictionary, CompressionType::No)?;
+            let mut handle_encoding = Vec::new();
+            handle.encode_to(&mut handle_encoding);
+            metaindex_block.add(ZSTD_DICTIONARY_KEY.as_bytes(), &handle_encoding);
+        }
+        let metaindex_handle = self.write_block(&metaindex_block.finish(), None)?;
+
+        let index_block = std::mem::replace(&mut self.index_block, BlockBuilder::new(1));
+        let index_handle = self.write_block(&index_block.finish(), None)?;
+
+        let footer = Footer {
+            metaindex_handle,
+            index_handle,
+        };
+        let mut footer_encoding = Vec::with_capacity(FOOTER_ENCODED_LENGTH);
+        footer.encode_to(&mut footer_encoding);
+        self.file.write_all(&footer_encoding)?;
+        self.offset += footer_encoding.len() as u64;
+
+        self.file.sync_all()?;
+        Ok(self.offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Compressor;
+    use crate::write_batch::ValueTypeCode;
+    use crate::BloomFilterPolicy;
+    use crate::InternalKey;
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn test_table_builder() {
+        let path = "/tmp/test_table_builder.ldb";
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+
+        let options = Options {
+            block_size: 256,
+            ..Options::default()
+        };
+        let entries = (0..1000)
+            .map(|i| (format!("key{:05}", i), format!("value{}", i)))
+            .collect::<Vec<_>>();
+
+        let mut builder = TableBuilder::new(&options, file);
+        for (key, value) in &entries {
+            builder.add(key.as_bytes(), value.as_bytes()).unwrap();
+        }
+        assert_eq!(builder.num_entries(), entries.len());
+        let file_size = builder.finish().unwrap();
+
+        let file = OpenOptions::new().read(true).open(path).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), file_size);
+
+        let mut buf = [0; FOOTER_ENCODED_LENGTH];
+        file.read_exact_at(&mut buf, file_size - FOOTER_ENCODED_LENGTH as u64)
+            .unwrap();
+        let footer = Footer::decode_from(&buf).unwrap();
+
+        let mut scratch = Vec::new();
+        let metaindex_block =
+            read_block(&file, &footer.metaindex_handle, &mut scratch, true).unwrap();
+        assert_eq!(Block::new(metaindex_block).unwrap().iter().count(), 0);
+
+        let mut index_scratch = Vec::new();
+        let index_block =
+            read_block(&file, &footer.index_handle, &mut index_scratch, true).unwrap();
+
+        let mut num_blocks = 0;
+        let mut actual = Vec::new();
+        for (last_key, handle_encoding) in Block::new(index_block).unwrap().iter() {
+            let (handle, _) = BlockHandle::decode_from(handle_encoding).unwrap();
+            let block = read_block(&file, &handle, &mut scratch, true).unwrap();
+            let kvs = Block::new(block)
+                .unwrap()
+                .iter()
+                .map(|(k, v)| {
+                    (
+                        String::from_utf8(k).unwrap(),
+                        String::from_utf8(v.to_vec()).unwrap(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(kvs.last().unwrap().0.as_bytes(), last_key);
+
+            num_blocks += 1;
+            actual.extend(kvs);
+        }
+        assert!(num_blocks > 1);
+        assert_eq!(actual, entries);
+    }
+
+    #[test]
+    fn test_block_iterator() {
+        // keys are sorted in descending order, so the block is read with a reversed comparator
+        fn reverse(a: &[u8], b: &[u8]) -> Ordering {
+            b.cmp(a)
+        }
+        let keys = (0..100)
+            .rev()
+            .map(|i| format!("key{:03}", i * 2))
+            .collect::<Vec<_>>();
+        let mut builder = BlockBuilder::new(3);
+        for key in &keys {
+            builder.add(key.as_bytes(), format!("value-{}", key).as_bytes());
+        }
+        let block = Block::new(builder.finish()).unwrap();
+        let mut iter = block.new_iterator(reverse);
+        assert!(!iter.valid());
+
+        iter.seek_to_first();
+        for key in &keys {
+            assert_eq!(iter.key(), key.as_bytes());
+            assert_eq!(iter.value(), format!("value-{}", key).as_bytes());
+            iter.next();
+        }
+        assert!(!iter.valid());
+
+        iter.seek_to_last();
+        for key in keys.iter().rev() {
+            assert_eq!(iter.key(), key.as_bytes());
+            iter.prev();
+        }
+        assert!(!iter.valid());
+
+        for i in 0..=200 {
+            let target = format!("key{:03}", i);
+            iter.seek(target.as_bytes());
+            // the first key which is not greater than target
+            match keys.iter().find(|key| key.as_str() <= target.as_str()) {
+                Some(key) => assert_eq!(iter.key(), key.as_bytes()),
+                None => assert!(!iter.valid()),
+            }
+        }
+        iter.seek(b"key999");
+        assert_eq!(iter.key(), b"key198");
+        iter.prev();
+        assert!(!iter.valid());
+        iter.status().unwrap();
+    }
+
+    #[test]
+    fn test_block_iterator_empty() {
+        let block = Block::new(BlockBuilder::new(16).finish()).unwrap();
+        let mut iter = block.new_iterator(|a, b| a.cmp(b));
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        iter.seek_to_last();
+        assert!(!iter.valid());
+        iter.seek(b"key");
+        assert!(!iter.valid());
+        iter.status().unwrap();
+
+        assert!(Block::new(vec![0, 0]).is_none());
+    }
+
+    #[test]
+    fn test_table_get() {
+        let path = "/tmp/test_table_get.ldb";
+        let file = File::create(path).unwrap();
+
+        let options = Options {
+            block_size: 256,
+            ..Options::default()
+        };
+        // every key has two versions
+        let mut builder = TableBuilder::new(&options, file);
+        for i in 0..500 {
+            let user_key = format!("key{:05}", i * 2);
+            for sequence in [2, 1] {
+                let key = InternalKey::new(user_key.as_bytes(), sequence, ValueTypeCode::Value);
+                builder
+                    .add(
+                        key.get_contents(),
+                        format!("{}@{}", user_key, sequence).as_bytes(),
+                    )
+                    .unwrap();
+            }
+        }
+        builder.finish().unwrap();
+
+        let table = Table::open(&Options::default(), File::open(path).unwrap(), 1).unwrap();
+        let get = |user_key: &str, sequence| {
+            let key = InternalKey::new(user_key.as_bytes(), sequence, ValueTypeCode::Value);
+            table
+                .get(&ReadOptions::default(), key.get_contents())
+                .unwrap()
+                .map(|(key, value)| {
+                    let key = InternalKey::decode_from(&key);
+                    (
+                        String::from_utf8(key.user_key().to_vec()).unwrap(),
+                        key.sequence(),
+                        String::from_utf8(value).unwrap(),
+                    )
+                })
+        };
+        let entry = |user_key: &str, sequence| {
+            Some((
+                user_key.to_string(),
+                sequence,
+                format!("{}@{}", user_key, sequence),
+            ))
+        };
+        assert_eq!(get("key00000", 2), entry("key00000", 2));
+        assert_eq!(get("key00000", 1), entry("key00000", 1));
+        assert_eq!(get("key00500", 10), entry("key00500", 2));
+        assert_eq!(get("key00500", 0), entry("key00502", 2));
+        assert_eq!(get("key00501", 10), entry("key00502", 2));
+        assert_eq!(get("key00998", 1), entry("key00998", 1));
+        assert_eq!(get("key00999", 10), None);
+        assert_eq!(get("a", 10), entry("key00000", 2));
+
+        assert!(Table::open(&Options::default(), File::open("/dev/null").unwrap(), 1).is_err());
+    }
+
+    #[test]
+    fn test_table_compression() {
+        // build_table builds a table of the entries with options, and returns
+        // the table size, the type bytes of the data blocks and whether the table has a dictionary
+        let build_table = |options: &Options, entries: &[(Vec<u8>, Vec<u8>)]| {
+            let path = "/tmp/test_table_compression.ldb";
+            let mut builder =
+                TableBuilder::new(options, File::create(path).unwrap()).with_zstd_dictionary();
+            for (key, value) in entries {
+                builder.add(key, value).unwrap();
+            }
+            let file_size = builder.finish().unwrap();
+
+            // the blocks are decompressed on read
+            let table =
+                Arc::new(Table::open(&Options::default(), File::open(path).unwrap(), 1).unwrap());
+            let options = ReadOptions {
+                verify_checksums: true,
+                ..ReadOptions::default()
+            };
+            let mut iter = table.iter(&options);
+            iter.seek_to_first();
+            for (key, value) in entries {
+                assert_eq!(iter.key(), key.as_slice());
+                assert_eq!(iter.value(), value.as_slice());
+                iter.next();
+            }
+            assert!(!iter.valid());
+            assert_eq!(iter.status(), Ok(()));
+
+            let types = table
+                .index_block
+                .iter()
+                .map(|(_, handle_encoding)| {
+                    let (handle, _) = BlockHandle::decode_from(handle_encoding).unwrap();
+                    let mut ty = [0];
+                    table
+                        .file
+                        .read_exact_at(&mut ty, handle.offset + handle.size)
+                        .unwrap();
+                    ty[0]
+                })
+                .collect::<Vec<_>>();
+            (file_size, types, table.dictionary.is_some())
+        };
+        let options = |compression| Options {
+            block_size: 256,
+            compression,
+            ..Options::default()
+        };
+
+        let compressible = (0..1000)
+            .map(|i| {
+                let key =
+                    InternalKey::new(format!("key{:05}", i).as_bytes(), 1, ValueTypeCode::Value);
+                (key.get_contents().to_vec(), b"value".repeat(10))
+            })
+            .collect::<Vec<_>>();
+        let (size, types, _) = build_table(&options(CompressionType::No), &compressible);
+        assert!(types
+            .iter()
+            .all(|&ty| ty == CompressionType::No.block_type()));
+        for compression in [
+            CompressionType::Snappy,
+            CompressionType::Zstd,
+            CompressionType::Lz4,
+        ] {
+            let (compressed_size, types, dictionary) =
+                build_table(&options(compression), &compressible);
+            assert!(types.iter().all(|&ty| ty == compression.block_type()));
+            assert!(compressed_size < size / 2);
+            assert!(!dictionary);
+        }
+
+        // blocks which don't compress well are stored as they are
+        let mut rnd = 301u32;
+        let incompressible = (0..1000)
+            .map(|i| {
+                let key =
+                    InternalKey::new(format!("key{:05}", i).as_bytes(), 1, ValueTypeCode::Value);
+                let value = (0..50)
+                    .map(|_| {
+                        rnd = rnd.wrapping_mul(1103515245).wrapping_add(12345);
+                        (rnd >> 16) as u8
+                    })
+                    .collect();
+                (key.get_contents().to_vec(), value)
+            })
+            .collect::<Vec<_>>();
+        let (_, types, _) = build_table(&options(CompressionType::Snappy), &incompressible);
+        assert!(types
+            .iter()
+            .all(|&ty| ty == CompressionType::No.block_type()));
+
+        // a registered codec compresses the blocks with its type byte, and they are read with it
+        struct CustomSnappy;
+        impl Compressor for CustomSnappy {
+            fn block_type(&self) -> u8 {
+                0x81
+            }
+            fn compress(&self, input: &[u8], dictionary: Option<&[u8]>) -> Option<Vec<u8>> {
+                let snappy = CompressionType::Snappy.compressor().unwrap();
+                snappy.compress(input, dictionary)
+            }
+            fn decompress(&self, input: &[u8], dictionary: Option<&[u8]>) -> Option<Vec<u8>> {
+                let snappy = CompressionType::Snappy.compressor().unwrap();
+                snappy.decompress(input, dictionary)
+            }
+        }
+        compression::register_compressor(Arc::new(CustomSnappy)).unwrap();
+        let (compressed_size, types, _) =
+            build_table(&options(CompressionType::Custom(0x81)), &compressible);
+        assert!(types.iter().all(|&ty| ty == 0x81));
+        assert!(compressed_size < size / 2);
+
+        // data blocks are compressed with a dictionary trained from them
+        let records = (0..1000)
+            .map(|i| {
+                let key =
+                    InternalKey::new(format!("key{:05}", i).as_bytes(), 1, ValueTypeCode::Value);
+                let value = format!(
+                    "{{\"id\":{},\"name\":\"user{}\",\"score\":{}}}",
+                    i,
+                    i * 7,
+                    i % 13
+                );
+                (key.get_contents().to_vec(), value.into_bytes())
+            })
+            .collect::<Vec<_>>();
+        let options = Options {
+            zstd_max_dict_bytes: 1024,
+            ..options(CompressionType::Zstd)
+        };
+        let (_, types, dictionary) = build_table(&options, &records);
+        assert!(types
+            .iter()
+            .all(|&ty| ty == CompressionType::Zstd.block_type()));
+        assert!(dictionary);
+    }
+
+    #[test]
+    fn test_table_filter() {
+        let path = "/tmp/test_table_filter.ldb";
+        let options = Options {
+            block_size: 256,
+            filter_policy: Some(Arc::new(BloomFilterPolicy::new(10))),
+            ..Options::default()
+        };
+        let mut builder = TableBuilder::new(&options, File::create(path).unwrap());
+        for i in 0..1000 {
+            let key = InternalKey::new(
+                format!("key{:05}", i * 2).as_bytes(),
+                1,
+                ValueTypeCode::Value,
+            );
+            builder.add(key.get_contents(), b"value").unwrap();
+        }
+        builder.finish().unwrap();
+
+        let table = Table::open(&options, File::open(path).unwrap(), 1).unwrap();
+        let metaindex = table_metaindex_keys(path);
+        assert_eq!(
+            metaindex,
+            vec![b"filter.leveldb.BuiltinBloomFilter2".to_vec()]
+        );
+
+        // the keys in the table are always found
+        for i in 0..1000 {
+            let key = InternalKey::new(
+                format!("key{:05}", i * 2).as_bytes(),
+                2,
+                ValueTypeCode::Value,
+            );
+            let (found, _) = table
+                .get(&ReadOptions::default(), key.get_contents())
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                &found[..found.len() - 8],
+                format!("key{:05}", i * 2).as_bytes()
+            );
+        }
+        assert_eq!(options.statistics.filter_hits(), 1000);
+        assert_eq!(options.statistics.filter_misses(), 0);
+
+        // most of the lookups of missing keys don't read data blocks
+        for i in 0..1000 {
+            let key = InternalKey::new(
+                format!("key{:05}", i * 2 + 1).as_bytes(),
+                2,
+                ValueTypeCode::Value,
+            );
+            if let Some((found, _)) = table
+                .get(&ReadOptions::default(), key.get_contents())
+                .unwrap()
+            {
+                assert_ne!(&found[..found.len() - 8], key.user_key());
+            }
+        }
+        assert!(options.statistics.filter_misses() > 950);
+        // the last missing key is after all the blocks, so no filter is consulted for it
+        assert_eq!(
+            options.statistics.filter_hits() + options.statistics.filter_misses(),
+            1999
+        );
+
+        // the filter is not used without the policy
+        let options = Options::default();
+        let table = Table::open(&options, File::open(path).unwrap(), 1).unwrap();
+        let key = InternalKey::new(b"key00001", 2, ValueTypeCode::Value);
+        assert!(table
+            .get(&ReadOptions::default(), key.get_contents())
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            options.statistics.filter_hits() + options.statistics.filter_misses(),
+            0
+        );
+
+        // the filters of the data blocks kept for the dictionary are built at their final offsets
+        let options = Options {
+            block_size: 256,
+            filter_policy: Some(Arc::new(BloomFilterPolicy::new(10))),
+            compression: CompressionType::Zstd,
+            zstd_max_dict_bytes: 256,
+            statistics: Arc::new(Statistics::default()),
+            ..Options::default()
+        };
+        let mut builder =
+            TableBuilder::new(&options, File::create(path).unwrap()).with_zstd_dictionary();
+        for i in 0..1000 {
+            let key = InternalKey::new(
+                format!("key{:05}", i * 2).as_bytes(),
+                1,
+                ValueTypeCode::Value,
+            );
+            let value = format!("{{\"id\":{},\"name\":\"user{}\"}}", i, i * 7);
+            builder.add(key.get_contents(), value.as_bytes()).unwrap();
+        }
+        builder.finish().unwrap();
+        let table = Table::open(&options, File::open(path).unwrap(), 1).unwrap();
+        assert!(table.has_dictionary());
+        for i in 0..2000 {
+            let key = InternalKey::new(format!("key{:05}", i).as_bytes(), 2, ValueTypeCode::Value);
+            let found = table
+                .get(&ReadOptions::default(), key.get_contents())
+                .unwrap()
+                .filter(|(found, _)| &found[..found.len() - 8] == key.user_key());
+            assert_eq!(found.is_some(), i % 2 == 0);
+        }
+        assert!(options.statistics.filter_hits() >= 1000);
+        assert!(options.statistics.filter_misses() > 950);
+    }
+
+    // table_metaindex_keys returns the keys in the metaindex block of the table file
+    fn table_metaindex_keys(path: &str) -> Vec<Vec<u8>> {
+        let file = File::open(path).unwrap();
+        let file_size = file.metadata().unwrap().len();
+        let mut buf = [0; FOOTER_ENCODED_LENGTH];
+        file.read_exact_at(&mut buf, file_size - FOOTER_ENCODED_LENGTH as u64)
+            .unwrap();
+        let footer = Footer::decode_from(&buf).unwrap();
+        let mut scratch = Vec::new();
+        let block = read_block(&file, &footer.metaindex_handle, &mut scratch, true).unwrap();
+        Block::new(block)
+            .unwrap()
+            .iter()
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    #[test]
+    fn test_block_cache() {
+        let path = "/tmp/test_block_cache.ldb";
+        let block_cache = Arc::new(BlockCache::new(1024 * 1024));
+        let options = Options {
+            block_size: 256,
+            block_cache: Some(block_cache.clone()),
+            ..Options::default()
+        };
+        let mut builder = TableBuilder::new(&options, File::create(path).unwrap());
+        for i in 0..1000 {
+            let key = InternalKey::new(format!("key{:05}", i).as_bytes(), 1, ValueTypeCode::Value);
+            builder.add(key.get_contents(), b"value").unwrap();
+        }
+        builder.finish().unwrap();
+        let table = Arc::new(Table::open(&options, File::open(path).unwrap(), 1).unwrap());
+        let num_blocks = table.index_block.iter().count() as u64;
+
+        let scan = |options: &ReadOptions| {
+            let mut iter = table.iter(options);
+            iter.seek_to_first();
+            while iter.valid() {
+                iter.next();
+            }
+            assert_eq!(iter.status(), Ok(()));
+        };
+
+        // a scan without filling the cache leaves it empty
+        let bulk = ReadOptions {
+            fill_cache: false,
+            ..ReadOptions::default()
+        };
+        scan(&bulk);
+        assert_eq!(block_cache.usage(), 0);
+        assert_eq!((block_cache.hits(), block_cache.misses()), (0, num_blocks));
+
+        // the first scan reads every block, and the second one finds them in the cache
+        scan(&ReadOptions::default());
+        assert_eq!(
+            (block_cache.hits(), block_cache.misses()),
+            (0, 2 * num_blocks)
+        );
+        assert!(block_cache.usage() > 0);
+        scan(&ReadOptions::default());
+        assert_eq!(
+            (block_cache.hits(), block_cache.misses()),
+            (num_blocks, 2 * num_blocks)
+        );
+
+        // a table opened again has its own blocks in the cache even with the same file number,
+        // since the number may be of a table of another db
+        let table = Table::open(&options, File::open(path).unwrap(), 1).unwrap();
+        let key = InternalKey::new(b"key00500", 1, ValueTypeCode::Value);
+        assert!(table
+            .get(&ReadOptions::default(), key.get_contents())
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            (block_cache.hits(), block_cache.misses()),
+            (num_blocks, 2 * num_blocks + 1)
+        );
+    }
+
+    #[test]
+    fn test_table_multi_get() {
+        let path = "/tmp/test_table_multi_get.ldb";
+        // the misses of the cache count the data blocks read from the file
+        let block_cache = Arc::new(BlockCache::new(1024 * 1024));
+        let options = Options {
+            block_size: 256,
+            block_cache: Some(block_cache.clone()),
+            ..Options::default()
+        };
+        let mut builder = TableBuilder::new(&options, File::create(path).unwrap());
+        for i in 0..1000 {
+            let key = InternalKey::new(format!("key{:05}", i).as_bytes(), 1, ValueTypeCode::Value);
+            builder
+                .add(key.get_contents(), format!("value{}", i).as_bytes())
+                .unwrap();
+        }
+        builder.finish().unwrap();
+        let table = Table::open(&options, File::open(path).unwrap(), 1).unwrap();
+
+        // adjacent keys in any order, a key past the end, and a key in another block
+        let keys = ["key00501", "key00500", "key99999", "key00502", "key00100"]
+            .map(|k| InternalKey::new(k.as_bytes(), 1, ValueTypeCode::Value));
+        let ikeys = keys.iter().map(|k| k.get_contents()).collect::<Vec<_>>();
+        let bulk = ReadOptions {
+            fill_cache: false,
+            ..ReadOptions::default()
+        };
+        let results = table.multi_get(&bulk, &ikeys);
+
+        // every data block which has some of the keys is read once
+        let blocks = ikeys
+            .iter()
+            .filter_map(|ikey| {
+                let mut index_iter = table.index_block.new_iterator(compare_internal_key);
+                index_iter.seek(ikey);
+                index_iter.valid().then(|| index_iter.value().to_vec())
+            })
+            .collect::<std::collections::HashSet<_>>();
+        assert!(blocks.len() < 4);
+        assert_eq!(block_cache.misses(), blocks.len() as u64);
+
+        for (i, result) in results.into_iter().enumerate() {
+            assert_eq!(result, table.get(&bulk, ikeys[i]));
+        }
+        assert_eq!(
+            table.get(&bulk, ikeys[1]).unwrap().unwrap().1,
+            b"value500".to_vec()
+        );
+        assert_eq!(table.get(&bulk, ikeys[2]), Ok(None));
+    }
+
+    #[test]
+    fn test_verify_checksums() {
+        let path = "/tmp/test_verify_checksums.ldb";
+        let file = File::create(path).unwrap();
+
+        let options = Options {
+            block_size: 256,
+            compression: CompressionType::No,
+            ..Options::default()
+        };
+        let mut builder = TableBuilder::new(&options, file);
+        for i in 0..100 {
+            let key = InternalKey::new(format!("key{:03}", i).as_bytes(), 1, ValueTypeCode::Value);
+            builder
+                .add(key.get_contents(), format!("value{:03}", i).as_bytes())
+                .unwrap();
+        }
+        builder.finish().unwrap();
+
+        // flip a byte of a value in the first data block
+        let mut contents = std::fs::read(path).unwrap();
+        let position = contents.windows(8).position(|w| w == b"value000").unwrap();
+        contents[position + 7] = b'X';
+        std::fs::write(path, contents).unwrap();
+
+        let table = Table::open(&Options::default(), File::open(path).unwrap(), 7).unwrap();
+        let key = InternalKey::new(b"key000", 1, ValueTypeCode::Value);
+
+        // the corruption is only noticed if the checksums are verified.
+        // the corrupted block is not cached, so that the verified reads below read it again.
+        let no_verify = ReadOptions {
+            fill_cache: false,
+            ..ReadOptions::default()
+        };
+        let (_, value) = table.get(&no_verify, key.get_contents()).unwrap().unwrap();
+        assert_eq!(value, b"value00X");
+
+        let options = ReadOptions {
+            verify_checksums: true,
+            ..ReadOptions::default()
+        };
+        assert_eq!(
+            table.get(&options, key.get_contents()),
+            Err(Status::Corruption(
+                "table 000007: block checksum mismatch at offset 0".to_string()
+            ))
+        );
+
+        let mut iter = Arc::new(table).iter(&options);
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        assert!(matches!(iter.status(), Err(Status::Corruption(_))));
+    }
+
+    #[test]
+    fn test_table_iterator() {
+        let path = "/tmp/test_table_iterator.ldb";
+        let file = File::create(path).unwrap();
+
+        let options = Options {
+            block_size: 256,
+            ..Options::default()
+        };
+        let keys = (0..500)
+            .map(|i| {
+                InternalKey::new(
+                    format!("key{:05}", i * 2).as_bytes(),
+                    1,
+                    ValueTypeCode::Value,
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut builder = TableBuilder::new(&options, file);
+        for key in &keys {
+            builder.add(key.get_contents(), key.user_key()).unwrap();
+        }
+        builder.finish().unwrap();
+
+        let table =
+            Arc::new(Table::open(&Options::default(), File::open(path).unwrap(), 1).unwrap());
+        let mut iter = table.iter(&ReadOptions::default());
+        assert!(!iter.valid());
+
+        iter.seek_to_first();
+        for key in &keys {
+            assert_eq!(iter.key(), key.get_contents());
+            assert_eq!(iter.value(), key.user_key());
+            iter.next();
+        }
+        assert!(!iter.valid());
+
+        iter.seek_to_last();
+        for key in keys.iter().rev() {
+            assert_eq!(iter.key(), key.get_contents());
+            iter.prev();
+        }
+        assert!(!iter.valid());
+
+        for i in 0..1000usize {
+            let target =
+                InternalKey::new(format!("key{:05}", i).as_bytes(), 1, ValueTypeCode::Value);
+            iter.seek(target.get_contents());
+            match keys.get(i.div_ceil(2)) {
+                Some(key) => assert_eq!(iter.key(), key.get_contents()),
+                None => assert!(!iter.valid()),
+            }
+        }
+        iter.status().unwrap();
+    }
 }
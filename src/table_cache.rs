@@ -0,0 +1,153 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::cache::ShardedLruCache;
+use crate::filename::table_file_name;
+use crate::table::{Entry, Table, TableIterator};
+use crate::{Options, ReadOptions, Result};
+
+// the number of open files which are used by things other than tables, such as the log and the manifest
+const NUM_NON_TABLE_CACHE_FILES: usize = 10;
+
+// TableCache keeps the tables of a db open, so that a read doesn't parse the footer, the index block
+// and the filter of the table every time.
+// the number of open tables is bounded by max_open_files, and the least recently used table is
+// closed when the bound is exceeded. a table in use by an iterator stays open until the iterator is dropped.
+#[derive(Debug)]
+pub struct TableCache {
+    db_path: PathBuf,
+    options: Options,
+    cache: ShardedLruCache<u64, Arc<Table>>,
+}
+
+impl TableCache {
+    pub fn new(db_path: &Path, options: &Options) -> Self {
+        let entries = options
+            .max_open_files
+            .saturating_sub(NUM_NON_TABLE_CACHE_FILES)
+            .max(1);
+        TableCache {
+            db_path: db_path.to_path_buf(),
+            options: options.clone(),
+            // every table is charged 1, so the capacity is the number of tables.
+            // the cache is not sharded, because shards would round the bound of open files up.
+            cache: ShardedLruCache::with_shard_bits(entries, 0),
+        }
+    }
+
+    // find_table returns the table of the file numbered file_number, opening it if it's not cached
+    pub fn find_table(&self, file_number: u64) -> Result<Arc<Table>> {
+        if let Some(table) = self.cache.get(&file_number) {
+            return Ok(table);
+        }
+
+        // an error is not cached, so that the table is opened again after the cause is fixed
+        let file = File::open(table_file_name(&self.db_path, file_number))?;
+        let table = Arc::new(Table::open(&self.options, file, file_number)?);
+        self.cache.insert(file_number, table.clone(), 1);
+        Ok(table)
+    }
+
+    // get returns the first entry whose internal key is not less than ikey in the table
+    pub fn get(
+        &self,
+        options: &ReadOptions,
+        file_number: u64,
+        ikey: &[u8],
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.find_table(file_number)?.get(options, ikey)
+    }
+
+    // multi_get looks up every key in ikeys as get does, reading each data block of the table at most once
+    pub fn multi_get(
+        &self,
+        options: &ReadOptions,
+        file_number: u64,
+        ikeys: &[&[u8]],
+    ) -> Result<Vec<Result<Option<Entry>>>> {
+        Ok(self.find_table(file_number)?.multi_get(options, ikeys))
+    }
+
+    // iter returns an iterator over all entries of the table.
+    // the iterator holds the table, so it keeps working after the table is evicted.
+    pub fn iter(&self, options: &ReadOptions, file_number: u64) -> Result<TableIterator> {
+        Ok(self.find_table(file_number)?.iter(options))
+    }
+
+    // evict closes the table of file_number. it's called when the file is deleted.
+    pub fn evict(&self, file_number: u64) {
+        self.cache.erase(&file_number);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iterator::InternalIterator;
+    use crate::table::TableBuilder;
+    use crate::write_batch::ValueTypeCode;
+    use crate::InternalKey;
+
+    #[test]
+    fn test_table_cache() {
+        let db_path = Path::new("/tmp/test_table_cache");
+        let _ = std::fs::remove_dir_all(db_path);
+        std::fs::create_dir_all(db_path).unwrap();
+
+        let options = Options {
+            max_open_files: NUM_NON_TABLE_CACHE_FILES + 32,
+            ..Options::default()
+        };
+        for number in 1..=100 {
+            let file = File::create(table_file_name(db_path, number)).unwrap();
+            let mut builder = TableBuilder::new(&options, file);
+            let key =
+                InternalKey::new(format!("key{}", number).as_bytes(), 1, ValueTypeCode::Value);
+            builder.add(key.get_contents(), b"value").unwrap();
+            builder.finish().unwrap();
+        }
+
+        let table_cache = TableCache::new(db_path, &options);
+        for number in 1..=100 {
+            let key =
+                InternalKey::new(format!("key{}", number).as_bytes(), 1, ValueTypeCode::Value);
+            let (found, value) = table_cache
+                .get(&ReadOptions::default(), number, key.get_contents())
+                .unwrap()
+                .unwrap();
+            assert_eq!(found, key.get_contents());
+            assert_eq!(value, b"value");
+        }
+        // no more than the bound of tables are kept open, and the most recently used ones are
+        assert_eq!(table_cache.cache.usage(), 32);
+        assert_eq!(table_cache.cache.misses(), 100);
+        assert!((69..=100).all(|number| table_cache.cache.get(&number).is_some()));
+
+        // the bound holds with a small max_open_files too
+        for (max_open_files, bound) in [(20, 10), (11, 1), (0, 1)] {
+            let options = Options {
+                max_open_files,
+                ..Options::default()
+            };
+            let table_cache = TableCache::new(db_path, &options);
+            for number in 1..=50 {
+                table_cache.find_table(number).unwrap();
+                assert!(table_cache.cache.usage() <= bound);
+            }
+            assert_eq!(table_cache.cache.usage(), bound);
+        }
+
+        // a cached table is not opened again
+        let table = table_cache.find_table(100).unwrap();
+        assert!(Arc::ptr_eq(&table, &table_cache.find_table(100).unwrap()));
+
+        // an iterator keeps working after its table is evicted and the file is removed
+        let mut iter = table_cache.iter(&ReadOptions::default(), 100).unwrap();
+        table_cache.evict(100);
+        std::fs::remove_file(table_file_name(db_path, 100)).unwrap();
+        iter.seek_to_first();
+        assert_eq!(iter.value(), b"value");
+        assert!(table_cache.find_table(100).is_err());
+    }
+}
@@ -1,14 +1,15 @@
 use crate::{
-    decode_length_prefixed_slice, decode_varint32, decode_varint64, InternalKey, Result, Status,
+    decode_length_prefixed_slice, decode_varint32, decode_varint64, put_length_prefixed_slice,
+    put_varint32, put_varint64, InternalKey, Result, Status,
 };
 use std::collections::HashSet;
 
-const NUM_LEVELS: usize = 7;
+pub const NUM_LEVELS: usize = 7;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FileMetaData {
-    pub number: usize,
-    pub file_size: usize,
+    pub number: u64,
+    pub file_size: u64,
 
     pub smallest: InternalKey,
     pub largest: InternalKey,
@@ -60,7 +61,7 @@ impl TryFrom<u8> for Tag {
             6 => Ok(Tag::DeletedFile),
             7 => Ok(Tag::NewFile),
             9 => Ok(Tag::PrevLogNumber),
-            _ => Err(Status::Corruption),
+            _ => Err(corruption("unknown tag")),
         }
     }
 }
@@ -73,16 +74,20 @@ impl TryFrom<u32> for Tag {
     }
 }
 
+fn corruption(msg: &str) -> Status {
+    Status::Corruption(format!("VersionEdit: {}", msg))
+}
+
 fn get_level(src: &[u8], pos: &mut usize) -> Result<usize> {
     let (level, num_bytes) = decode_varint32(&src[*pos..]).unwrap();
     *pos += num_bytes;
     if level >= NUM_LEVELS as u32 {
-        return Err(Status::Corruption);
+        return Err(corruption("level larger than the number of levels"));
     }
     Ok(level as usize)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default, PartialEq, Eq)]
 pub struct VersionEdit {
     pub comparator: Option<Vec<u8>>,
     pub log_number: Option<u64>,
@@ -91,11 +96,52 @@ pub struct VersionEdit {
     pub last_sequence: Option<u64>,
 
     pub compact_pointers: Vec<(usize, InternalKey)>,
-    pub deleted_files: HashSet<(usize, usize)>,
+    pub deleted_files: HashSet<(usize, u64)>,
     pub new_files: Vec<(usize, FileMetaData)>,
 }
 
 impl VersionEdit {
+    pub fn encode_to(&self, dst: &mut Vec<u8>) {
+        if let Some(comparator) = &self.comparator {
+            put_varint32(dst, Tag::Comparator.into());
+            put_length_prefixed_slice(dst, comparator);
+        }
+        if let Some(log_number) = self.log_number {
+            put_varint32(dst, Tag::LogNumber.into());
+            put_varint64(dst, log_number);
+        }
+        if let Some(prev_log_number) = self.prev_log_number {
+            put_varint32(dst, Tag::PrevLogNumber.into());
+            put_varint64(dst, prev_log_number);
+        }
+        if let Some(next_file_number) = self.next_file_number {
+            put_varint32(dst, Tag::NextFileNumber.into());
+            put_varint64(dst, next_file_number);
+        }
+        if let Some(last_sequence) = self.last_sequence {
+            put_varint32(dst, Tag::LastSequence.into());
+            put_varint64(dst, last_sequence);
+        }
+        for (level, key) in &self.compact_pointers {
+            put_varint32(dst, Tag::CompactPointer.into());
+            put_varint32(dst, *level as u32);
+            put_length_prefixed_slice(dst, key.get_contents());
+        }
+        for (level, number) in &self.deleted_files {
+            put_varint32(dst, Tag::DeletedFile.into());
+            put_varint32(dst, *level as u32);
+            put_varint64(dst, *number);
+        }
+        for (level, file) in &self.new_files {
+            put_varint32(dst, Tag::NewFile.into());
+            put_varint32(dst, *level as u32);
+            put_varint64(dst, file.number);
+            put_varint64(dst, file.file_size);
+            put_length_prefixed_slice(dst, file.smallest.get_contents());
+            put_length_prefixed_slice(dst, file.largest.get_contents());
+        }
+    }
+
     pub fn decode_from(src: &[u8]) -> Result<Self> {
         let mut pos = 0;
 
@@ -108,67 +154,73 @@ impl VersionEdit {
         let mut deleted_files = HashSet::new();
         let mut new_files = Vec::new();
         while pos < src.len() {
-            let (tag, bytes) = decode_varint32(&src[pos..]).ok_or(Status::Corruption)?;
+            let (tag, bytes) = decode_varint32(&src[pos..]).ok_or_else(|| corruption("tag"))?;
             pos += bytes;
 
             match tag.try_into()? {
                 Tag::Comparator => {
-                    let (slice, bytes) =
-                        decode_length_prefixed_slice(&src[pos..]).ok_or(Status::Corruption)?;
+                    let (slice, bytes) = decode_length_prefixed_slice(&src[pos..])
+                        .ok_or_else(|| corruption("comparator name"))?;
                     pos += bytes;
                     comparator = Some(slice.to_vec());
                 }
                 Tag::LogNumber => {
-                    let (num, bytes) = decode_varint64(&src[pos..]).ok_or(Status::Corruption)?;
+                    let (num, bytes) =
+                        decode_varint64(&src[pos..]).ok_or_else(|| corruption("log number"))?;
                     pos += bytes;
                     log_number = Some(num);
                 }
                 Tag::PrevLogNumber => {
-                    let (num, bytes) = decode_varint64(&src[pos..]).ok_or(Status::Corruption)?;
+                    let (num, bytes) = decode_varint64(&src[pos..])
+                        .ok_or_else(|| corruption("previous log number"))?;
                     pos += bytes;
                     prev_log_number = Some(num);
                 }
                 Tag::NextFileNumber => {
-                    let (num, bytes) = decode_varint64(&src[pos..]).ok_or(Status::Corruption)?;
+                    let (num, bytes) = decode_varint64(&src[pos..])
+                        .ok_or_else(|| corruption("next file number"))?;
                     pos += bytes;
                     next_file_number = Some(num);
                 }
                 Tag::LastSequence => {
-                    let (num, bytes) = decode_varint64(&src[pos..]).ok_or(Status::Corruption)?;
+                    let (num, bytes) = decode_varint64(&src[pos..])
+                        .ok_or_else(|| corruption("last sequence number"))?;
                     pos += bytes;
                     last_sequence = Some(num);
                 }
                 Tag::CompactPointer => {
                     let level = get_level(src, &mut pos)?;
-                    let (slice, bytes) =
-                        decode_length_prefixed_slice(&src[pos..]).ok_or(Status::Corruption)?;
+                    let (slice, bytes) = decode_length_prefixed_slice(&src[pos..])
+                        .ok_or_else(|| corruption("compaction pointer"))?;
                     pos += bytes;
                     compact_pointers.push((level, InternalKey::decode_from(slice)));
                 }
                 Tag::DeletedFile => {
                     let level = get_level(src, &mut pos)?;
-                    let (num, bytes) = decode_varint64(&src[pos..]).ok_or(Status::Corruption)?;
+                    let (num, bytes) =
+                        decode_varint64(&src[pos..]).ok_or_else(|| corruption("deleted file"))?;
                     pos += bytes;
-                    deleted_files.insert((level, num as usize));
+                    deleted_files.insert((level, num));
                 }
                 Tag::NewFile => {
                     let level = get_level(src, &mut pos)?;
-                    let (num, bytes) = decode_varint64(&src[pos..]).ok_or(Status::Corruption)?;
+                    let (num, bytes) =
+                        decode_varint64(&src[pos..]).ok_or_else(|| corruption("new-file entry"))?;
                     pos += bytes;
                     let (file_size, bytes) =
-                        decode_varint64(&src[pos..]).ok_or(Status::Corruption)?;
+                        decode_varint64(&src[pos..]).ok_or_else(|| corruption("new-file entry"))?;
                     pos += bytes;
-                    let (smallest, bytes) =
-                        decode_length_prefixed_slice(&src[pos..]).ok_or(Status::Corruption)?;
+                    let (smallest, bytes) = decode_length_prefixed_slice(&src[pos..])
+                        .ok_or_else(|| corruption("new-file entry"))?;
                     pos += bytes;
-                    let (largest, bytes) =
-                        decode_length_prefixed_slice(&src[pos..]).ok_or(Status::Corruption)?;
+                    let (largest, bytes) = decode_length_prefixed_slice(&src[pos..])
+                        .ok_or_else(|| corruption("new-file entry"))?;
                     pos += bytes;
                     new_files.push((
                         level,
                         FileMetaData {
-                            number: num as usize,
-                            file_size: file_size as usize,
+                            number: num,
+                            file_size,
                             smallest: InternalKey::decode_from(smallest),
                             largest: InternalKey::decode_from(largest),
                         },
@@ -189,3 +241,44 @@ impl VersionEdit {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_batch::ValueTypeCode;
+
+    #[test]
+    fn test_encode_decode() {
+        let mut edit = VersionEdit {
+            comparator: Some(b"foo".to_vec()),
+            log_number: Some(100),
+            prev_log_number: Some(99),
+            next_file_number: Some(200),
+            last_sequence: Some(1 << 40),
+            ..VersionEdit::default()
+        };
+        for i in 0..4 {
+            edit.compact_pointers
+                .push((i, InternalKey::new(b"x", 1 << 50, ValueTypeCode::Value)));
+            edit.deleted_files.insert((i, 300 + i as u64));
+            edit.new_files.push((
+                i,
+                FileMetaData {
+                    number: 400 + i as u64,
+                    file_size: 1 << 32,
+                    smallest: InternalKey::new(b"foo", 1 << 50, ValueTypeCode::Value),
+                    largest: InternalKey::new(b"zoo", 1 << 51, ValueTypeCode::Deletion),
+                },
+            ));
+        }
+
+        let mut encoded = Vec::new();
+        edit.encode_to(&mut encoded);
+        let decoded = VersionEdit::decode_from(&encoded).unwrap();
+        assert_eq!(decoded, edit);
+
+        let mut encoded2 = Vec::new();
+        decoded.encode_to(&mut encoded2);
+        assert_eq!(encoded2.len(), encoded.len());
+    }
+}
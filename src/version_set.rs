@@ -0,0 +1,1191 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+
+use crate::filename::{current_file_name, descriptor_file_name, set_current_file};
+use crate::iterator::InternalIterator;
+use crate::merger::MergingIterator;
+use crate::table::TableIterator;
+use crate::table_cache::TableCache;
+use crate::version_edit::{FileMetaData, VersionEdit, NUM_LEVELS};
+use crate::write_batch::ValueTypeCode;
+use crate::{compare_internal_key, log, InternalKey, ReadOptions, Result, Status, ValueItem};
+
+pub(crate) const COMPARATOR_NAME: &[u8] = b"leveldb.BytewiseComparator";
+
+// the result of looking up a key in the tables: the newest item of the key, or none if no table has it
+type LookupResult = Result<Option<ValueItem<Vec<u8>>>>;
+
+// level 0 is compacted when it reaches this many files
+pub(crate) const L0_COMPACTION_TRIGGER: usize = 4;
+
+// writes are delayed a little when level 0 reaches this many files,
+// so that the compaction catches up without stopping them for long at once
+pub(crate) const L0_SLOWDOWN_WRITES_TRIGGER: usize = 8;
+
+// writes are stopped when level 0 reaches this many files, until the compaction reduces them
+pub(crate) const L0_STOP_WRITES_TRIGGER: usize = 12;
+
+// max_bytes_for_level returns the total size of files which the level can hold before it is compacted.
+// level 1 holds 5 files of max_file_size, and each deeper level holds 10 times as much as the previous one.
+// level 0 is limited by the number of files instead, because every read merges all of them.
+fn max_bytes_for_level(max_file_size: u64, level: usize) -> f64 {
+    let mut result = 5. * max_file_size as f64;
+    for _ in 1..level {
+        result *= 10.;
+    }
+    result
+}
+
+// max_grandparent_overlap_bytes returns how many bytes of the grandparent level (level + 2)
+// an output of a compaction may overlap, so that a later compaction of the output doesn't read too much
+fn max_grandparent_overlap_bytes(max_file_size: u64) -> u64 {
+    10 * max_file_size
+}
+
+fn total_file_size(files: &[Arc<FileMetaData>]) -> u64 {
+    files.iter().map(|f| f.file_size).sum()
+}
+
+// Version is the set of table files per level at a point in time.
+#[derive(Debug, Default)]
+pub struct Version {
+    // level 0 files are sorted by file number, files in other levels are sorted by smallest key
+    pub files: [Vec<Arc<FileMetaData>>; NUM_LEVELS],
+
+    // the level which needs compaction most, and how much. a score >= 1 means the compaction is needed
+    compaction_level: usize,
+    compaction_score: f64,
+}
+
+impl Version {
+    // apply returns a new version which is this version with the edit applied
+    fn apply(&self, edit: &VersionEdit, max_file_size: u64) -> Version {
+        let mut files = self.files.clone();
+        for (level, level_files) in files.iter_mut().enumerate() {
+            level_files.retain(|f| !edit.deleted_files.contains(&(level, f.number)));
+        }
+        for (level, file) in &edit.new_files {
+            files[*level].push(Arc::new(file.clone()));
+        }
+
+        files[0].sort_by_key(|f| f.number);
+        for level_files in files.iter_mut().skip(1) {
+            level_files.sort_by(|a, b| {
+                compare_internal_key(a.smallest.get_contents(), b.smallest.get_contents())
+            });
+        }
+
+        let mut version = Version {
+            files,
+            ..Version::default()
+        };
+        version.finalize(max_file_size);
+        version
+    }
+
+    // finalize computes the level which should be compacted next
+    fn finalize(&mut self, max_file_size: u64) {
+        // the last level is never compacted into another level
+        for level in 0..NUM_LEVELS - 1 {
+            let score = if level == 0 {
+                self.files[level].len() as f64 / L0_COMPACTION_TRIGGER as f64
+            } else {
+                total_file_size(&self.files[level]) as f64
+                    / max_bytes_for_level(max_file_size, level)
+            };
+            if score > self.compaction_score {
+                self.compaction_level = level;
+                self.compaction_score = score;
+            }
+        }
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.compaction_score >= 1.
+    }
+
+    pub fn num_level_files(&self, level: usize) -> usize {
+        self.files[level].len()
+    }
+
+    // overlapping_inputs returns the files in the level which overlap [begin, end] in user keys.
+    // for level 0, the range is expanded by the overlapping files, since they overlap each other.
+    fn overlapping_inputs(&self, level: usize, begin: &[u8], end: &[u8]) -> Vec<Arc<FileMetaData>> {
+        let mut begin = begin.to_vec();
+        let mut end = end.to_vec();
+        let mut inputs = Vec::new();
+
+        let mut i = 0;
+        while i < self.files[level].len() {
+            let file = &self.files[level][i];
+            i += 1;
+
+            let file_start = file.smallest.user_key();
+            let file_limit = file.largest.user_key();
+            if file_limit < begin.as_slice() || end.as_slice() < file_start {
+                continue;
+            }
+            inputs.push(file.clone());
+
+            if level == 0 {
+                // restart the search if the file expands the range
+                if file_start < begin.as_slice() {
+                    begin = file_start.to_vec();
+                    inputs.clear();
+                    i = 0;
+                } else if end.as_slice() < file_limit {
+                    end = file_limit.to_vec();
+                    inputs.clear();
+                    i = 0;
+                }
+            }
+        }
+        inputs
+    }
+
+    // get looks up the newest entry for the user key of lookup_key in the tables.
+    pub fn get(
+        &self,
+        table_cache: &TableCache,
+        read_options: &ReadOptions,
+        lookup_key: &InternalKey,
+    ) -> Result<Option<ValueItem<Vec<u8>>>> {
+        let user_key = lookup_key.user_key();
+
+        // level 0 files may overlap each other, so all of them are searched from the newest
+        for file in self.files[0].iter().rev() {
+            if user_key < file.smallest.user_key() || file.largest.user_key() < user_key {
+                continue;
+            }
+            if let Some(item) = get_from_table(table_cache, read_options, file, lookup_key)? {
+                return Ok(Some(item));
+            }
+        }
+
+        // files in other levels are disjoint, so at most one file per level may contain the key
+        for files in self.files.iter().skip(1) {
+            let index = files.partition_point(|f| {
+                compare_internal_key(f.largest.get_contents(), lookup_key.get_contents()).is_lt()
+            });
+            let Some(file) = files.get(index) else {
+                continue;
+            };
+            if user_key < file.smallest.user_key() {
+                continue;
+            }
+            if let Some(item) = get_from_table(table_cache, read_options, file, lookup_key)? {
+                return Ok(Some(item));
+            }
+        }
+        Ok(None)
+    }
+
+    // multi_get looks up the newest entry for the user key of every lookup key, as get does.
+    // the keys which fall in the same table are looked up together, so that a data block is read once.
+    pub fn multi_get(
+        &self,
+        table_cache: &TableCache,
+        read_options: &ReadOptions,
+        lookup_keys: &[InternalKey],
+    ) -> Vec<Result<Option<ValueItem<Vec<u8>>>>> {
+        // the result of each key, or none while it's not found
+        let mut results: Vec<Option<LookupResult>> = (0..lookup_keys.len()).map(|_| None).collect();
+
+        // level 0 files may overlap each other, so all of them are searched from the newest
+        for file in self.files[0].iter().rev() {
+            let indices = (0..lookup_keys.len())
+                .filter(|&i| results[i].is_none())
+                .filter(|&i| {
+                    let user_key = lookup_keys[i].user_key();
+                    file.smallest.user_key() <= user_key && user_key <= file.largest.user_key()
+                })
+                .collect::<Vec<_>>();
+            multi_get_from_table(
+                table_cache,
+                read_options,
+                file,
+                lookup_keys,
+                &indices,
+                &mut results,
+            );
+        }
+
+        // files in other levels are disjoint, so the keys are grouped by the only file which may contain them
+        for files in self.files.iter().skip(1) {
+            let mut indices_per_file = BTreeMap::<usize, Vec<usize>>::new();
+            for (i, lookup_key) in lookup_keys.iter().enumerate() {
+                if results[i].is_some() {
+                    continue;
+                }
+                let index = files.partition_point(|f| {
+                    compare_internal_key(f.largest.get_contents(), lookup_key.get_contents())
+                        .is_lt()
+                });
+                match files.get(index) {
+                    Some(file) if file.smallest.user_key() <= lookup_key.user_key() => {
+                        indices_per_file.entry(index).or_default().push(i)
+                    }
+                    _ => {}
+                }
+            }
+            for (index, indices) in indices_per_file {
+                multi_get_from_table(
+                    table_cache,
+                    read_options,
+                    &files[index],
+                    lookup_keys,
+                    &indices,
+                    &mut results,
+                );
+            }
+        }
+
+        results
+            .into_iter()
+            .map(|result| result.unwrap_or(Ok(None)))
+            .collect()
+    }
+
+    // add_iterators appends iterators which together yield all entries of the tables in this version.
+    // the version must be kept alive while the iterators are used, so that the files are not removed.
+    pub fn add_iterators(
+        &self,
+        table_cache: &Arc<TableCache>,
+        read_options: &ReadOptions,
+        iters: &mut Vec<Box<dyn InternalIterator + Send>>,
+    ) -> Result<()> {
+        // level 0 files may overlap each other, so each of them is merged separately
+        for file in &self.files[0] {
+            iters.push(Box::new(table_cache.iter(read_options, file.number)?));
+        }
+
+        // files in other levels are disjoint, so a level is iterated as one sorted run
+        for files in self.files.iter().skip(1) {
+            if !files.is_empty() {
+                iters.push(Box::new(LevelIterator::new(
+                    table_cache.clone(),
+                    files.clone(),
+                    read_options,
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+// LevelIterator iterates over the entries of sorted and disjoint files, such as the files in a level > 0.
+// a file is opened only when the iteration enters it.
+pub struct LevelIterator {
+    table_cache: Arc<TableCache>,
+    files: Vec<Arc<FileMetaData>>,
+    verify_checksums: bool,
+    fill_cache: bool,
+
+    // the index of the file which table iterates over
+    index: usize,
+    table: Option<TableIterator>,
+
+    status: Option<Status>,
+}
+
+impl LevelIterator {
+    pub fn new(
+        table_cache: Arc<TableCache>,
+        files: Vec<Arc<FileMetaData>>,
+        read_options: &ReadOptions,
+    ) -> Self {
+        LevelIterator {
+            table_cache,
+            files,
+            verify_checksums: read_options.verify_checksums,
+            fill_cache: read_options.fill_cache,
+            index: 0,
+            table: None,
+            status: None,
+        }
+    }
+
+    fn open_table(&mut self, index: usize) {
+        self.index = index;
+        self.table = None;
+        if index >= self.files.len() {
+            return;
+        }
+
+        let options = ReadOptions {
+            verify_checksums: self.verify_checksums,
+            fill_cache: self.fill_cache,
+            ..ReadOptions::default()
+        };
+        match self.table_cache.iter(&options, self.files[index].number) {
+            Ok(table) => self.table = Some(table),
+            Err(e) => self.status = Some(e),
+        }
+    }
+
+    // table_exhausted returns true if the current table has no more entries in the direction.
+    // an error of the table stops the iteration.
+    fn table_exhausted(&mut self) -> bool {
+        let Some(table) = &self.table else {
+            return false;
+        };
+        if table.valid() {
+            return false;
+        }
+        if let Err(e) = table.status() {
+            self.status = Some(e);
+            self.table = None;
+            return false;
+        }
+        true
+    }
+
+    fn skip_empty_tables_forward(&mut self) {
+        while self.table_exhausted() {
+            self.open_table(self.index + 1);
+            if let Some(table) = &mut self.table {
+                table.seek_to_first();
+            }
+        }
+    }
+
+    fn skip_empty_tables_backward(&mut self) {
+        while self.table_exhausted() {
+            if self.index == 0 {
+                self.table = None;
+                return;
+            }
+            self.open_table(self.index - 1);
+            if let Some(table) = &mut self.table {
+                table.seek_to_last();
+            }
+        }
+    }
+}
+
+impl InternalIterator for LevelIterator {
+    fn valid(&self) -> bool {
+        self.table.as_ref().is_some_and(|table| table.valid())
+    }
+
+    fn seek_to_first(&mut self) {
+        self.open_table(0);
+        if let Some(table) = &mut self.table {
+            table.seek_to_first();
+        }
+        self.skip_empty_tables_forward();
+    }
+
+    fn seek_to_last(&mut self) {
+        if self.files.is_empty() {
+            self.table = None;
+            return;
+        }
+        self.open_table(self.files.len() - 1);
+        if let Some(table) = &mut self.table {
+            table.seek_to_last();
+        }
+        self.skip_empty_tables_backward();
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        let index = self
+            .files
+            .partition_point(|f| compare_internal_key(f.largest.get_contents(), target).is_lt());
+        self.open_table(index);
+        if let Some(table) = &mut self.table {
+            table.seek(target);
+        }
+        self.skip_empty_tables_forward();
+    }
+
+    fn next(&mut self) {
+        self.table
+            .as_mut()
+            .expect("next on an invalid iterator")
+            .next();
+        self.skip_empty_tables_forward();
+    }
+
+    fn prev(&mut self) {
+        self.table
+            .as_mut()
+            .expect("prev on an invalid iterator")
+            .prev();
+        self.skip_empty_tables_backward();
+    }
+
+    fn key(&self) -> &[u8] {
+        self.table
+            .as_ref()
+            .expect("key on an invalid iterator")
+            .key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.table
+            .as_ref()
+            .expect("value on an invalid iterator")
+            .value()
+    }
+
+    fn status(&self) -> Result<()> {
+        if let Some(e) = &self.status {
+            return Err(e.clone());
+        }
+        match &self.table {
+            Some(table) => table.status(),
+            None => Ok(()),
+        }
+    }
+}
+
+// Compaction describes a compaction of files in level and level + 1 into level + 1
+#[derive(Debug)]
+pub struct Compaction {
+    pub level: usize,
+    // inputs[0] are files in level, and inputs[1] are files in level + 1
+    pub inputs: [Vec<Arc<FileMetaData>>; 2],
+    // edit is the change of the version made by this compaction
+    pub edit: VersionEdit,
+
+    input_version: Arc<Version>,
+
+    // files in level + 2 which overlap the compaction, and the state of should_stop_before over them
+    grandparents: Vec<Arc<FileMetaData>>,
+    grandparent_index: usize,
+    seen_key: bool,
+    overlapped_bytes: u64,
+    max_grandparent_overlap_bytes: u64,
+}
+
+impl Compaction {
+    // is_trivial_move returns true if the compaction can be done by just moving a file to the next level.
+    // a file which overlaps too much of the grandparent level is merged instead,
+    // since the moved file would make a later compaction of it expensive.
+    pub fn is_trivial_move(&self) -> bool {
+        self.inputs[0].len() == 1
+            && self.inputs[1].is_empty()
+            && total_file_size(&self.grandparents) <= self.max_grandparent_overlap_bytes
+    }
+
+    // should_stop_before returns true if the current output should be finished before the internal key,
+    // because the output already overlaps too much of the grandparent level.
+    // it must be called with the keys in increasing order.
+    pub fn should_stop_before(&mut self, internal_key: &[u8]) -> bool {
+        while self.grandparent_index < self.grandparents.len()
+            && compare_internal_key(
+                internal_key,
+                self.grandparents[self.grandparent_index]
+                    .largest
+                    .get_contents(),
+            )
+            .is_gt()
+        {
+            if self.seen_key {
+                self.overlapped_bytes += self.grandparents[self.grandparent_index].file_size;
+            }
+            self.grandparent_index += 1;
+        }
+        self.seen_key = true;
+
+        if self.overlapped_bytes > self.max_grandparent_overlap_bytes {
+            self.overlapped_bytes = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // add_input_deletions adds all input files to the edit as deleted files
+    pub fn add_input_deletions(&mut self) {
+        for (which, inputs) in self.inputs.iter().enumerate() {
+            for file in inputs {
+                self.edit
+                    .deleted_files
+                    .insert((self.level + which, file.number));
+            }
+        }
+    }
+
+    // make_input_iterator returns an iterator which merges all entries of the input files
+    pub fn make_input_iterator(
+        &self,
+        table_cache: &Arc<TableCache>,
+        read_options: &ReadOptions,
+    ) -> Result<MergingIterator<Box<dyn InternalIterator + Send>>> {
+        let mut children: Vec<Box<dyn InternalIterator + Send>> = Vec::new();
+        if self.level == 0 {
+            for file in &self.inputs[0] {
+                children.push(Box::new(table_cache.iter(read_options, file.number)?));
+            }
+        } else {
+            children.push(Box::new(LevelIterator::new(
+                table_cache.clone(),
+                self.inputs[0].clone(),
+                read_options,
+            )));
+        }
+        if !self.inputs[1].is_empty() {
+            children.push(Box::new(LevelIterator::new(
+                table_cache.clone(),
+                self.inputs[1].clone(),
+                read_options,
+            )));
+        }
+        Ok(MergingIterator::new(children, compare_internal_key))
+    }
+
+    // is_base_level_for_key returns true if no level deeper than the output level may contain the key,
+    // so that a deletion marker of the key is no longer needed after the compaction.
+    pub fn is_base_level_for_key(&self, user_key: &[u8]) -> bool {
+        self.input_version
+            .files
+            .iter()
+            .skip(self.level + 2)
+            .flatten()
+            .all(|f| user_key < f.smallest.user_key() || f.largest.user_key() < user_key)
+    }
+}
+
+fn get_from_table(
+    table_cache: &TableCache,
+    read_options: &ReadOptions,
+    file: &FileMetaData,
+    lookup_key: &InternalKey,
+) -> Result<Option<ValueItem<Vec<u8>>>> {
+    let entry = table_cache.get(read_options, file.number, lookup_key.get_contents())?;
+    entry_item(entry, lookup_key)
+}
+
+// entry_item returns the item of the entry found for lookup_key in a table,
+// or none if the entry is of another user key
+fn entry_item(
+    entry: Option<(Vec<u8>, Vec<u8>)>,
+    lookup_key: &InternalKey,
+) -> Result<Option<ValueItem<Vec<u8>>>> {
+    let Some((found_key, value)) = entry else {
+        return Ok(None);
+    };
+
+    let found_key = InternalKey::decode_from(&found_key);
+    if found_key.user_key() != lookup_key.user_key() {
+        return Ok(None);
+    }
+    match found_key.value_type_code()? {
+        ValueTypeCode::Value => Ok(Some(ValueItem::Value(value))),
+        ValueTypeCode::Deletion => Ok(Some(ValueItem::Deletion)),
+    }
+}
+
+// multi_get_from_table looks up the lookup keys at indices in file, and sets the results of the keys found.
+// an error of the table is the result of all of the keys.
+fn multi_get_from_table(
+    table_cache: &TableCache,
+    read_options: &ReadOptions,
+    file: &FileMetaData,
+    lookup_keys: &[InternalKey],
+    indices: &[usize],
+    results: &mut [Option<LookupResult>],
+) {
+    if indices.is_empty() {
+        return;
+    }
+    let ikeys = indices
+        .iter()
+        .map(|&i| lookup_keys[i].get_contents())
+        .collect::<Vec<_>>();
+    let entries = match table_cache.multi_get(read_options, file.number, &ikeys) {
+        Ok(entries) => entries,
+        Err(e) => {
+            for &i in indices {
+                results[i] = Some(Err(e.clone()));
+            }
+            return;
+        }
+    };
+
+    for (&i, entry) in indices.iter().zip(entries) {
+        match entry.and_then(|entry| entry_item(entry, &lookup_keys[i])) {
+            Ok(None) => {}
+            item => results[i] = Some(item),
+        }
+    }
+}
+
+// key_range returns the smallest and the largest key in the files
+fn key_range(files: &[Arc<FileMetaData>]) -> (InternalKey, InternalKey) {
+    assert!(!files.is_empty());
+    let mut smallest = &files[0].smallest;
+    let mut largest = &files[0].largest;
+    for file in &files[1..] {
+        if compare_internal_key(file.smallest.get_contents(), smallest.get_contents()).is_lt() {
+            smallest = &file.smallest;
+        }
+        if compare_internal_key(file.largest.get_contents(), largest.get_contents()).is_gt() {
+            largest = &file.largest;
+        }
+    }
+    (smallest.clone(), largest.clone())
+}
+
+// VersionSet manages the current version and persists every change of it to the MANIFEST.
+#[derive(Debug)]
+pub struct VersionSet {
+    db_path: PathBuf,
+    // the size of the table files, which also sizes the levels
+    max_file_size: u64,
+
+    current: Arc<Version>,
+    // versions which were replaced but may still be used by iterators
+    old_versions: Vec<Weak<Version>>,
+    compact_pointers: [Option<InternalKey>; NUM_LEVELS],
+
+    next_file_number: u64,
+    manifest_file_number: u64,
+    last_sequence: u64,
+    log_number: u64,
+    prev_log_number: u64,
+
+    descriptor_log: Option<log::Writer>,
+}
+
+impl VersionSet {
+    pub fn new(db_path: impl Into<PathBuf>, max_file_size: u64) -> Self {
+        VersionSet {
+            db_path: db_path.into(),
+            max_file_size,
+
+            current: Arc::new(Version::default()),
+            old_versions: Vec::new(),
+            compact_pointers: Default::default(),
+
+            next_file_number: 2,
+            manifest_file_number: 0,
+            last_sequence: 0,
+            log_number: 0,
+            prev_log_number: 0,
+
+            descriptor_log: None,
+        }
+    }
+
+    // create_new_db writes the first manifest of an empty database and points CURRENT to it
+    pub fn create_new_db(db_path: &Path) -> Result<()> {
+        let edit = VersionEdit {
+            comparator: Some(COMPARATOR_NAME.to_vec()),
+            log_number: Some(0),
+            next_file_number: Some(2),
+            last_sequence: Some(0),
+            ..VersionEdit::default()
+        };
+
+        let manifest_path = descriptor_file_name(db_path, 1);
+        let result = (|| {
+            let mut writer = log::Writer::new(File::create(&manifest_path)?);
+            let mut record = Vec::new();
+            edit.encode_to(&mut record);
+            writer.append(&record)?;
+            writer.sync()?;
+            set_current_file(db_path, 1)?;
+            Ok(())
+        })();
+        if result.is_err() {
+            let _ = std::fs::remove_file(&manifest_path);
+        }
+        result
+    }
+
+    // recover restores the last version from the manifest pointed by CURRENT
+    pub fn recover(&mut self) -> Result<()> {
+        let current = std::fs::read_to_string(current_file_name(&self.db_path))?;
+        let manifest = current.strip_suffix('\n').ok_or_else(|| {
+            Status::Corruption("CURRENT file does not end with newline".to_string())
+        })?;
+        let file = File::open(self.db_path.join(manifest))?;
+        let mut reader = log::Reader::new(file);
+
+        let mut version = Version::default();
+        let mut log_number = None;
+        let mut prev_log_number = None;
+        let mut next_file_number = None;
+        let mut last_sequence = None;
+        while let Some(record) = reader.read()? {
+            let edit = VersionEdit::decode_from(&record)?;
+            if let Some(comparator) = &edit.comparator {
+                if comparator != COMPARATOR_NAME {
+                    return Err(Status::InvalidArgument);
+                }
+            }
+
+            version = version.apply(&edit, self.max_file_size);
+            for (level, key) in &edit.compact_pointers {
+                self.compact_pointers[*level] = Some(key.clone());
+            }
+            log_number = edit.log_number.or(log_number);
+            prev_log_number = edit.prev_log_number.or(prev_log_number);
+            next_file_number = edit.next_file_number.or(next_file_number);
+            last_sequence = edit.last_sequence.or(last_sequence);
+        }
+
+        let missing = |field: &str| Status::Corruption(format!("no {} entry in descriptor", field));
+        let next_file_number = next_file_number.ok_or_else(|| missing("meta-nextfile"))?;
+        let log_number = log_number.ok_or_else(|| missing("meta-lognumber"))?;
+        let last_sequence = last_sequence.ok_or_else(|| missing("last-sequence-number"))?;
+        let prev_log_number = prev_log_number.unwrap_or(0);
+
+        self.current = Arc::new(version);
+        // the manifest is rewritten by the next log_and_apply
+        self.manifest_file_number = next_file_number;
+        self.next_file_number = next_file_number + 1;
+        self.last_sequence = last_sequence;
+        self.log_number = log_number;
+        self.prev_log_number = prev_log_number;
+        self.mark_file_number_used(prev_log_number);
+        self.mark_file_number_used(log_number);
+        Ok(())
+    }
+
+    // log_and_apply persists the edit to the manifest, and then installs the new version.
+    // log number, next file number and last sequence of the edit are filled by the version set.
+    pub fn log_and_apply(&mut self, edit: &mut VersionEdit) -> Result<()> {
+        let log_number = *edit.log_number.get_or_insert(self.log_number);
+        assert!(log_number >= self.log_number);
+        assert!(log_number < self.next_file_number);
+        let prev_log_number = *edit.prev_log_number.get_or_insert(self.prev_log_number);
+        edit.next_file_number = Some(self.next_file_number);
+        edit.last_sequence = Some(self.last_sequence);
+
+        let version = self.current.apply(edit, self.max_file_size);
+
+        // a new manifest starts with a snapshot of the current version
+        let mut new_manifest = None;
+        if self.descriptor_log.is_none() {
+            let path = descriptor_file_name(&self.db_path, self.manifest_file_number);
+            let mut writer = log::Writer::new(File::create(&path)?);
+            if let Err(e) = self.write_snapshot(&mut writer) {
+                let _ = std::fs::remove_file(&path);
+                return Err(e);
+            }
+            self.descriptor_log = Some(writer);
+            new_manifest = Some(path);
+        }
+
+        let mut record = Vec::new();
+        edit.encode_to(&mut record);
+        let descriptor_log = self.descriptor_log.as_mut().unwrap();
+        let mut result = descriptor_log
+            .append(&record)
+            .and_then(|_| descriptor_log.sync());
+        if result.is_ok() && new_manifest.is_some() {
+            result = set_current_file(&self.db_path, self.manifest_file_number).map_err(Into::into);
+        }
+        if let Err(e) = result {
+            if let Some(path) = new_manifest {
+                self.descriptor_log = None;
+                let _ = std::fs::remove_file(path);
+            }
+            return Err(e);
+        }
+
+        for (level, key) in &edit.compact_pointers {
+            self.compact_pointers[*level] = Some(key.clone());
+        }
+        self.old_versions.retain(|v| v.strong_count() > 0);
+        self.old_versions.push(Arc::downgrade(&self.current));
+        self.current = Arc::new(version);
+        self.log_number = log_number;
+        self.prev_log_number = prev_log_number;
+        Ok(())
+    }
+
+    fn write_snapshot(&self, writer: &mut log::Writer) -> Result<()> {
+        let mut edit = VersionEdit {
+            comparator: Some(COMPARATOR_NAME.to_vec()),
+            ..VersionEdit::default()
+        };
+        for (level, key) in self.compact_pointers.iter().enumerate() {
+            if let Some(key) = key {
+                edit.compact_pointers.push((level, key.clone()));
+            }
+        }
+        for (level, files) in self.current.files.iter().enumerate() {
+            for file in files {
+                edit.new_files.push((level, file.as_ref().clone()));
+            }
+        }
+
+        let mut record = Vec::new();
+        edit.encode_to(&mut record);
+        writer.append(&record)
+    }
+
+    pub fn current(&self) -> Arc<Version> {
+        self.current.clone()
+    }
+
+    // pick_compaction returns the compaction of the level which needs it most,
+    // or None if no level needs compaction.
+    pub fn pick_compaction(&self) -> Option<Compaction> {
+        let version = self.current.clone();
+        if !version.needs_compaction() {
+            return None;
+        }
+        let level = version.compaction_level;
+        let files = &version.files[level];
+
+        // pick the first file after the key where the last compaction of the level stopped,
+        // so that compactions rotate through the key space
+        let start = match &self.compact_pointers[level] {
+            Some(pointer) => files.iter().position(|f| {
+                compare_internal_key(f.largest.get_contents(), pointer.get_contents()).is_gt()
+            }),
+            None => None,
+        };
+        let mut inputs0 = vec![files[start.unwrap_or(0)].clone()];
+
+        // level 0 files may overlap each other, so pick up all files overlapping the picked one
+        if level == 0 {
+            let (smallest, largest) = key_range(&inputs0);
+            inputs0 = version.overlapping_inputs(0, smallest.user_key(), largest.user_key());
+        }
+        let (smallest, largest) = key_range(&inputs0);
+        let inputs1 =
+            version.overlapping_inputs(level + 1, smallest.user_key(), largest.user_key());
+
+        // the outputs are cut by their overlap with the grandparent level over the whole range of the inputs
+        let grandparents = if level + 2 < NUM_LEVELS {
+            let all_inputs = [inputs0.as_slice(), inputs1.as_slice()].concat();
+            let (all_smallest, all_largest) = key_range(&all_inputs);
+            version.overlapping_inputs(level + 2, all_smallest.user_key(), all_largest.user_key())
+        } else {
+            Vec::new()
+        };
+
+        let mut edit = VersionEdit::default();
+        edit.compact_pointers.push((level, largest.clone()));
+        Some(Compaction {
+            level,
+            inputs: [inputs0, inputs1],
+            edit,
+            input_version: version,
+            grandparents,
+            grandparent_index: 0,
+            seen_key: false,
+            overlapped_bytes: 0,
+            max_grandparent_overlap_bytes: max_grandparent_overlap_bytes(self.max_file_size),
+        })
+    }
+
+    pub fn new_file_number(&mut self) -> u64 {
+        let number = self.next_file_number;
+        self.next_file_number += 1;
+        number
+    }
+
+    pub fn mark_file_number_used(&mut self, number: u64) {
+        if self.next_file_number <= number {
+            self.next_file_number = number + 1;
+        }
+    }
+
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    pub fn set_last_sequence(&mut self, sequence: u64) {
+        assert!(sequence >= self.last_sequence);
+        self.last_sequence = sequence;
+    }
+
+    pub fn log_number(&self) -> u64 {
+        self.log_number
+    }
+
+    pub fn prev_log_number(&self) -> u64 {
+        self.prev_log_number
+    }
+
+    pub fn manifest_file_number(&self) -> u64 {
+        self.manifest_file_number
+    }
+
+    // live_files returns the numbers of all table files referenced by the current version
+    // or by an older version which is still in use
+    pub fn live_files(&self) -> HashSet<u64> {
+        let old_versions = self.old_versions.iter().filter_map(Weak::upgrade);
+        std::iter::once(self.current.clone())
+            .chain(old_versions)
+            .flat_map(|version| {
+                version
+                    .files
+                    .iter()
+                    .flat_map(|files| files.iter().map(|f| f.number))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_FILE_SIZE: u64 = 1024;
+
+    fn file(number: u64, file_size: u64, smallest: &[u8], largest: &[u8]) -> FileMetaData {
+        FileMetaData {
+            number,
+            file_size,
+            smallest: InternalKey::new(smallest, 100, ValueTypeCode::Value),
+            largest: InternalKey::new(largest, 100, ValueTypeCode::Value),
+        }
+    }
+
+    // version_set returns a version set whose current version has the files, without touching the disk
+    fn version_set(db_path: &str, files: &[(usize, FileMetaData)]) -> VersionSet {
+        let edit = VersionEdit {
+            new_files: files.to_vec(),
+            ..VersionEdit::default()
+        };
+        let mut versions = VersionSet::new(db_path, MAX_FILE_SIZE);
+        versions.current = Arc::new(Version::default().apply(&edit, MAX_FILE_SIZE));
+        versions
+    }
+
+    // open_version_set creates an empty database and recovers the version set of it
+    fn open_version_set(db_path: &str) -> VersionSet {
+        let _ = std::fs::remove_dir_all(db_path);
+        std::fs::create_dir_all(db_path).unwrap();
+        VersionSet::create_new_db(Path::new(db_path)).unwrap();
+        let mut versions = VersionSet::new(db_path, MAX_FILE_SIZE);
+        versions.recover().unwrap();
+        versions
+    }
+
+    fn file_numbers(version: &Version) -> Vec<Vec<u64>> {
+        version
+            .files
+            .iter()
+            .map(|files| files.iter().map(|f| f.number).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_recover() {
+        let db_path = "/tmp/test_version_set_recover";
+        let mut versions = open_version_set(db_path);
+
+        let log_number = versions.new_file_number();
+        let mut edit = VersionEdit {
+            log_number: Some(log_number),
+            ..VersionEdit::default()
+        };
+        let level0_file = file(versions.new_file_number(), 100, b"a", b"c");
+        let level2_file = file(versions.new_file_number(), 200, b"d", b"f");
+        edit.new_files.push((0, level0_file.clone()));
+        edit.new_files.push((2, level2_file.clone()));
+        edit.compact_pointers
+            .push((2, InternalKey::new(b"e", 10, ValueTypeCode::Value)));
+        versions.set_last_sequence(1000);
+        versions.log_and_apply(&mut edit).unwrap();
+
+        // the second edit goes to the same manifest
+        let mut edit = VersionEdit::default();
+        let level1_file = file(versions.new_file_number(), 300, b"a", b"c");
+        edit.deleted_files.insert((0, level0_file.number));
+        edit.new_files.push((1, level1_file.clone()));
+        versions.log_and_apply(&mut edit).unwrap();
+        let manifest_file_number = versions.manifest_file_number();
+
+        let mut recovered = VersionSet::new(db_path, MAX_FILE_SIZE);
+        recovered.recover().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(current_file_name(Path::new(db_path))).unwrap(),
+            format!("MANIFEST-{:06}\n", manifest_file_number)
+        );
+        assert_eq!(
+            file_numbers(&recovered.current()),
+            file_numbers(&versions.current())
+        );
+        assert_eq!(
+            file_numbers(&recovered.current())[..3],
+            [vec![], vec![level1_file.number], vec![level2_file.number]]
+        );
+        assert_eq!(recovered.current().files[2][0].as_ref(), &level2_file);
+        assert_eq!(recovered.log_number(), log_number);
+        assert_eq!(recovered.last_sequence(), 1000);
+        assert_eq!(
+            recovered.compact_pointers[2],
+            Some(InternalKey::new(b"e", 10, ValueTypeCode::Value))
+        );
+        assert!(recovered.compact_pointers[1].is_none());
+        // the file numbers used before are not reused
+        assert!(recovered.new_file_number() > level1_file.number);
+    }
+
+    #[test]
+    fn test_pick_compaction_level0() {
+        // level 0 is compacted by the number of files, however small they are
+        let files = [
+            (0, file(1, 10, b"a", b"c")),
+            (0, file(2, 10, b"b", b"d")),
+            (0, file(3, 10, b"x", b"z")),
+            (1, file(4, 10, b"c", b"e")),
+            (1, file(5, 10, b"f", b"g")),
+        ];
+        let versions = version_set("/tmp/test_pick_compaction_level0", &files);
+        assert!(!versions.current().needs_compaction());
+        assert!(versions.pick_compaction().is_none());
+
+        let mut files = files.to_vec();
+        files.push((0, file(6, 10, b"c", b"c")));
+        let versions = version_set("/tmp/test_pick_compaction_level0", &files);
+        let compaction = versions.pick_compaction().unwrap();
+        assert_eq!(compaction.level, 0);
+        // all level 0 files overlapping the first one are picked, with the level 1 files overlapping them
+        let numbers =
+            |files: &[Arc<FileMetaData>]| files.iter().map(|f| f.number).collect::<Vec<_>>();
+        assert_eq!(numbers(&compaction.inputs[0]), vec![1, 2, 6]);
+        assert_eq!(numbers(&compaction.inputs[1]), vec![4]);
+        assert!(!compaction.is_trivial_move());
+    }
+
+    #[test]
+    fn test_pick_compaction_by_size() {
+        // level 0 is under its trigger, and level 2 is over its budget of 50 * MAX_FILE_SIZE
+        let mut files = vec![
+            (0, file(1, 10, b"a", b"b")),
+            (1, file(2, 4 * MAX_FILE_SIZE, b"a", b"b")),
+        ];
+        for (i, key) in [b"c", b"e", b"g"].iter().enumerate() {
+            let number = 10 + i as u64;
+            files.push((2, file(number, 20 * MAX_FILE_SIZE, *key, *key)));
+        }
+        let versions = version_set("/tmp/test_pick_compaction_by_size", &files);
+        let compaction = versions.pick_compaction().unwrap();
+        assert_eq!(compaction.level, 2);
+        assert_eq!(compaction.inputs[0][0].number, 10);
+        assert!(compaction.is_trivial_move());
+    }
+
+    #[test]
+    fn test_pick_compaction_advances_compact_pointer() {
+        let db_path = "/tmp/test_pick_compaction_advances_compact_pointer";
+        let mut versions = open_version_set(db_path);
+        let mut edit = VersionEdit::default();
+        let mut numbers = Vec::new();
+        for key in [b"a", b"c", b"e", b"g", b"i", b"k"] {
+            let number = versions.new_file_number();
+            edit.new_files
+                .push((1, file(number, MAX_FILE_SIZE, key, key)));
+            numbers.push(number);
+        }
+        versions.log_and_apply(&mut edit).unwrap();
+
+        // each compaction of the level starts after the largest key of the previous one,
+        // and it wraps around to the first file at the end of the level
+        for &expected in numbers.iter().chain(&numbers[..1]) {
+            let mut compaction = versions.pick_compaction().unwrap();
+            assert_eq!(compaction.level, 1);
+            assert_eq!(compaction.inputs[0].len(), 1);
+            assert_eq!(compaction.inputs[0][0].number, expected);
+            assert_eq!(
+                compaction.edit.compact_pointers,
+                vec![(1, compaction.inputs[0][0].largest.clone())]
+            );
+            versions.log_and_apply(&mut compaction.edit).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_is_base_level_for_key() {
+        let files = [
+            (0, file(1, 10, b"a", b"z")),
+            (0, file(2, 10, b"a", b"z")),
+            (0, file(3, 10, b"a", b"z")),
+            (0, file(4, 10, b"a", b"z")),
+            (1, file(5, 10, b"b", b"d")),
+            (2, file(6, 10, b"f", b"h")),
+            (4, file(7, 10, b"m", b"p")),
+        ];
+        let versions = version_set("/tmp/test_is_base_level_for_key", &files);
+        let compaction = versions.pick_compaction().unwrap();
+        assert_eq!(compaction.level, 0);
+
+        // the output level 1 doesn't matter, but the deeper levels do
+        assert!(compaction.is_base_level_for_key(b"c"));
+        assert!(!compaction.is_base_level_for_key(b"f"));
+        assert!(!compaction.is_base_level_for_key(b"g"));
+        assert!(!compaction.is_base_level_for_key(b"h"));
+        assert!(compaction.is_base_level_for_key(b"i"));
+        assert!(!compaction.is_base_level_for_key(b"n"));
+        assert!(compaction.is_base_level_for_key(b"q"));
+    }
+
+    #[test]
+    fn test_max_bytes_for_level() {
+        assert_eq!(max_bytes_for_level(MAX_FILE_SIZE, 1), 5. * 1024.);
+        assert_eq!(max_bytes_for_level(MAX_FILE_SIZE, 2), 50. * 1024.);
+        assert_eq!(max_bytes_for_level(MAX_FILE_SIZE, 3), 500. * 1024.);
+        // the default file size keeps the 10MB level 1 of leveldb
+        assert_eq!(max_bytes_for_level(2 * 1024 * 1024, 1), 10. * 1024. * 1024.);
+
+        // level 1 needs compaction once it holds more than 5 full files
+        let files = (0..5)
+            .map(|i| {
+                (
+                    1,
+                    file(i + 1, MAX_FILE_SIZE, &[b'a' + i as u8], &[b'a' + i as u8]),
+                )
+            })
+            .collect::<Vec<_>>();
+        let versions = version_set("/tmp/test_max_bytes_for_level", &files);
+        assert!(versions.current().needs_compaction());
+        let versions = version_set("/tmp/test_max_bytes_for_level", &files[..4]);
+        assert!(!versions.current().needs_compaction());
+    }
+
+    #[test]
+    fn test_should_stop_before() {
+        // level 1 needs compaction, and its file overlaps 4 grandparent files of 6 * MAX_FILE_SIZE each
+        let mut files = vec![(1, file(1, 6 * MAX_FILE_SIZE, b"a", b"z"))];
+        for (i, key) in [b"b", b"d", b"f", b"h"].iter().enumerate() {
+            let number = 10 + i as u64;
+            files.push((3, file(number, 6 * MAX_FILE_SIZE, *key, *key)));
+        }
+        let versions = version_set("/tmp/test_should_stop_before", &files);
+        let mut compaction = versions.pick_compaction().unwrap();
+        assert_eq!(compaction.level, 1);
+        assert_eq!(compaction.grandparents.len(), 4);
+        // the file overlaps too much of level 3 to be moved as is
+        assert!(!compaction.is_trivial_move());
+
+        let key = |user_key: &[u8]| InternalKey::new(user_key, 50, ValueTypeCode::Value);
+        // the first key doesn't count the grandparents before it
+        assert!(!compaction.should_stop_before(key(b"c").get_contents()));
+        assert!(!compaction.should_stop_before(key(b"e").get_contents()));
+        // 12 * MAX_FILE_SIZE overlapped since the first key exceeds the limit of 10 * MAX_FILE_SIZE
+        assert!(compaction.should_stop_before(key(b"g").get_contents()));
+        // the overlap is counted again from the new output
+        assert!(!compaction.should_stop_before(key(b"i").get_contents()));
+        assert!(!compaction.should_stop_before(key(b"z").get_contents()));
+    }
+
+    #[test]
+    fn test_trivial_move_with_small_grandparent_overlap() {
+        let files = [
+            (1, file(1, 6 * MAX_FILE_SIZE, b"a", b"z")),
+            (3, file(2, MAX_FILE_SIZE, b"m", b"m")),
+        ];
+        let versions = version_set(
+            "/tmp/test_trivial_move_with_small_grandparent_overlap",
+            &files,
+        );
+        let compaction = versions.pick_compaction().unwrap();
+        assert_eq!(compaction.grandparents.len(), 1);
+        assert!(compaction.is_trivial_move());
+    }
+}
@@ -4,6 +4,7 @@ use crate::{
 };
 use crate::{decode_length_prefixed_slice, Result};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ValueTypeCode {
     Deletion = 0,
     Value = 1,
@@ -71,13 +72,29 @@ impl WriteBatch {
         put_length_prefixed_slice(&mut self.rep, key);
     }
 
-    pub fn apply_to(&self, mem_table: &mut MemTable) -> Result<()> {
-        let iter = self.iter();
-        for result in iter {
+    // append adds the updates of other after the ones of this batch
+    pub fn append(&mut self, other: &WriteBatch) {
+        self.set_count(self.get_count() + other.get_count());
+        self.rep
+            .extend_from_slice(&other.rep[WRITE_BATCH_HEADER_SIZE..]);
+    }
+
+    // approximate_size returns the size of the encoded batch
+    pub fn approximate_size(&self) -> usize {
+        self.rep.len()
+    }
+
+    pub fn apply_to(&self, mem_table: &MemTable) -> Result<()> {
+        // each entry takes its own sequence number, starting from the batch's one
+        for (sequence, result) in (self.get_sequence()..).zip(self.iter()) {
             let value = result?;
             match value {
-                ValueType::Value(key, value) => mem_table.set(key, value),
-                ValueType::Deletion(key) => mem_table.delete(key),
+                ValueType::Value(key, value) => {
+                    mem_table.add(sequence, ValueTypeCode::Value, key, value)
+                }
+                ValueType::Deletion(key) => {
+                    mem_table.add(sequence, ValueTypeCode::Deletion, key, &[])
+                }
             }
         }
         Ok(())
@@ -90,7 +107,9 @@ impl WriteBatch {
     pub fn from(rep: impl Into<Vec<u8>>) -> Result<Self> {
         let rep = rep.into();
         if rep.len() < WRITE_BATCH_HEADER_SIZE {
-            return Err(Status::Corruption);
+            return Err(Status::Corruption(
+                "malformed WriteBatch (too small)".to_string(),
+            ));
         }
         Ok(Self { rep: rep.to_vec() })
     }
@@ -124,7 +143,11 @@ impl<'a> Iterator for WriteBatchIter<'a> {
         let value_type = match value_type_bytes {
             0 => ValueTypeCode::Deletion,
             1 => ValueTypeCode::Value,
-            _ => return Some(Err(Status::Corruption)),
+            _ => {
+                return Some(Err(Status::Corruption(
+                    "unknown WriteBatch tag".to_string(),
+                )))
+            }
         };
 
         let (key, bytes) = decode_length_prefixed_slice(&self.wb.rep[self.offset..])?;
@@ -208,6 +231,39 @@ mod tests {
         assert_eq!(offset, batch.rep.len());
     }
 
+    #[test]
+    fn test_write_batch_append() {
+        let mut batch = WriteBatch::new();
+        batch.put(b"key1", b"value1");
+        batch.set_sequence(10);
+
+        let mut other = WriteBatch::new();
+        other.delete(b"key1");
+        other.put(b"key2", b"value2");
+        other.set_sequence(20);
+
+        batch.append(&other);
+        // the sequence of the appended batch is dropped
+        assert_eq!(batch.get_sequence(), 10);
+        assert_eq!(batch.get_count(), 3);
+        assert_eq!(
+            batch.iter().collect::<Vec<_>>(),
+            vec![
+                Ok(ValueType::value(b"key1", b"value1")),
+                Ok(ValueType::deletion(b"key1")),
+                Ok(ValueType::value(b"key2", b"value2")),
+            ]
+        );
+
+        batch.append(&WriteBatch::new());
+        assert_eq!(batch.get_count(), 3);
+        // the header, two puts of 13 bytes and a deletion of 6 bytes
+        assert_eq!(
+            batch.approximate_size(),
+            WRITE_BATCH_HEADER_SIZE + 13 + 6 + 13
+        );
+    }
+
     #[test]
     fn test_write_batch_iter() {
         let mut batch = WriteBatch::new();